@@ -0,0 +1,209 @@
+use super::geom::*;
+use super::sampler::Sampler;
+use std::sync::Arc;
+
+// Only the wrapped material sees the perturbed normal, and only if it is on the outside of the surface and still
+// faces the viewer
+fn scatter_with_normal(
+    base: &Arc<dyn Material + Send + Sync>,
+    ray: &Ray,
+    hit: &Hit,
+    normal: Vec3,
+    rng: &mut Sampler,
+) -> Option<Scatter> {
+    if normal.dot(&hit.normal) <= 0.0 || normal.dot(&ray.direction) >= 0.0 {
+        return base.scatter(ray, hit, rng);
+    }
+    let shaded = Hit { normal, ..*hit };
+    base.scatter(ray, &shaded, rng)
+}
+
+/**
+ * A tangent space normal map where rgb in [0, 1] encodes a normal in [-1, 1] with blue along the surface normal.
+ * The texture should be loaded linearly
+ */
+pub struct NormalMap {
+    base: Arc<dyn Material + Send + Sync>,
+    map: Arc<dyn Texture + Send + Sync>,
+    strength: f64,
+}
+
+impl NormalMap {
+    pub fn new(
+        base: Arc<dyn Material + Send + Sync>,
+        map: Arc<dyn Texture + Send + Sync>,
+        strength: f64,
+    ) -> NormalMap {
+        NormalMap {
+            base,
+            map,
+            strength,
+        }
+    }
+}
+
+impl Material for NormalMap {
+    fn scatter(&self, ray: &Ray, hit: &Hit, rng: &mut Sampler) -> Option<Scatter> {
+        let encoded =
+            self.map
+                .color_filtered(hit.u, hit.v, &hit.point, &hit.normal, hit.footprint());
+        let tangent_space = Vec3::new(
+            self.strength * (2.0 * encoded.x() - 1.0),
+            self.strength * (2.0 * encoded.y() - 1.0),
+            2.0 * encoded.z() - 1.0,
+        );
+        let frame = Onb::from_normal_tangent(&hit.normal, &hit.dpdu);
+        let normal = frame.to_world(&tangent_space).unit();
+        scatter_with_normal(&self.base, ray, hit, normal, rng)
+    }
+
+    fn albedo(&self, hit: &Hit) -> Vec3 {
+        self.base.albedo(hit)
+    }
+}
+
+/**
+ * Bump mapping from the first channel of a height texture, scale is in world units per unit of height.
+ * The height is differentiated numerically along the surface's u and v
+ */
+pub struct BumpMap {
+    base: Arc<dyn Material + Send + Sync>,
+    height: Arc<dyn Texture + Send + Sync>,
+    scale: f64,
+}
+
+impl BumpMap {
+    pub fn new(
+        base: Arc<dyn Material + Send + Sync>,
+        height: Arc<dyn Texture + Send + Sync>,
+        scale: f64,
+    ) -> BumpMap {
+        BumpMap {
+            base,
+            height,
+            scale,
+        }
+    }
+}
+
+const BUMP_DELTA: f64 = 1e-4;
+
+impl Material for BumpMap {
+    fn scatter(&self, ray: &Ray, hit: &Hit, rng: &mut Sampler) -> Option<Scatter> {
+        let h = self.height.color(hit.u, hit.v, &hit.point, &hit.normal).x();
+        let h_u = self
+            .height
+            .color(
+                hit.u + BUMP_DELTA,
+                hit.v,
+                &(hit.point + BUMP_DELTA * hit.dpdu),
+                &hit.normal,
+            )
+            .x();
+        let h_v = self
+            .height
+            .color(
+                hit.u,
+                hit.v + BUMP_DELTA,
+                &(hit.point + BUMP_DELTA * hit.dpdv),
+                &hit.normal,
+            )
+            .x();
+        let dhdu = self.scale * (h_u - h) / BUMP_DELTA;
+        let dhdv = self.scale * (h_v - h) / BUMP_DELTA;
+
+        // Displacing along the normal tilts the tangents and so the normal
+        let dpdu = hit.dpdu + dhdu * hit.normal;
+        let dpdv = hit.dpdv + dhdv * hit.normal;
+        let normal = dpdu.cross(&dpdv);
+        if normal.length_squared() == 0.0 {
+            return self.base.scatter(ray, hit, rng);
+        }
+        let normal = normal.unit();
+        let normal = if normal.dot(&hit.normal) < 0.0 {
+            normal.flip()
+        } else {
+            normal
+        };
+        scatter_with_normal(&self.base, ray, hit, normal, rng)
+    }
+
+    fn albedo(&self, hit: &Hit) -> Vec3 {
+        self.base.albedo(hit)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::geom::tests::*;
+
+    // Scatters along whatever normal it was handed so the test can see it
+    struct Probe;
+
+    impl Material for Probe {
+        fn scatter(&self, ray: &Ray, hit: &Hit, _rng: &mut Sampler) -> Option<Scatter> {
+            Some(Scatter {
+                scattered: Ray::new_at(hit.point, hit.normal, ray.time),
+                attenuation: Vec3::new(1.0, 1.0, 1.0),
+            })
+        }
+    }
+
+    // Height rising along u and v at the given rates
+    struct Slope(f64, f64);
+
+    impl Texture for Slope {
+        fn color(&self, u: f64, v: f64, _point: &Vec3, _normal: &Vec3) -> Vec3 {
+            let h = self.0 * u + self.1 * v;
+            Vec3::new(h, h, h)
+        }
+    }
+
+    fn shading_normals(material: &dyn Material) -> Vec<Vec3> {
+        (directions().into_iter())
+            .map(|wo| {
+                scatters(material, wo, 1)[0]
+                    .as_ref()
+                    .unwrap()
+                    .scattered
+                    .direction
+            })
+            .collect()
+    }
+
+    #[test]
+    fn flat_maps_leave_the_normal_alone() {
+        let probe: Arc<dyn Material + Send + Sync> = Arc::new(Probe);
+        let flat = NormalMap::new(probe.clone(), Arc::new(SolidColor::new(0.5, 0.5, 1.0)), 2.0);
+        let level = BumpMap::new(probe, Arc::new(SolidColor::new(0.3, 0.3, 0.3)), 5.0);
+        for normal in shading_normals(&flat)
+            .iter()
+            .chain(shading_normals(&level).iter())
+        {
+            assert!((*normal - Vec3::new(0.0, 0.0, 1.0)).length() < 1e-12);
+        }
+    }
+
+    #[test]
+    fn perturbed_normals_stay_above_the_surface() {
+        let probe: Arc<dyn Material + Send + Sync> = Arc::new(Probe);
+        let mut state = 7;
+        let mut unit = || {
+            state = mix_bits(state);
+            bits_to_unit(state)
+        };
+        for _ in 0..200 {
+            let color = SolidColor::new(unit(), unit(), unit());
+            let normal_map = NormalMap::new(probe.clone(), Arc::new(color), 4.0 * unit());
+            let slope = Slope(20.0 * unit() - 10.0, 20.0 * unit() - 10.0);
+            let bump_map = BumpMap::new(probe.clone(), Arc::new(slope), 10.0 * unit());
+            for normal in shading_normals(&normal_map)
+                .iter()
+                .chain(shading_normals(&bump_map).iter())
+            {
+                assert!(normal.z() > 0.0);
+            }
+        }
+    }
+}
@@ -0,0 +1,490 @@
+use super::geom::*;
+use super::texture::ImageTexture;
+use anyhow::{bail, Context, Result};
+use std::collections::HashMap;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+
+// Edge length in texels of the square tiles images are cached in
+const TILE_SIZE: usize = 64;
+
+/**
+ * Hands out image textures so that every material referring to the same file shares one.
+ * Only the header is read up front. Texels are decoded on first use and kept in tiles, which are the only copy held on
+ * to and are evicted by the clock algorithm so that together they never take more than the budget
+ */
+pub struct TextureCache {
+    tiles: Arc<TileCache>,
+    textures: Mutex<HashMap<PathBuf, Arc<ImageTexture>>>,
+}
+
+impl TextureCache {
+    // budget is in bytes of decoded texels
+    pub fn new(budget: usize) -> TextureCache {
+        TextureCache {
+            tiles: Arc::new(TileCache {
+                sources: RwLock::new(Vec::new()),
+                shards: (0..SHARDS)
+                    .map(|_| {
+                        RwLock::new(Shard {
+                            map: HashMap::new(),
+                            ring: Vec::new(),
+                            hand: 0,
+                            used: 0,
+                            budget: budget / SHARDS,
+                        })
+                    })
+                    .collect(),
+            }),
+            textures: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Texel values are used as is, which suits data like normal or height maps
+    pub fn get(&self, path: &Path) -> Result<Arc<ImageTexture>> {
+        let path = path
+            .canonicalize()
+            .with_context(|| format!("failed to find texture: {:?}", path))?;
+        let mut textures = self.textures.lock().unwrap();
+        if let Some(texture) = textures.get(&path) {
+            return Ok(texture.clone());
+        }
+        // Reading the header now means a missing or broken file fails here rather than mid render
+        let (info, _) = decoder(&path)?
+            .read_info()
+            .with_context(|| format!("failed to read texture header: {:?}", path))?;
+        let id = {
+            let mut sources = self.tiles.sources.write().unwrap();
+            sources.push(Arc::new(Source {
+                path: path.clone(),
+                decoding: Mutex::new(()),
+            }));
+            sources.len() - 1
+        };
+        let texture = Arc::new(ImageTexture::new(
+            self.tiles.clone(),
+            id,
+            info.width as usize,
+            info.height as usize,
+        ));
+        textures.insert(path, texture.clone());
+        Ok(texture)
+    }
+}
+
+// Where a texture's texels come from when they aren't in the cache
+struct Source {
+    path: PathBuf,
+    // Held while the file is decoded so that threads missing at once don't each decode a copy
+    decoding: Mutex<()>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+struct TileKey {
+    texture: usize,
+    level: usize,
+    x: usize,
+    y: usize,
+}
+
+impl TileKey {
+    fn shard(&self) -> usize {
+        hash_bits(&[
+            self.texture as u64,
+            self.level as u64,
+            self.x as u64,
+            self.y as u64,
+        ]) as usize
+            % SHARDS
+    }
+}
+
+struct Tile {
+    key: TileKey,
+    width: usize,
+    // Single precision is plenty for what came from 8 or 16 bit channels, at half the memory
+    pixels: Vec<[f32; 3]>,
+    // Set on every lookup, the clock hand clears it and only evicts tiles that weren't used since it last passed
+    referenced: AtomicBool,
+}
+
+impl Tile {
+    fn bytes(&self) -> usize {
+        self.pixels.len() * std::mem::size_of::<[f32; 3]>()
+    }
+
+    fn texel(&self, i: usize, j: usize) -> Vec3 {
+        let [r, g, b] = self.pixels[j * self.width + i];
+        Vec3::new(f64::from(r), f64::from(g), f64::from(b))
+    }
+}
+
+// Tiles are spread over shards by their key so that threads looking up different tiles rarely share a lock
+const SHARDS: usize = 16;
+
+// One part of the cache with a share of the budget, its tiles in a ring the clock hand sweeps over
+struct Shard {
+    // Where each tile is in the ring
+    map: HashMap<TileKey, usize>,
+    ring: Vec<Tile>,
+    hand: usize,
+    used: usize,
+    budget: usize,
+}
+
+impl Shard {
+    fn fits(&self, tile: &Tile) -> bool {
+        self.used + tile.bytes() <= self.budget
+    }
+
+    // Evicts tiles until the new one fits, one bigger than the whole budget is left out and false returned
+    fn insert(&mut self, tile: Tile) -> bool {
+        if tile.bytes() > self.budget {
+            return false;
+        }
+        while !self.fits(&tile) {
+            self.hand %= self.ring.len();
+            if self.ring[self.hand]
+                .referenced
+                .swap(false, Ordering::Relaxed)
+            {
+                self.hand += 1;
+                continue;
+            }
+            // The last tile takes the evicted one's place, and the hand looks at it next
+            let evicted = self.ring.swap_remove(self.hand);
+            self.map.remove(&evicted.key);
+            if let Some(moved) = self.ring.get(self.hand) {
+                self.map.insert(moved.key, self.hand);
+            }
+            self.used -= evicted.bytes();
+        }
+        self.used += tile.bytes();
+        self.map.insert(tile.key, self.ring.len());
+        self.ring.push(tile);
+        true
+    }
+}
+
+// Texels of every texture in a cache, shared with the textures it handed out
+pub struct TileCache {
+    // Every texture's file by id
+    sources: RwLock<Vec<Arc<Source>>>,
+    shards: Vec<RwLock<Shard>>,
+}
+
+impl TileCache {
+    // i and j must already be within the level
+    pub fn texel(&self, texture: usize, level: usize, i: usize, j: usize) -> Vec3 {
+        let key = TileKey {
+            texture,
+            level,
+            x: i / TILE_SIZE,
+            y: j / TILE_SIZE,
+        };
+        let (i, j) = (i % TILE_SIZE, j % TILE_SIZE);
+        if let Some(color) = self.cached(key, i, j) {
+            return color;
+        }
+
+        let source = self.sources.read().unwrap()[texture].clone();
+        let _decoding = source.decoding.lock().unwrap();
+        // Another thread may have brought the tile in while this one waited
+        if let Some(color) = self.cached(key, i, j) {
+            return color;
+        }
+        self.load(&source, key, i, j)
+    }
+
+    fn cached(&self, key: TileKey, i: usize, j: usize) -> Option<Vec3> {
+        let shard = self.shards[key.shard()].read().unwrap();
+        let tile = &shard.ring[*shard.map.get(&key)?];
+        // Only written when it changes, so lookups of a hot tile don't fight over its cache line
+        if !tile.referenced.load(Ordering::Relaxed) {
+            tile.referenced.store(true, Ordering::Relaxed);
+        }
+        Some(tile.texel(i, j))
+    }
+
+    /**
+     * Decodes the whole file for a missing tile since png has no random access, the decoded image is dropped again
+     * once its tiles are cut out. While it is at hand any of its other tiles that fit without evicting come along
+     */
+    fn load(&self, source: &Source, key: TileKey, i: usize, j: usize) -> Vec3 {
+        // The header was fine when the texture was handed out so this only fails on corrupt data
+        let levels = decode_mipmapped(&source.path).unwrap_or_else(|e| panic!("{:#}", e));
+
+        let tile = levels[key.level].tile(key);
+        let color = tile.texel(i, j);
+        self.shards[key.shard()].write().unwrap().insert(tile);
+        for (level, pixels) in levels.iter().enumerate() {
+            for y in 0..pixels.height.div_ceil(TILE_SIZE) {
+                for x in 0..pixels.width.div_ceil(TILE_SIZE) {
+                    let other = TileKey {
+                        texture: key.texture,
+                        level,
+                        x,
+                        y,
+                    };
+                    let mut shard = self.shards[other.shard()].write().unwrap();
+                    if shard.map.contains_key(&other) {
+                        continue;
+                    }
+                    // Never used so these are the first to go
+                    let tile = pixels.tile(other);
+                    if shard.fits(&tile) {
+                        tile.referenced.store(false, Ordering::Relaxed);
+                        shard.insert(tile);
+                    }
+                }
+            }
+        }
+        color
+    }
+}
+
+fn decoder(path: &Path) -> Result<png::Decoder<File>> {
+    let file = File::open(path).with_context(|| format!("failed to open texture: {:?}", path))?;
+    let mut decoder = png::Decoder::new(file);
+    // Palettes and low bit depths get expanded to at least 8 bit channels
+    decoder.set_transformations(png::Transformations::EXPAND);
+    Ok(decoder)
+}
+
+// Full resolution first, each level half the size of the one before down to a single texel
+fn decode_mipmapped(path: &Path) -> Result<Vec<MipLevel>> {
+    let (info, mut reader) = decoder(path)?
+        .read_info()
+        .with_context(|| format!("failed to read texture header: {:?}", path))?;
+    let mut buffer = vec![0; info.buffer_size()];
+    reader
+        .next_frame(&mut buffer)
+        .with_context(|| format!("failed to read texture data: {:?}", path))?;
+
+    let channels = match info.color_type {
+        png::ColorType::Grayscale => 1,
+        png::ColorType::GrayscaleAlpha => 2,
+        png::ColorType::RGB => 3,
+        png::ColorType::RGBA => 4,
+        png::ColorType::Indexed => bail!("unexpanded palette in texture: {:?}", path),
+    };
+    let samples: Vec<f64> = match info.bit_depth {
+        png::BitDepth::Sixteen => buffer
+            .chunks_exact(2)
+            .map(|b| f64::from(u16::from_be_bytes([b[0], b[1]])) / 65535.0)
+            .collect(),
+        _ => buffer.iter().map(|b| f64::from(*b) / 255.0).collect(),
+    };
+    let pixels = samples
+        .chunks_exact(channels)
+        .map(|c| match channels {
+            1 | 2 => [c[0] as f32; 3],
+            _ => [c[0] as f32, c[1] as f32, c[2] as f32],
+        })
+        .collect();
+
+    let mut levels = vec![MipLevel {
+        width: info.width as usize,
+        height: info.height as usize,
+        pixels,
+    }];
+    while let Some(next) = levels[levels.len() - 1].downsample() {
+        levels.push(next);
+    }
+    Ok(levels)
+}
+
+struct MipLevel {
+    width: usize,
+    height: usize,
+    // Single precision is plenty for what came from 8 or 16 bit channels, at half the memory
+    pixels: Vec<[f32; 3]>,
+}
+
+impl MipLevel {
+    // The image repeats so odd sizes wrap around for the last texel when downsampling
+    fn texel(&self, i: usize, j: usize) -> Vec3 {
+        let [r, g, b] = self.pixels[(j % self.height) * self.width + i % self.width];
+        Vec3::new(f64::from(r), f64::from(g), f64::from(b))
+    }
+
+    // Box filtered to half the size, None once there is a single texel
+    fn downsample(&self) -> Option<MipLevel> {
+        if self.width == 1 && self.height == 1 {
+            return None;
+        }
+        let width = self.width.div_ceil(2);
+        let height = self.height.div_ceil(2);
+        let mut pixels = Vec::with_capacity(width * height);
+        for j in 0..height {
+            for i in 0..width {
+                let sum = self.texel(2 * i, 2 * j)
+                    + self.texel(2 * i + 1, 2 * j)
+                    + self.texel(2 * i, 2 * j + 1)
+                    + self.texel(2 * i + 1, 2 * j + 1);
+                let mean = sum / 4.0;
+                pixels.push([mean.x() as f32, mean.y() as f32, mean.z() as f32]);
+            }
+        }
+        Some(MipLevel {
+            width,
+            height,
+            pixels,
+        })
+    }
+
+    // Tiles along the right and bottom edges are cut short
+    fn tile(&self, key: TileKey) -> Tile {
+        let (x0, y0) = (key.x * TILE_SIZE, key.y * TILE_SIZE);
+        let width = TILE_SIZE.min(self.width - x0);
+        let height = TILE_SIZE.min(self.height - y0);
+        let mut pixels = Vec::with_capacity(width * height);
+        for j in y0..y0 + height {
+            pixels
+                .extend_from_slice(&self.pixels[j * self.width + x0..j * self.width + x0 + width]);
+        }
+        Tile {
+            key,
+            width,
+            pixels,
+            referenced: AtomicBool::new(true),
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::geom::tests::components;
+
+    const TILE_BYTES: usize = TILE_SIZE * TILE_SIZE * 12;
+
+    // Red and green count along the row and column so neighbouring texels differ
+    pub fn texture_file(name: &str, width: u32, height: u32) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("{}-{}.png", name, std::process::id()));
+        let mut data = Vec::new();
+        for j in 0..height {
+            for i in 0..width {
+                data.extend_from_slice(&[i as u8, j as u8, (i ^ j) as u8]);
+            }
+        }
+        let mut encoder = png::Encoder::new(File::create(&path).unwrap(), width, height);
+        encoder.set_color(png::ColorType::RGB);
+        encoder.set_depth(png::BitDepth::Eight);
+        let mut writer = encoder.write_header().unwrap();
+        writer.write_image_data(&data).unwrap();
+        path
+    }
+
+    fn expected(i: usize, j: usize) -> [f64; 3] {
+        [i as u8, j as u8, (i ^ j) as u8].map(|c| f64::from(f32::from(c) / 255.0))
+    }
+
+    fn texel(cache: &TextureCache, level: usize, i: usize, j: usize) -> [f64; 3] {
+        components(&cache.tiles.texel(0, level, i, j))
+    }
+
+    fn used(tiles: &TileCache) -> Vec<usize> {
+        (tiles.shards.iter())
+            .map(|shard| shard.read().unwrap().used)
+            .collect()
+    }
+
+    #[test]
+    fn each_level_is_the_one_above_box_filtered_to_half() {
+        // Odd sizes wrap around for the last row and column
+        let (width, height) = (37, 20);
+        let path = texture_file("cache-levels", width, height);
+        let levels = decode_mipmapped(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let sizes: Vec<_> = levels.iter().map(|l| (l.width, l.height)).collect();
+        let expected_sizes = [(37, 20), (19, 10), (10, 5), (5, 3), (3, 2), (2, 1), (1, 1)];
+        assert_eq!(sizes, expected_sizes);
+        for j in 0..height as usize {
+            for i in 0..width as usize {
+                assert_eq!(components(&levels[0].texel(i, j)), expected(i, j));
+            }
+        }
+        for pair in levels.windows(2) {
+            let (above, level) = (&pair[0], &pair[1]);
+            for j in 0..level.height {
+                for i in 0..level.width {
+                    let sum = above.texel(2 * i, 2 * j)
+                        + above.texel(2 * i + 1, 2 * j)
+                        + above.texel(2 * i, 2 * j + 1)
+                        + above.texel(2 * i + 1, 2 * j + 1);
+                    assert!((level.texel(i, j) - sum / 4.0).length() < 1e-6);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn clock_eviction_stays_under_budget() {
+        let path = texture_file("cache-budget", 512, 256);
+        let cache = TextureCache::new(SHARDS * TILE_BYTES);
+        cache.get(&path).unwrap();
+        let mut state = 1u64;
+        for _ in 0..100 {
+            state = mix_bits(state);
+            let level = (state % 4) as usize;
+            let (i, j) = (
+                (state >> 8) as usize % (512 >> level),
+                (state >> 32) as usize % (256 >> level),
+            );
+            texel(&cache, level, i, j);
+            for used in used(&cache.tiles) {
+                assert!(used <= TILE_BYTES);
+            }
+        }
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn evicted_tiles_come_back_the_same() {
+        let path = texture_file("cache-refetch", 512, 256);
+        let cache = TextureCache::new(SHARDS * TILE_BYTES);
+        cache.get(&path).unwrap();
+        for pass in 0..2 {
+            // A tile at a time so that each is brought in once a pass, and spot checked all over
+            for (x, y) in (0..4).flat_map(|y| (0..8).map(move |x| (x, y))) {
+                for (i, j) in (0..TILE_SIZE)
+                    .step_by(7)
+                    .flat_map(|j| (0..TILE_SIZE).step_by(5).map(move |i| (i, j)))
+                {
+                    let (i, j) = (x * TILE_SIZE + i, y * TILE_SIZE + j);
+                    assert_eq!(texel(&cache, 0, i, j), expected(i, j), "pass {}", pass);
+                }
+            }
+            // There is room for half of the first level's 32 tiles at most, so the second pass refetches evicted ones
+            let held = (cache.tiles.shards.iter())
+                .map(|shard| {
+                    shard
+                        .read()
+                        .unwrap()
+                        .map
+                        .keys()
+                        .filter(|key| key.level == 0)
+                        .count()
+                })
+                .sum::<usize>();
+            assert!(held <= SHARDS);
+        }
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn textures_of_the_same_file_are_shared() {
+        let path = texture_file("cache-shared", 4, 4);
+        let cache = TextureCache::new(SHARDS * TILE_BYTES);
+        let first = cache.get(&path).unwrap();
+        let dir = path.parent().unwrap();
+        let other = dir.join(".").join(path.file_name().unwrap());
+        let second = cache.get(&other).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(Arc::ptr_eq(&first, &second));
+        assert_eq!(cache.tiles.sources.read().unwrap().len(), 1);
+    }
+}
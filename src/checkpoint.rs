@@ -0,0 +1,160 @@
+use super::draw::Film;
+use anyhow::{bail, Context, Result};
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+const MAGIC: &[u8; 8] = b"RTCHECK2";
+// Far longer than the settings of any render, so a corrupt length isn't trusted with an allocation
+const MAX_SETTINGS: usize = 1 << 16;
+
+/**
+ * Saves a render stopped between passes so that it can carry on as if it never stopped.
+ * Samples are a pure function of the seed and their index, so the film and the index of the next sample are the
+ * sampler's whole state. Settings describe everything else the image depends on and a checkpoint only resumes
+ * with the same ones
+ */
+pub fn save(path: &Path, settings: &str, next_sample: u32, film: &Film) -> Result<()> {
+    // Written aside and renamed over the old one, a crash mid write leaves the previous checkpoint intact
+    let partial = path.with_file_name(format!(
+        "{}.partial",
+        path.file_name()
+            .and_then(|n| n.to_str())
+            .unwrap_or("checkpoint")
+    ));
+    let file = File::create(&partial)
+        .with_context(|| format!("failed to open checkpoint: {:?}", partial))?;
+    let mut writer = BufWriter::new(file);
+    writer.write_all(MAGIC)?;
+    writer.write_all(&(settings.len() as u32).to_le_bytes())?;
+    writer.write_all(settings.as_bytes())?;
+    writer.write_all(&next_sample.to_le_bytes())?;
+    film.write(&mut writer)?;
+    writer
+        .into_inner()
+        .map_err(|e| e.into_error())
+        .and_then(|file| file.sync_all())
+        .with_context(|| format!("failed to write checkpoint: {:?}", partial))?;
+    fs::rename(&partial, path).with_context(|| format!("failed to replace checkpoint: {:?}", path))
+}
+
+/**
+ * The film and the index of the next sample to render.
+ * The film has to be width by height and no more than samples_per_pixel can have been rendered
+ */
+pub fn load(
+    path: &Path,
+    settings: &str,
+    width: u32,
+    height: u32,
+    samples_per_pixel: u32,
+) -> Result<(Film, u32)> {
+    let file =
+        File::open(path).with_context(|| format!("failed to open checkpoint: {:?}", path))?;
+    let mut reader = BufReader::new(file);
+    let mut magic = [0; 8];
+    reader
+        .read_exact(&mut magic)
+        .with_context(|| format!("failed to read checkpoint: {:?}", path))?;
+    if &magic != MAGIC {
+        bail!("not a checkpoint: {:?}", path);
+    }
+    let mut word = [0; 4];
+    reader.read_exact(&mut word)?;
+    let length = u32::from_le_bytes(word) as usize;
+    if length > MAX_SETTINGS {
+        bail!(
+            "corrupt checkpoint {:?}, its settings are {} bytes",
+            path,
+            length
+        );
+    }
+    let mut saved = vec![0; length];
+    reader.read_exact(&mut saved)?;
+    let saved = String::from_utf8_lossy(&saved);
+    if saved != settings {
+        bail!(
+            "checkpoint {:?} is of a different render, it was started with {}",
+            path,
+            saved
+        );
+    }
+    reader.read_exact(&mut word)?;
+    let next_sample = u32::from_le_bytes(word);
+    if next_sample > samples_per_pixel {
+        bail!(
+            "checkpoint {:?} is {} samples in, more than the {} asked for",
+            path,
+            next_sample,
+            samples_per_pixel
+        );
+    }
+    let film = Film::read(&mut reader, width, height)
+        .with_context(|| format!("failed to read checkpoint: {:?}", path))?;
+    Ok((film, next_sample))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::draw::{self, tests, Adaptive};
+
+    fn bytes(film: &Film) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        film.write(&mut bytes).unwrap();
+        bytes
+    }
+
+    #[test]
+    fn resumed_render_matches_one_that_never_stopped() {
+        let mut options = tests::options(24);
+        options.adaptive = Some(Adaptive {
+            min_samples: 8,
+            threshold: 0.05,
+        });
+        let path = std::env::temp_dir().join(format!("checkpoint-test-{}", std::process::id()));
+        let scene = tests::scene();
+        let saved = tests::render(&scene, 20, 15, &options, &[4, 6]);
+        save(&path, "settings", 10, &saved).unwrap();
+        assert!(load(&path, "other settings", 20, 15, 24).is_err());
+
+        let (mut film, next_sample) = load(&path, "settings", 20, 15, 24).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(next_sample, 10);
+        assert_eq!(bytes(&film), bytes(&saved));
+        // Built again like a resumed render would, with the same ids for the same objects
+        let (camera, world) = tests::scene();
+        draw::render_pass(&mut film, &camera, &world, &options, 10..24, &|_| {});
+        let straight = tests::render(&tests::scene(), 20, 15, &options, &[4, 6, 14]);
+        assert_eq!(bytes(&film), bytes(&straight));
+    }
+
+    #[test]
+    fn checkpoints_of_other_sizes_and_corrupt_ones_are_rejected() {
+        let path = std::env::temp_dir().join(format!("checkpoint-bad-{}", std::process::id()));
+        let options = tests::options(8);
+        let film = tests::render(&tests::scene(), 6, 4, &options, &[2]);
+        save(&path, "settings", 2, &film).unwrap();
+        assert!(load(&path, "settings", 6, 4, 8).is_ok());
+        assert!(load(&path, "settings", 4, 6, 8).is_err());
+        assert!(load(&path, "settings", 6, 5, 8).is_err());
+        // Further along than the render asks for
+        save(&path, "settings", 9, &film).unwrap();
+        assert!(load(&path, "settings", 6, 4, 8).is_err());
+
+        // A settings length that would allocate gigabytes, and a film size that overflows
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend_from_slice(&u32::MAX.to_le_bytes());
+        fs::write(&path, &bytes).unwrap();
+        assert!(load(&path, "settings", 6, 4, 8).is_err());
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend_from_slice(&8u32.to_le_bytes());
+        bytes.extend_from_slice(b"settings");
+        for v in [2, u32::MAX, u32::MAX].iter() {
+            bytes.extend_from_slice(&v.to_le_bytes());
+        }
+        fs::write(&path, &bytes).unwrap();
+        assert!(load(&path, "settings", 6, 4, 8).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+}
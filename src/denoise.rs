@@ -0,0 +1,174 @@
+use super::draw::{Aovs, Framebuffer};
+use super::geom::*;
+use rayon::prelude::*;
+
+// Half the width of the square window each pixel is filtered over
+const RADIUS: i64 = 8;
+const SIGMA_SPATIAL: f64 = 4.0;
+const SIGMA_ALBEDO: f64 = 0.1;
+const SIGMA_NORMAL: f64 = 0.1;
+// Relative to the depth of the pixel being filtered
+const SIGMA_DEPTH: f64 = 0.05;
+// How many standard deviations of noise apart two pixels may be and still be blended
+const COLOR_TOLERANCE: f64 = 2.0;
+
+/**
+ * Joint cross bilateral filter guided by the aovs, run on linear radiance before tone mapping.
+ * Dividing out the albedo first means only the lighting gets blurred and textures stay sharp.
+ * Neighbors only count when their albedo, normal and depth match, and when their lighting is within the noise
+ * the variance estimates say both pixels have, so edges in shadows and highlights survive as well.
+ * Pixels where the camera saw the sky are left alone and never blended into the rest
+ */
+pub fn denoise(image: &Framebuffer, aovs: &Aovs) -> Vec<Vec3> {
+    let (width, height) = (image.width as i64, image.height as i64);
+    let hit = |i: usize| aovs.depth[i].is_finite();
+    let demodulate = |c: f64| c.max(1e-3);
+    let albedo: Vec<Vec3> = aovs.albedo.iter().map(|a| a.map(demodulate)).collect();
+    let lighting: Vec<Vec3> = image
+        .pixels
+        .iter()
+        .zip(albedo.iter())
+        .map(|(p, a)| Vec3::new(p.x() / a.x(), p.y() / a.y(), p.z() / a.z()))
+        .collect();
+    // The variance was of radiance, dividing by the albedo scales it by the square
+    let variance: Vec<f64> = aovs
+        .variance
+        .iter()
+        .zip(albedo.iter())
+        .map(|(v, a)| v / a.luminance().powi(2))
+        .collect();
+
+    let mut rows: Vec<Vec<Vec3>> = Vec::with_capacity(height as usize);
+    (0..image.height)
+        .into_par_iter()
+        .map(|y| {
+            let y = i64::from(y);
+            (0..width)
+                .map(|x| {
+                    let p = (y * width + x) as usize;
+                    if !hit(p) {
+                        return image.pixels[p];
+                    }
+                    let mut sum = Vec3::zero();
+                    let mut total = 0.0;
+                    for qy in (y - RADIUS).max(0)..(y + RADIUS + 1).min(height) {
+                        for qx in (x - RADIUS).max(0)..(x + RADIUS + 1).min(width) {
+                            let q = (qy * width + qx) as usize;
+                            if !hit(q) {
+                                continue;
+                            }
+                            let spatial = ((qx - x).pow(2) + (qy - y).pow(2)) as f64
+                                / (2.0 * SIGMA_SPATIAL * SIGMA_SPATIAL);
+                            let albedo_distance = (aovs.albedo[q] - aovs.albedo[p])
+                                .length_squared()
+                                / (2.0 * SIGMA_ALBEDO * SIGMA_ALBEDO);
+                            let normal_distance =
+                                (1.0 - aovs.normal[q].dot(&aovs.normal[p])).max(0.0) / SIGMA_NORMAL;
+                            let depth_distance = ((aovs.depth[q] - aovs.depth[p])
+                                / (SIGMA_DEPTH * aovs.depth[p].max(1e-9)))
+                            .powi(2)
+                                / 2.0;
+                            let color_distance = (lighting[q].luminance()
+                                - lighting[p].luminance())
+                            .powi(2)
+                                / (COLOR_TOLERANCE * COLOR_TOLERANCE * (variance[p] + variance[q])
+                                    + 1e-6);
+                            let weight = (-(spatial
+                                + albedo_distance
+                                + normal_distance
+                                + depth_distance
+                                + color_distance))
+                                .exp();
+                            sum += weight * lighting[q];
+                            total += weight;
+                        }
+                    }
+                    // The pixel itself always has weight one so total is never zero
+                    let filtered = sum / total;
+                    Vec3::new(
+                        filtered.x() * albedo[p].x(),
+                        filtered.y() * albedo[p].y(),
+                        filtered.z() * albedo[p].z(),
+                    )
+                })
+                .collect()
+        })
+        .collect_into_vec(&mut rows);
+    rows.into_iter().flatten().collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SIZE: u32 = 24;
+
+    // A square image whose left and right halves can differ in radiance, albedo and normal
+    fn halves(
+        radiance: [f64; 2],
+        albedo: [f64; 2],
+        normal: [Vec3; 2],
+        variance: f64,
+    ) -> (Framebuffer, Aovs) {
+        let side = |i: usize| usize::from(i as u32 % SIZE >= SIZE / 2);
+        let count = (SIZE * SIZE) as usize;
+        let grey = |c: f64| Vec3::new(c, c, c);
+        let image = Framebuffer {
+            width: SIZE,
+            height: SIZE,
+            pixels: (0..count).map(|i| grey(radiance[side(i)])).collect(),
+            samples: vec![16; count],
+            aovs: None,
+        };
+        let aovs = Aovs {
+            depth: vec![5.0; count],
+            normal: (0..count).map(|i| normal[side(i)]).collect(),
+            albedo: (0..count).map(|i| grey(albedo[side(i)])).collect(),
+            position: vec![Vec3::zero(); count],
+            material_id: vec![1; count],
+            object_id: vec![1; count],
+            variance: vec![variance; count],
+        };
+        (image, aovs)
+    }
+
+    // The brightness of the pixel on the left of the edge and the one on its right, halfway down
+    fn across_edge(pixels: &[Vec3]) -> (f64, f64) {
+        let row = (SIZE / 2 * SIZE) as usize;
+        let edge = (SIZE / 2) as usize;
+        (pixels[row + edge - 1].y(), pixels[row + edge].y())
+    }
+
+    #[test]
+    fn constant_image_is_unchanged() {
+        let up = Vec3::new(0.0, 0.0, 1.0);
+        let (mut image, aovs) = halves([0.3; 2], [0.6; 2], [up; 2], 0.01);
+        image.pixels = vec![Vec3::new(0.3, 0.5, 0.7); image.pixels.len()];
+        for (p, q) in denoise(&image, &aovs).iter().zip(image.pixels.iter()) {
+            assert!((*p - *q).length() < 1e-12);
+        }
+    }
+
+    #[test]
+    fn normal_edges_are_not_blurred_across() {
+        let up = Vec3::new(0.0, 0.0, 1.0);
+        let side = Vec3::new(1.0, 0.0, 0.0);
+        // So noisy that the lighting alone would let the halves blend
+        let (image, aovs) = halves([0.2, 0.8], [0.5; 2], [up, side], 10.0);
+        let (left, right) = across_edge(&denoise(&image, &aovs));
+        assert!((left - 0.2).abs() < 1e-3 && (right - 0.8).abs() < 1e-3);
+
+        let (image, aovs) = halves([0.2, 0.8], [0.5; 2], [up, up], 10.0);
+        let (left, right) = across_edge(&denoise(&image, &aovs));
+        assert!(left > 0.3 && right < 0.7);
+    }
+
+    #[test]
+    fn albedo_edges_are_not_blurred_across() {
+        let up = Vec3::new(0.0, 0.0, 1.0);
+        // The lighting differs too, so blending would show once the albedo is put back
+        let (image, aovs) = halves([0.1, 0.8], [0.2, 0.8], [up; 2], 10.0);
+        let (left, right) = across_edge(&denoise(&image, &aovs));
+        assert!((left - 0.1).abs() < 1e-3 && (right - 0.8).abs() < 1e-3);
+    }
+}
@@ -0,0 +1,567 @@
+use super::draw::{self, Camera, Film, Options, TileBounds, TileResult};
+use super::geom::*;
+use anyhow::{bail, Context, Result};
+use std::collections::VecDeque;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread;
+use std::time::Duration;
+
+const MAGIC: &[u8; 8] = b"RTWORK02";
+const MESSAGE_DONE: u8 = 0;
+const MESSAGE_TILE: u8 = 1;
+// From workers, between tiles that take a while
+const MESSAGE_ALIVE: u8 = 2;
+// Limits on what a worker takes from the coordinator, far above what a command line needs
+const MAX_ARGS: u32 = 1024;
+const MAX_ARG_LENGTH: u32 = 1 << 16;
+// The most tiles one worker is given at once
+const MAX_CONCURRENCY: u32 = 1024;
+// How often the coordinator looks for new workers, and whether it is done
+const ACCEPT_POLL: Duration = Duration::from_millis(50);
+
+// Everything a tile is rendered from, workers build theirs from the options the coordinator passes on
+pub struct Job {
+    pub width: u32,
+    pub height: u32,
+    pub camera: Camera,
+    pub world: Box<dyn Hittable + Send + Sync>,
+    pub options: Options,
+}
+
+// Tiles waiting for a worker and the results of those that are done, by their index in render order
+struct Queue {
+    pending: VecDeque<usize>,
+    results: Vec<Option<TileResult>>,
+    finished: usize,
+}
+
+/**
+ * Hands out every tile of the image with all of its samples to whichever workers connect, and gathers the film
+ * from what they send back.
+ * The protocol is little endian over TCP. A worker opens with MAGIC and how many tiles it renders at once, gets the
+ * command line options to build the scene from and how often to say it is still there, and is then sent tiles
+ * until the coordinator says it is done.
+ * A worker that drops out or isn't heard from within the timeout has its unfinished tiles handed to the others,
+ * and the render waits for new workers if none are left. Tiles are merged in the order a local render would merge
+ * them, so the image comes out the same
+ */
+pub fn serve(
+    listener: TcpListener,
+    args: Vec<String>,
+    job: &Job,
+    timeout: Duration,
+    progress: bool,
+) -> Result<Film> {
+    let (width, height) = (job.width, job.height);
+    let tiles: Arc<Vec<TileBounds>> = Arc::new(
+        draw::tile_origins(width, height, &job.options)
+            .into_iter()
+            .map(|(x, y)| TileBounds::new(width, height, &job.options, x, y))
+            .collect(),
+    );
+    let samples = job.options.samples_per_pixel;
+    let state = Arc::new((
+        Mutex::new(Queue {
+            pending: (0..tiles.len()).collect(),
+            results: (0..tiles.len()).map(|_| None).collect(),
+            finished: 0,
+        }),
+        Condvar::new(),
+    ));
+    eprintln!(
+        "waiting for workers on {}, {} tiles to render",
+        listener.local_addr()?,
+        tiles.len()
+    );
+
+    // Accepting stops once every tile is done, so no one can connect after serve returns
+    listener.set_nonblocking(true)?;
+    let accepting = state.clone();
+    let args = Arc::new(args);
+    let acceptor = thread::spawn(move || loop {
+        {
+            let queue = lock(&accepting);
+            if queue.finished == queue.results.len() {
+                return;
+            }
+        }
+        let stream = match listener.accept() {
+            Ok((stream, _)) => stream,
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                thread::sleep(ACCEPT_POLL);
+                continue;
+            }
+            Err(e) => {
+                eprintln!("failed to accept a worker: {}", e);
+                continue;
+            }
+        };
+        let (state, tiles, args) = (accepting.clone(), tiles.clone(), args.clone());
+        thread::spawn(move || {
+            let peer = stream
+                .peer_addr()
+                .map_or("an unknown address".to_string(), |a| a.to_string());
+            eprintln!("worker {} connected", peer);
+            let mut in_flight = InFlight {
+                state: &state,
+                tiles: Vec::new(),
+            };
+            // Some platforms pass the listener's non-blocking mode on to the streams it accepts
+            let served = stream
+                .set_nonblocking(false)
+                .and_then(|_| stream.set_read_timeout(Some(timeout)))
+                .and_then(|_| stream.set_write_timeout(Some(timeout)))
+                .and_then(|_| {
+                    serve_worker(
+                        stream,
+                        &tiles,
+                        &args,
+                        samples,
+                        timeout,
+                        &mut in_flight,
+                        progress,
+                    )
+                });
+            match served {
+                Ok(()) => eprintln!("worker {} finished", peer),
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                    eprintln!("worker {} dropped out", peer)
+                }
+                Err(e)
+                    if e.kind() == io::ErrorKind::WouldBlock
+                        || e.kind() == io::ErrorKind::TimedOut =>
+                {
+                    eprintln!("worker {} stopped responding", peer)
+                }
+                Err(e) => eprintln!("worker {} dropped out: {}", peer, e),
+            }
+        });
+    });
+
+    let mut queue = lock(&state);
+    while queue.finished < queue.results.len() {
+        queue = wait(&state, queue);
+    }
+    drop(queue);
+    // An error only means it panicked, which leaves nothing to wait for
+    let _ = acceptor.join();
+    let queue = lock(&state);
+    let mut film = Film::new(width, height);
+    for result in queue.results.iter().flatten() {
+        film.merge(result);
+    }
+    Ok(film)
+}
+
+// The tiles a worker has been sent and not yet returned, which go back to the queue however it stops, panics included
+struct InFlight<'a> {
+    state: &'a (Mutex<Queue>, Condvar),
+    tiles: Vec<usize>,
+}
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        if self.tiles.is_empty() {
+            return;
+        }
+        let mut queue = lock(self.state);
+        // Back to the front, they were the next to be rendered
+        for index in self.tiles.drain(..).rev() {
+            queue.pending.push_front(index);
+        }
+        self.state.1.notify_all();
+    }
+}
+
+// Keeps one worker as busy as it says it can be until every tile is done
+fn serve_worker(
+    stream: TcpStream,
+    tiles: &[TileBounds],
+    args: &[String],
+    samples: u32,
+    timeout: Duration,
+    in_flight: &mut InFlight,
+    progress: bool,
+) -> io::Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = BufWriter::new(stream);
+    let mut magic = [0; 8];
+    reader.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        return Err(invalid("not a worker"));
+    }
+    let concurrency = read_u32(&mut reader)?.clamp(1, MAX_CONCURRENCY) as usize;
+    writer.write_all(&(args.len() as u32).to_le_bytes())?;
+    for arg in args {
+        writer.write_all(&(arg.len() as u32).to_le_bytes())?;
+        writer.write_all(arg.as_bytes())?;
+    }
+    // Often enough that a heartbeat or two can go astray without the worker being given up on
+    let heartbeat = (timeout / 4).as_millis().clamp(1, u128::from(u32::MAX)) as u32;
+    writer.write_all(&heartbeat.to_le_bytes())?;
+
+    let state = in_flight.state;
+    loop {
+        let mut assigned = Vec::new();
+        {
+            let mut queue = lock(state);
+            loop {
+                while in_flight.tiles.len() + assigned.len() < concurrency {
+                    match queue.pending.pop_front() {
+                        Some(index) => assigned.push(index),
+                        None => break,
+                    }
+                }
+                // With nothing to do the worker still waits around for tiles others drop
+                if !in_flight.tiles.is_empty()
+                    || !assigned.is_empty()
+                    || queue.finished == tiles.len()
+                {
+                    break;
+                }
+                queue = wait(state, queue);
+            }
+        }
+        in_flight.tiles.extend(assigned.iter().copied());
+        if in_flight.tiles.is_empty() {
+            writer.write_all(&[MESSAGE_DONE])?;
+            return writer.flush();
+        }
+        for index in assigned {
+            let tile = &tiles[index];
+            writer.write_all(&[MESSAGE_TILE])?;
+            for v in [index as u32, tile.x, tile.y, samples].iter() {
+                writer.write_all(&v.to_le_bytes())?;
+            }
+        }
+        writer.flush()?;
+
+        let mut message = [MESSAGE_ALIVE];
+        while message[0] == MESSAGE_ALIVE {
+            reader.read_exact(&mut message)?;
+        }
+        if message[0] != MESSAGE_TILE {
+            return Err(invalid("sent something other than a tile"));
+        }
+        let index = read_u32(&mut reader)? as usize;
+        let position = in_flight
+            .tiles
+            .iter()
+            .position(|i| *i == index)
+            .ok_or_else(|| invalid("sent a tile it was not given"))?;
+        let result = TileResult::read(&mut reader, &tiles[index])?;
+        in_flight.tiles.swap_remove(position);
+        let mut queue = lock(state);
+        queue.results[index] = Some(result);
+        queue.finished += 1;
+        if progress {
+            eprintln!("{} of {} tiles done", queue.finished, tiles.len());
+        }
+        state.1.notify_all();
+    }
+}
+
+// The queue is only changed in small steps that leave it whole, so a thread that panicked holding it did no harm
+fn lock(state: &(Mutex<Queue>, Condvar)) -> MutexGuard<'_, Queue> {
+    state.0.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+fn wait<'a>(
+    state: &(Mutex<Queue>, Condvar),
+    queue: MutexGuard<'a, Queue>,
+) -> MutexGuard<'a, Queue> {
+    state.1.wait(queue).unwrap_or_else(|e| e.into_inner())
+}
+
+/**
+ * Renders tiles for the coordinator at address until it has no more.
+ * Tiles render on the thread pool while this thread reads the next ones
+ */
+pub fn work(address: &str, build: impl Fn(&[String]) -> Result<Job>) -> Result<()> {
+    let stream =
+        TcpStream::connect(address).with_context(|| format!("failed to connect to {}", address))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let writer = Arc::new(Mutex::new(BufWriter::new(stream)));
+    {
+        let mut writer = writer.lock().unwrap();
+        writer.write_all(MAGIC)?;
+        writer.write_all(&(rayon::current_num_threads() as u32).to_le_bytes())?;
+        writer.flush()?;
+    }
+    let count = read_u32(&mut reader).with_context(|| "coordinator hung up")?;
+    if count > MAX_ARGS {
+        bail!("coordinator sent {} arguments", count);
+    }
+    let args = (0..count)
+        .map(|_| {
+            let length = read_u32(&mut reader)?;
+            if length > MAX_ARG_LENGTH {
+                bail!("coordinator sent an argument {} bytes long", length);
+            }
+            let mut bytes = vec![0; length as usize];
+            reader.read_exact(&mut bytes)?;
+            Ok(String::from_utf8_lossy(&bytes).into_owned())
+        })
+        .collect::<Result<Vec<String>>>()?;
+    let heartbeat = Duration::from_millis(u64::from(read_u32(&mut reader)?));
+    let job = Arc::new(build(&args)?);
+    let film = Arc::new(Film::new(job.width, job.height));
+    // The only tiles and sample counts the coordinator can send, anything else would render out of bounds
+    let origins = draw::tile_origins(job.width, job.height, &job.options);
+    eprintln!("rendering {}", args.join(" "));
+    // Until the connection fails, or everything holding on to the writer is gone once the work is done
+    let alive = Arc::downgrade(&writer);
+    thread::spawn(move || loop {
+        thread::sleep(heartbeat);
+        let writer = match alive.upgrade() {
+            Some(writer) => writer,
+            None => return,
+        };
+        let mut writer = writer.lock().unwrap();
+        if writer
+            .write_all(&[MESSAGE_ALIVE])
+            .and_then(|_| writer.flush())
+            .is_err()
+        {
+            return;
+        }
+    });
+
+    loop {
+        let mut message = [0];
+        reader
+            .read_exact(&mut message)
+            .with_context(|| "coordinator hung up")?;
+        if message[0] == MESSAGE_DONE {
+            return Ok(());
+        }
+        let index = read_u32(&mut reader)?;
+        let (x, y) = (read_u32(&mut reader)?, read_u32(&mut reader)?);
+        let samples = read_u32(&mut reader)?;
+        if !origins.contains(&(x, y)) {
+            bail!(
+                "coordinator sent a tile at {}, {} that is not in the image",
+                x,
+                y
+            );
+        }
+        if samples > job.options.samples_per_pixel {
+            bail!(
+                "coordinator asked for {} samples, more than the {} of the render",
+                samples,
+                job.options.samples_per_pixel
+            );
+        }
+        let (job, film, writer) = (job.clone(), film.clone(), writer.clone());
+        rayon::spawn(move || {
+            let result = draw::render_tile(
+                &film,
+                &job.camera,
+                &job.world,
+                &job.options,
+                &(0..samples),
+                x,
+                y,
+            );
+            let mut writer = writer.lock().unwrap();
+            let sent = writer
+                .write_all(&[MESSAGE_TILE])
+                .and_then(|_| writer.write_all(&index.to_le_bytes()))
+                .and_then(|_| result.write(&mut *writer))
+                .and_then(|_| writer.flush());
+            // The coordinator is gone, reading the next message fails as well
+            if let Err(e) = sent {
+                eprintln!("failed to send tile {}: {}", index, e);
+            }
+        });
+    }
+}
+
+fn read_u32<R: Read>(reader: &mut R) -> io::Result<u32> {
+    let mut bytes = [0; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::draw::tests;
+    use std::net::SocketAddr;
+
+    fn job() -> Job {
+        let (camera, world) = tests::scene();
+        Job {
+            width: 30,
+            height: 20,
+            camera,
+            world,
+            options: tests::options(6),
+        }
+    }
+
+    // Connects like a worker and takes the first tiles it is sent, leaving the reader where the tiles ended
+    fn take_tiles(
+        address: SocketAddr,
+        count: u32,
+    ) -> (BufReader<TcpStream>, TcpStream, Vec<[u32; 4]>) {
+        let mut stream = TcpStream::connect(address).unwrap();
+        stream.write_all(MAGIC).unwrap();
+        stream.write_all(&count.to_le_bytes()).unwrap();
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        for _ in 0..read_u32(&mut reader).unwrap() {
+            let mut arg = vec![0; read_u32(&mut reader).unwrap() as usize];
+            reader.read_exact(&mut arg).unwrap();
+        }
+        read_u32(&mut reader).unwrap();
+        let tiles = (0..count)
+            .map(|_| {
+                let mut message = [0];
+                reader.read_exact(&mut message).unwrap();
+                assert_eq!(message[0], MESSAGE_TILE);
+                let mut tile = [0; 4];
+                for v in tile.iter_mut() {
+                    *v = read_u32(&mut reader).unwrap();
+                }
+                tile
+            })
+            .collect();
+        (reader, stream, tiles)
+    }
+
+    // Waits for the coordinator to hang up on a worker
+    fn hung_up(mut reader: BufReader<TcpStream>) {
+        let mut rest = Vec::new();
+        reader.read_to_end(&mut rest).unwrap();
+    }
+
+    #[test]
+    fn tiles_of_workers_that_fail_go_to_the_others() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let workers = thread::spawn(move || {
+            // Drops out with tiles in flight
+            let (_, stream, _) = take_tiles(address, 3);
+            drop(stream);
+            // Sends back a tile that is bigger than any it was given
+            let (reader, mut stream, tiles) = take_tiles(address, 1);
+            let [index, x, y, _] = tiles[0];
+            stream.write_all(&[MESSAGE_TILE]).unwrap();
+            for v in [index, x, y, u32::MAX, u32::MAX].iter() {
+                stream.write_all(&v.to_le_bytes()).unwrap();
+            }
+            for v in [0, 0, i64::MAX, i64::MAX].iter() {
+                stream.write_all(&v.to_le_bytes()).unwrap();
+            }
+            hung_up(reader);
+            // Says it is alive and then sends something else
+            let (reader, mut stream, _) = take_tiles(address, 1);
+            stream
+                .write_all(&[MESSAGE_ALIVE, MESSAGE_ALIVE, 7])
+                .unwrap();
+            hung_up(reader);
+            work(&address.to_string(), |args| {
+                assert_eq!(args, ["scene", "test"]);
+                Ok(job())
+            })
+            .unwrap();
+        });
+
+        let job = job();
+        let args = vec!["scene".to_string(), "test".to_string()];
+        let film = serve(listener, args, &job, Duration::from_secs(30), false).unwrap();
+        workers.join().unwrap();
+
+        let local = tests::render(&tests::scene(), 30, 20, &job.options, &[6]);
+        let bytes = |film: &Film| {
+            let mut bytes = Vec::new();
+            film.write(&mut bytes).unwrap();
+            bytes
+        };
+        assert_eq!(bytes(&film), bytes(&local));
+    }
+
+    #[test]
+    fn quiet_workers_time_out() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let workers = thread::spawn(move || {
+            let (reader, _stream, _) = take_tiles(address, 2);
+            hung_up(reader);
+            work(&address.to_string(), |_| Ok(job())).unwrap();
+        });
+        let job = job();
+        let film = serve(
+            listener,
+            Vec::new(),
+            &job,
+            Duration::from_millis(200),
+            false,
+        )
+        .unwrap();
+        workers.join().unwrap();
+        let image = film.framebuffer(&job.options);
+        assert!(image.samples.iter().all(|n| *n == 6));
+        // Nothing is listening once the render is done
+        assert!(TcpStream::connect(address).is_err());
+    }
+
+    // Runs a worker against a coordinator that sends it these bytes once it has said hello
+    fn work_for(sent: Vec<u8>) -> Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let coordinator = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut hello = [0; 12];
+            stream.read_exact(&mut hello).unwrap();
+            stream.write_all(&sent).unwrap();
+            // Until the worker gives up
+            let mut rest = Vec::new();
+            let _ = stream.read_to_end(&mut rest);
+        });
+        let worked = work(&address.to_string(), |_| Ok(job()));
+        coordinator.join().unwrap();
+        worked
+    }
+
+    fn words(words: &[u32]) -> Vec<u8> {
+        words
+            .iter()
+            .flat_map(|w| w.to_le_bytes().to_vec())
+            .collect()
+    }
+
+    #[test]
+    fn workers_reject_arguments_too_many_or_too_long() {
+        assert!(work_for(words(&[u32::MAX])).is_err());
+        assert!(work_for(words(&[1, u32::MAX])).is_err());
+        assert!(work_for(words(&[1, MAX_ARG_LENGTH + 1])).is_err());
+    }
+
+    #[test]
+    fn workers_reject_tiles_outside_the_render() {
+        // No arguments, a heartbeat, then a tile with its index, x, y and sample count
+        let tile = |x: u32, y: u32, samples: u32| {
+            let mut sent = words(&[0, 1000]);
+            sent.push(MESSAGE_TILE);
+            sent.extend(words(&[0, x, y, samples]));
+            work_for(sent)
+        };
+        assert!(tile(30, 0, 6).is_err());
+        assert!(tile(0, u32::MAX, 6).is_err());
+        assert!(tile(3, 0, 6).is_err());
+        assert!(tile(0, 0, 7).is_err());
+        // A tile that is there is rendered, and the worker stops when the coordinator says it is done
+        let mut sent = words(&[0, 1000]);
+        sent.push(MESSAGE_TILE);
+        sent.extend(words(&[0, 8, 0, 6]));
+        sent.push(MESSAGE_DONE);
+        assert!(work_for(sent).is_ok());
+    }
+}
@@ -1,36 +1,230 @@
+use super::filter::Filter;
 use super::geom::*;
+use super::sampler::{Method, Sampler};
+use super::spectrum::{self, Observer};
+use super::tiles::{self, Order};
 use rand::distributions::Uniform;
-use rand::rngs::ThreadRng;
 use rand::*;
-use rand_distr::{Distribution, UnitBall, UnitDisc};
 use rayon::prelude::*;
-use std::ops::AddAssign;
-use std::ops::Deref;
+use std::io::{self, Read, Write};
+use std::ops::{Deref, Range};
 struct Pixel(Vec3);
 
-impl Pixel {
-    fn as_rgb(&self, samples: u32) -> [u8; 4] {
-        let scale = 1.0 / f64::from(samples);
+// Linear radiance filtered from the samples around each pixel, top row first
+pub struct Framebuffer {
+    pub width: u32,
+    pub height: u32,
+    pub pixels: Vec<Vec3>,
+    // How many camera rays went into each pixel, which varies with adaptive sampling
+    pub samples: Vec<u32>,
+    pub aovs: Option<Aovs>,
+}
+
+impl Framebuffer {
+    // Just the pixels inside the window, as an image of its own
+    pub fn crop(&self, crop: &Crop) -> Framebuffer {
+        Framebuffer {
+            width: crop.x1 - crop.x0,
+            height: crop.y1 - crop.y0,
+            pixels: crop.select(&self.pixels, self.width),
+            samples: crop.select(&self.samples, self.width),
+            aovs: self.aovs.as_ref().map(|aovs| Aovs {
+                depth: crop.select(&aovs.depth, self.width),
+                normal: crop.select(&aovs.normal, self.width),
+                albedo: crop.select(&aovs.albedo, self.width),
+                position: crop.select(&aovs.position, self.width),
+                material_id: crop.select(&aovs.material_id, self.width),
+                object_id: crop.select(&aovs.object_id, self.width),
+                variance: crop.select(&aovs.variance, self.width),
+            }),
+        }
+    }
+}
+
+// Per pixel buffers describing what the camera saw first, in the same layout as the pixels
+pub struct Aovs {
+    // Distance from the camera, infinite where every sample missed
+    pub depth: Vec<f64>,
+    pub normal: Vec<Vec3>,
+    // Misses count the sky as their albedo
+    pub albedo: Vec<Vec3>,
+    pub position: Vec<Vec3>,
+    // Numbered from one in the order they first appear scanning the image, zero where the sky was hit
+    pub material_id: Vec<u32>,
+    pub object_id: Vec<u32>,
+    // Of the luminance of each pixel's mean, so how much noise is left in it
+    pub variance: Vec<f64>,
+}
 
-        let r = (self.0.x() * scale).sqrt();
-        let g = (self.0.y() * scale).sqrt();
-        let b = (self.0.z() * scale).sqrt();
+// Sums over the camera rays of one pixel of what they first hit
+#[derive(Clone, Copy)]
+struct FirstHits {
+    samples: u32,
+    hits: u32,
+    depth: f64,
+    normal: Vec3,
+    albedo: Vec3,
+    position: Vec3,
+    // Ids can't be averaged so these come from the first sample alone
+    material: u32,
+    object: u32,
+}
+
+impl FirstHits {
+    fn new() -> FirstHits {
+        FirstHits {
+            samples: 0,
+            hits: 0,
+            depth: 0.0,
+            normal: Vec3::zero(),
+            albedo: Vec3::zero(),
+            position: Vec3::zero(),
+            material: 0,
+            object: 0,
+        }
+    }
 
-        let ir = (clamp(r, 0.0, 1.0) * 255.0) as u8;
-        let ig = (clamp(g, 0.0, 1.0) * 255.0) as u8;
-        let ib = (clamp(b, 0.0, 1.0) * 255.0) as u8;
-        [ir, ig, ib, 255]
+    // What the camera ray hit, taken from the trace that shades it
+    fn add(&mut self, ray: &Ray, hit: Option<&Hit>) {
+        match hit {
+            Some(hit) => {
+                if self.samples == 0 {
+                    self.material = hit.material_id;
+                    self.object = hit.object;
+                }
+                self.hits += 1;
+                self.depth += hit.t * ray.direction.length();
+                self.normal += hit.normal;
+                self.albedo += hit.material.albedo(hit);
+                self.position += hit.point;
+            }
+            None => self.albedo += sky(ray),
+        }
+        self.samples += 1;
     }
+
+    fn write<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        writer.write_all(&self.samples.to_le_bytes())?;
+        writer.write_all(&self.hits.to_le_bytes())?;
+        writer.write_all(&self.depth.to_le_bytes())?;
+        write_vec3(writer, &self.normal)?;
+        write_vec3(writer, &self.albedo)?;
+        write_vec3(writer, &self.position)?;
+        writer.write_all(&self.material.to_le_bytes())?;
+        writer.write_all(&self.object.to_le_bytes())
+    }
+
+    fn read<R: Read>(reader: &mut R) -> io::Result<FirstHits> {
+        Ok(FirstHits {
+            samples: read_u32(reader)?,
+            hits: read_u32(reader)?,
+            depth: read_f64(reader)?,
+            normal: read_vec3(reader)?,
+            albedo: read_vec3(reader)?,
+            position: read_vec3(reader)?,
+            material: read_u32(reader)?,
+            object: read_u32(reader)?,
+        })
+    }
+}
+
+// Welford's running mean and variance of the luminance of a pixel's samples
+#[derive(Clone, Copy)]
+struct Welford {
+    count: u32,
+    mean: f64,
+    m2: f64,
 }
 
-impl AddAssign for Pixel {
-    fn add_assign(&mut self, rhs: Pixel) {
-        self.0 += rhs.0;
+impl Welford {
+    fn new() -> Welford {
+        Welford {
+            count: 0,
+            mean: 0.0,
+            m2: 0.0,
+        }
+    }
+
+    fn write<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        writer.write_all(&self.count.to_le_bytes())?;
+        writer.write_all(&self.mean.to_le_bytes())?;
+        writer.write_all(&self.m2.to_le_bytes())
+    }
+
+    fn read<R: Read>(reader: &mut R) -> io::Result<Welford> {
+        Ok(Welford {
+            count: read_u32(reader)?,
+            mean: read_f64(reader)?,
+            m2: read_f64(reader)?,
+        })
+    }
+
+    fn add(&mut self, x: f64) {
+        self.count += 1;
+        let delta = x - self.mean;
+        self.mean += delta / f64::from(self.count);
+        self.m2 += delta * (x - self.mean);
+    }
+
+    // Of the mean rather than of single samples, so it shrinks as samples are added
+    fn variance(&self) -> f64 {
+        if self.count < 2 {
+            return 0.0;
+        }
+        self.m2 / f64::from(self.count - 1) / f64::from(self.count)
+    }
+
+    // The standard error relative to the mean, with a floor so that near black pixels don't sample forever
+    fn relative_error(&self) -> f64 {
+        self.variance().sqrt() / (self.mean + 0.01)
     }
 }
 
-fn clamp(v: f64, lower: f64, upper: f64) -> f64 {
-    v.min(upper).max(lower)
+// Renumbers the scene's ids of materials or objects in the order they appear in the image
+fn number_ids(scene_ids: impl Iterator<Item = u32>) -> Vec<u32> {
+    let mut ids = std::collections::HashMap::new();
+    scene_ids
+        .map(|scene_id| {
+            if scene_id == 0 {
+                return 0;
+            }
+            let next = ids.len() as u32 + 1;
+            *ids.entry(scene_id).or_insert(next)
+        })
+        .collect()
+}
+
+impl Aovs {
+    fn from_first_hits(first_hits: &[FirstHits], stats: &[Welford]) -> Aovs {
+        let per_hit = |f: &FirstHits, v: Vec3| {
+            if f.hits > 0 {
+                v / f64::from(f.hits)
+            } else {
+                Vec3::zero()
+            }
+        };
+        Aovs {
+            depth: first_hits
+                .iter()
+                .map(|f| {
+                    if f.hits > 0 {
+                        f.depth / f64::from(f.hits)
+                    } else {
+                        f64::INFINITY
+                    }
+                })
+                .collect(),
+            normal: first_hits.iter().map(|f| per_hit(f, f.normal)).collect(),
+            albedo: first_hits
+                .iter()
+                .map(|f| f.albedo / f64::from(f.samples.max(1)))
+                .collect(),
+            position: first_hits.iter().map(|f| per_hit(f, f.position)).collect(),
+            material_id: number_ids(first_hits.iter().map(|f| f.material)),
+            object_id: number_ids(first_hits.iter().map(|f| f.object)),
+            variance: stats.iter().map(Welford::variance).collect(),
+        }
+    }
 }
 
 #[derive(Clone)]
@@ -41,7 +235,6 @@ pub struct Camera {
     vertical: Vec3,
     u: Vec3,
     v: Vec3,
-    w: Vec3,
     lens_radius: f64,
     time_distribution: Uniform<f64>,
 }
@@ -51,6 +244,7 @@ fn degrees_to_radians(degrees: f64) -> f64 {
 }
 
 impl Camera {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         lookfrom: Vec3,
         lookat: Vec3,
@@ -83,14 +277,13 @@ impl Camera {
             vertical,
             u,
             v,
-            w,
             lens_radius,
             time_distribution: Uniform::new(time0, time1),
         }
     }
 
-    pub fn cast_ray(&self, rng: &mut ThreadRng, u: f64, v: f64) -> Ray {
-        let [x, y] = UnitDisc.sample(rng);
+    pub fn cast_ray(&self, rng: &mut Sampler, u: f64, v: f64) -> Ray {
+        let (x, y) = rng.disc();
         let rd = self.lens_radius * Vec3::new(x, y, 0.0);
         let offset = self.u * rd.x() + self.v * rd.y();
         Ray::new_at(
@@ -99,76 +292,960 @@ impl Camera {
             rng.sample(self.time_distribution),
         )
     }
+
+    // A ray with differentials to the points du and dv over on the focal plane
+    pub fn cast_ray_differential(
+        &self,
+        rng: &mut Sampler,
+        u: f64,
+        v: f64,
+        du: f64,
+        dv: f64,
+    ) -> Ray {
+        let mut ray = self.cast_ray(rng, u, v);
+        ray.differentials = Some(Differentials {
+            rx_origin: ray.origin,
+            rx_direction: ray.direction + du * self.horizontal,
+            ry_origin: ray.origin,
+            ry_direction: ray.direction + dv * self.vertical,
+        });
+        ray
+    }
 }
 
 const MAX_DEPTH: u32 = 50;
 
-// TODO: Pass in the camera along with the world
-pub fn draw<H>(width: u32, height: u32, camera: &Camera, world: &H) -> Vec<u8>
+#[derive(Clone, Copy, PartialEq)]
+pub enum Mode {
+    Rgb,
+    // Each path carries a single wavelength so that dispersive materials can split light
+    Spectral,
+}
+
+pub struct Options {
+    pub mode: Mode,
+    // Which samples make up each pixel and how much each counts
+    pub filter: Filter,
+    pub samples_per_pixel: u32,
+    pub sampler: Method,
+    // Renders with the same seed and options come out the same
+    pub seed: u64,
+    // Also fill in the Framebuffer's aovs from what every camera ray hits first
+    pub aovs: bool,
+    // Stop sampling pixels early once they are smooth enough, samples_per_pixel becomes the most any pixel gets
+    pub adaptive: Option<Adaptive>,
+    // Edge length in pixels of the square tiles the image is split into, and the order they are rendered in
+    pub tile_size: u32,
+    pub tile_order: Order,
+    // Only trace the pixels inside this window, the rest of the image stays black
+    pub crop: Option<Crop>,
+}
+
+// A window of pixels from x0, y0 up to but not including x1, y1, counting from the top left
+#[derive(Clone, Copy)]
+pub struct Crop {
+    pub x0: u32,
+    pub y0: u32,
+    pub x1: u32,
+    pub y1: u32,
+}
+
+impl Crop {
+    pub fn contains(&self, x: u32, y: u32) -> bool {
+        x >= self.x0 && x < self.x1 && y >= self.y0 && y < self.y1
+    }
+
+    fn select<T: Copy>(&self, values: &[T], width: u32) -> Vec<T> {
+        (self.y0..self.y1)
+            .flat_map(|y| {
+                values[(y * width + self.x0) as usize..(y * width + self.x1) as usize].iter()
+            })
+            .copied()
+            .collect()
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct Adaptive {
+    // Every pixel gets at least this many so the variance estimate can be trusted
+    pub min_samples: u32,
+    // A pixel is done once its standard error is below this fraction of its luminance
+    pub threshold: f64,
+}
+
+// Pixels are only checked every so many samples, stopping right after a lucky run would bias them
+const ADAPTIVE_BATCH: u32 = 8;
+
+/**
+ * Everything accumulated for an image so far, so that rendering can happen over several passes.
+ * Pixels keep the weighted sums of the samples splatted into them, which only become radiance once divided out
+ */
+pub struct Film {
+    width: u32,
+    height: u32,
+    splats: Vec<(Vec3, f64)>,
+    luminance: Vec<Welford>,
+    first_hits: Vec<FirstHits>,
+}
+
+impl Film {
+    pub fn new(width: u32, height: u32) -> Film {
+        let pixels = (width as usize)
+            .checked_mul(height as usize)
+            .expect("film too large to address");
+        Film {
+            width,
+            height,
+            splats: vec![(Vec3::zero(), 0.0); pixels],
+            luminance: vec![Welford::new(); pixels],
+            first_hits: vec![FirstHits::new(); pixels],
+        }
+    }
+
+    // Everything accumulated so far, little endian and bit exact so that reading it back loses nothing
+    pub fn write<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        writer.write_all(&self.width.to_le_bytes())?;
+        writer.write_all(&self.height.to_le_bytes())?;
+        for ((splat, luminance), first) in self
+            .splats
+            .iter()
+            .zip(self.luminance.iter())
+            .zip(self.first_hits.iter())
+        {
+            write_vec3(writer, &splat.0)?;
+            writer.write_all(&splat.1.to_le_bytes())?;
+            luminance.write(writer)?;
+            first.write(writer)?;
+        }
+        Ok(())
+    }
+
+    // Reads back a film of the expected size, which is checked before anything is allocated
+    pub fn read<R: Read>(reader: &mut R, width: u32, height: u32) -> io::Result<Film> {
+        let size = (read_u32(reader)?, read_u32(reader)?);
+        if size != (width, height) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "the film is {}x{} rather than {}x{}",
+                    size.0, size.1, width, height
+                ),
+            ));
+        }
+        let mut film = Film::new(width, height);
+        for ((splat, luminance), first) in film
+            .splats
+            .iter_mut()
+            .zip(film.luminance.iter_mut())
+            .zip(film.first_hits.iter_mut())
+        {
+            *splat = (read_vec3(reader)?, read_f64(reader)?);
+            *luminance = Welford::read(reader)?;
+            *first = FirstHits::read(reader)?;
+        }
+        Ok(film)
+    }
+
+    // Adds in a tile rendered from this film, tiles have to be merged in the same order for the same image
+    pub fn merge(&mut self, result: &TileResult) {
+        let width = i64::from(self.width);
+        let (x0, y0, x1, _) = result.band;
+        for (k, splat) in result.splats.iter().enumerate() {
+            let (x, y) = (x0 + k as i64 % (x1 - x0), y0 + k as i64 / (x1 - x0));
+            let pixel = &mut self.splats[(y * width + x) as usize];
+            pixel.0 += splat.0;
+            pixel.1 += splat.1;
+        }
+        let tile = &result.tile;
+        for row in 0..tile.height {
+            let from = (row * tile.width) as usize..((row + 1) * tile.width) as usize;
+            let to = ((tile.y + row) * self.width + tile.x) as usize;
+            self.luminance[to..to + tile.width as usize]
+                .copy_from_slice(&result.luminance[from.clone()]);
+            self.first_hits[to..to + tile.width as usize].copy_from_slice(&result.first_hits[from]);
+        }
+    }
+
+    // The image as it stands, aovs are only there if the passes were rendered with them
+    pub fn framebuffer(&self, options: &Options) -> Framebuffer {
+        let mut image = self.preview(options);
+        if options.aovs {
+            let (first_hits, luminance): (Vec<FirstHits>, Vec<Welford>) =
+                (0..self.first_hits.len())
+                    .map(|i| {
+                        if self.shown(i, options) {
+                            (self.first_hits[i], self.luminance[i])
+                        } else {
+                            (FirstHits::new(), Welford::new())
+                        }
+                    })
+                    .unzip();
+            image.aovs = Some(Aovs::from_first_hits(&first_hits, &luminance));
+        }
+        image
+    }
+
+    // Just the pixels and how many samples they have, which is all there is to look at while a pass renders
+    pub fn preview(&self, options: &Options) -> Framebuffer {
+        Framebuffer {
+            width: self.width,
+            height: self.height,
+            pixels: self
+                .splats
+                .iter()
+                .enumerate()
+                .map(|(i, (color, weight))| {
+                    if self.shown(i, options) {
+                        resolve(*color, *weight)
+                    } else {
+                        Vec3::zero()
+                    }
+                })
+                .collect(),
+            samples: (0..self.luminance.len())
+                .map(|i| {
+                    if self.shown(i, options) {
+                        self.luminance[i].count
+                    } else {
+                        0
+                    }
+                })
+                .collect(),
+            aovs: None,
+        }
+    }
+
+    // Samples around a crop window splat into it, what they leave outside is not part of the image
+    fn shown(&self, i: usize, options: &Options) -> bool {
+        let (x, y) = (i as u32 % self.width, i as u32 / self.width);
+        options.crop.is_none_or(|crop| crop.contains(x, y))
+    }
+}
+
+// Negative lobes can cancel out the weights, those pixels are left black rather than blown up
+fn resolve(color: Vec3, weight: f64) -> Vec3 {
+    if weight.abs() > 1e-9 {
+        color / weight
+    } else {
+        Vec3::zero()
+    }
+}
+
+fn write_vec3<W: Write>(writer: &mut W, v: &Vec3) -> io::Result<()> {
+    for c in [v.x(), v.y(), v.z()].iter() {
+        writer.write_all(&c.to_le_bytes())?;
+    }
+    Ok(())
+}
+
+fn read_u32<R: Read>(reader: &mut R) -> io::Result<u32> {
+    let mut bytes = [0; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn read_u64<R: Read>(reader: &mut R) -> io::Result<u64> {
+    let mut bytes = [0; 8];
+    reader.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+fn read_f64<R: Read>(reader: &mut R) -> io::Result<f64> {
+    Ok(f64::from_bits(read_u64(reader)?))
+}
+
+fn read_vec3<R: Read>(reader: &mut R) -> io::Result<Vec3> {
+    Ok(Vec3::new(
+        read_f64(reader)?,
+        read_f64(reader)?,
+        read_f64(reader)?,
+    ))
+}
+
+// A finished tile, in pixels of the film with the top row first
+pub struct Tile {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+    // Radiance of the tile's own pixels with everything rendered so far, for previews
+    pub pixels: Vec<Vec3>,
+}
+
+// Where a tile's pixels are in the film, and the band around them its samples splat into
+#[derive(Clone, Copy, PartialEq)]
+pub struct TileBounds {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+    band: (i64, i64, i64, i64),
+}
+
+impl TileBounds {
+    // For the tile at x, y of a width by height film, clipped to what options have traced and to the film
+    pub fn new(width: u32, height: u32, options: &Options, x: u32, y: u32) -> TileBounds {
+        let region = traced_region(width, height, options);
+        let reach = filter_reach(&options.filter);
+        let tile_width = options.tile_size.min(region.x1 - x);
+        let tile_height = options.tile_size.min(region.y1 - y);
+        TileBounds {
+            x,
+            y,
+            width: tile_width,
+            height: tile_height,
+            band: (
+                (i64::from(x) - reach).max(0),
+                (i64::from(y) - reach).max(0),
+                (i64::from(x + tile_width) + reach).min(i64::from(width)),
+                (i64::from(y + tile_height) + reach).min(i64::from(height)),
+            ),
+        }
+    }
+}
+
+// What one tile of a pass adds to the film before it is merged in
+pub struct TileResult {
+    pub tile: Tile,
+    // The tile grown by how far samples splat, clipped to the film
+    band: (i64, i64, i64, i64),
+    splats: Vec<(Vec3, f64)>,
+    luminance: Vec<Welford>,
+    first_hits: Vec<FirstHits>,
+}
+
+impl TileResult {
+    // Bit exact like the film, so a tile rendered elsewhere merges in just as if it was rendered here
+    pub fn write<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        let tile = &self.tile;
+        for v in [tile.x, tile.y, tile.width, tile.height].iter() {
+            writer.write_all(&v.to_le_bytes())?;
+        }
+        for v in [self.band.0, self.band.1, self.band.2, self.band.3].iter() {
+            writer.write_all(&v.to_le_bytes())?;
+        }
+        for pixel in tile.pixels.iter() {
+            write_vec3(writer, pixel)?;
+        }
+        for splat in self.splats.iter() {
+            write_vec3(writer, &splat.0)?;
+            writer.write_all(&splat.1.to_le_bytes())?;
+        }
+        for (luminance, first) in self.luminance.iter().zip(self.first_hits.iter()) {
+            luminance.write(writer)?;
+            first.write(writer)?;
+        }
+        Ok(())
+    }
+
+    /**
+     * Reads back the tile with the expected bounds, anything else is an error.
+     * The bounds are checked before anything is allocated, so sizes are only ever the ones expected
+     */
+    pub fn read<R: Read>(reader: &mut R, expected: &TileBounds) -> io::Result<TileResult> {
+        let (x, y) = (read_u32(reader)?, read_u32(reader)?);
+        let (width, height) = (read_u32(reader)?, read_u32(reader)?);
+        let mut band = [0; 4];
+        for v in band.iter_mut() {
+            *v = read_u64(reader)? as i64;
+        }
+        let bounds = TileBounds {
+            x,
+            y,
+            width,
+            height,
+            band: (band[0], band[1], band[2], band[3]),
+        };
+        if bounds != *expected {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "not the tile that was expected",
+            ));
+        }
+        // Bounds made here are within the film, so these can't overflow
+        let pixel_count = u64::from(width) * u64::from(height);
+        let band_count = ((band[2] - band[0]) * (band[3] - band[1])) as u64;
+        let pixels = (0..pixel_count)
+            .map(|_| read_vec3(reader))
+            .collect::<io::Result<_>>()?;
+        let splats = (0..band_count)
+            .map(|_| Ok((read_vec3(reader)?, read_f64(reader)?)))
+            .collect::<io::Result<_>>()?;
+        let mut luminance = Vec::with_capacity(pixel_count as usize);
+        let mut first_hits = Vec::with_capacity(pixel_count as usize);
+        for _ in 0..pixel_count {
+            luminance.push(Welford::read(reader)?);
+            first_hits.push(FirstHits::read(reader)?);
+        }
+        Ok(TileResult {
+            tile: Tile {
+                x,
+                y,
+                width,
+                height,
+                pixels,
+            },
+            band: bounds.band,
+            splats,
+            luminance,
+            first_hits,
+        })
+    }
+}
+
+/**
+ * Adds the samples with indices in the range to every pixel of the film.
+ * Samples are numbered within options.samples_per_pixel, the total the sampler and ray footprints are planned for.
+ * Tiles are picked up by the thread pool in options.tile_order and on_tile hears about each as soon as it is done,
+ * the film itself only changes once all of them are
+ */
+pub fn render_pass<H>(
+    film: &mut Film,
+    camera: &Camera,
+    world: &H,
+    options: &Options,
+    samples: Range<u32>,
+    on_tile: &(dyn Fn(&Tile) + Sync),
+) where
+    H: Deref<Target = dyn Hittable + Send + Sync> + Send + Sync,
+{
+    // Bridging hands tiles out one at a time in order, where splitting the list would scatter them
+    let mut results: Vec<(usize, TileResult)> = tile_origins(film.width, film.height, options)
+        .into_iter()
+        .enumerate()
+        .par_bridge()
+        .map(|(index, (x, y))| {
+            let result = render_tile(film, camera, world, options, &samples, x, y);
+            on_tile(&result.tile);
+            (index, result)
+        })
+        .collect();
+
+    // Merging in the same order every time keeps the sums, and with them the image, the same from run to run
+    results.sort_by_key(|(index, _)| *index);
+    for (_, result) in results {
+        film.merge(&result);
+    }
+}
+
+// The top left pixels of the tiles a pass is split into, in the order they are rendered
+pub fn tile_origins(width: u32, height: u32, options: &Options) -> Vec<(u32, u32)> {
+    let region = traced_region(width, height, options);
+    tiles::layout(
+        region.x1 - region.x0,
+        region.y1 - region.y0,
+        options.tile_size,
+        options.tile_order,
+    )
+    .into_iter()
+    .map(|(column, row)| {
+        (
+            region.x0 + column * options.tile_size,
+            region.y0 + row * options.tile_size,
+        )
+    })
+    .collect()
+}
+
+/**
+ * The pixels a pass traces, all of them without a crop.
+ * With one it is the window grown by the filter's reach, so pixels at its edges get the same samples from around
+ * them as in a render of the whole image and come out exactly the same
+ */
+pub fn traced_region(width: u32, height: u32, options: &Options) -> Crop {
+    match options.crop {
+        Some(crop) => {
+            let reach = filter_reach(&options.filter) as u32;
+            Crop {
+                x0: crop.x0.saturating_sub(reach),
+                y0: crop.y0.saturating_sub(reach),
+                x1: (crop.x1 + reach).min(width),
+                y1: (crop.y1 + reach).min(height),
+            }
+        }
+        None => Crop {
+            x0: 0,
+            y0: 0,
+            x1: width,
+            y1: height,
+        },
+    }
+}
+
+/**
+ * How many pixels away from its own a sample can still land within the filter.
+ * A sample is at most half a pixel from its own pixel's center, so further than radius - 0.5 pixels away it only
+ * touches the edge of the filter, where only box weighs anything
+ */
+fn filter_reach(filter: &Filter) -> i64 {
+    (filter.radius() - 0.5).ceil().max(0.0) as i64
+}
+
+/**
+ * Adds a sample at dx, dy within the pixel at i, film_row to every pixel of the band whose center is within the
+ * filter's radius. Film rows are counted from the top and dy from the bottom of the pixel
+ */
+fn splat(
+    splats: &mut [(Vec3, f64)],
+    band: (i64, i64, i64, i64),
+    filter: &Filter,
+    (i, film_row): (u32, u32),
+    (dx, dy): (f64, f64),
+    sample: &Vec3,
+) {
+    let reach = filter_reach(filter);
+    let band_width = band.2 - band.0;
+    let (x, y) = (f64::from(i) + dx, f64::from(film_row + 1) - dy);
+    let (i, film_row) = (i64::from(i), i64::from(film_row));
+    for py in (film_row - reach).max(band.1)..=(film_row + reach).min(band.3 - 1) {
+        for px in (i - reach).max(band.0)..=(i + reach).min(band.2 - 1) {
+            let weight = filter.weight(x - (px as f64 + 0.5), y - (py as f64 + 0.5));
+            if weight != 0.0 {
+                let splat = &mut splats[((py - band.1) * band_width + px - band.0) as usize];
+                splat.0 += weight * *sample;
+                splat.1 += weight;
+            }
+        }
+    }
+}
+
+// Renders the samples in the range for the tile at tx, ty on top of what the film has so far, without changing it
+pub fn render_tile<H>(
+    film: &Film,
+    camera: &Camera,
+    world: &H,
+    options: &Options,
+    samples: &Range<u32>,
+    tx: u32,
+    ty: u32,
+) -> TileResult
 where
     H: Deref<Target = dyn Hittable + Send + Sync> + Send + Sync,
 {
+    let (width, height) = (film.width, film.height);
     let image_width = f64::from(width);
     let image_height = f64::from(height);
-    let samples_per_pixel = 100u32;
-    // Final output of the entire representation
-    let mut rows: Vec<Vec<u8>> = Vec::with_capacity(height as usize);
-    let dist = Uniform::new(0.0f64, 1.0f64);
-
-    (0..height)
-        .into_par_iter()
-        // Invert because png writes from top to bottom
-        .rev()
-        .map(|j| {
-            let mut pixels: Vec<u8> = Vec::with_capacity(width as usize * 4);
-            let mut rng = thread_rng();
-            for i in 0..width {
-                let mut color = Pixel(Vec3::zero());
-                for _ in 0..samples_per_pixel {
-                    let u = (f64::from(i) + rng.sample(dist)) / (image_width - 1.0);
-                    let v = (f64::from(j) + rng.sample(dist)) / (image_height - 1.0);
-                    let ray = camera.cast_ray(&mut rng, u, v);
-                    color += ray_color(&mut rng, &ray, world, MAX_DEPTH);
+    let samples_per_pixel = options.samples_per_pixel;
+    let filter = options.filter;
+    let wavelengths = Uniform::new(spectrum::LAMBDA_MIN, spectrum::LAMBDA_MAX);
+    let observer = Observer::cie1931();
+    // Each sample covers a fraction of the pixel, scaled down like pbrt with a floor so textures stay filtered
+    let spread = (1.0 / f64::from(samples_per_pixel).sqrt()).max(0.125);
+    let (du, dv) = (spread / (image_width - 1.0), spread / (image_height - 1.0));
+
+    let bounds = TileBounds::new(width, height, options, tx, ty);
+    let (tile_width, tile_height, band) = (bounds.width, bounds.height, bounds.band);
+    let band_width = band.2 - band.0;
+    let mut splats = vec![(Vec3::zero(), 0.0); (band_width * (band.3 - band.1)) as usize];
+    let mut luminances = Vec::with_capacity((tile_width * tile_height) as usize);
+    let mut all_first_hits = Vec::with_capacity((tile_width * tile_height) as usize);
+    let mut rng = Sampler::new(options.sampler, samples_per_pixel, options.seed);
+
+    // Rows are top first in the film, the flipped j is what the camera counts from the bottom
+    for film_row in ty..ty + tile_height {
+        let j = height - 1 - film_row;
+        for i in tx..tx + tile_width {
+            let pixel = (film_row * width + i) as usize;
+            let mut first = film.first_hits[pixel];
+            let mut luminance = film.luminance[pixel];
+            for s in samples.clone() {
+                if let Some(adaptive) = &options.adaptive {
+                    // Counted from the pixel's own samples so that passes can split them up anywhere
+                    if luminance.count >= adaptive.min_samples
+                        && (luminance.count - adaptive.min_samples).is_multiple_of(ADAPTIVE_BATCH)
+                        && luminance.relative_error() <= adaptive.threshold
+                    {
+                        break;
+                    }
                 }
-                pixels.extend_from_slice(&color.as_rgb(samples_per_pixel));
+                rng.start_pixel_sample(i, j, s);
+                let (dx, dy) = rng.get_2d();
+                let u = (f64::from(i) + dx) / (image_width - 1.0);
+                let v = (f64::from(j) + dy) / (image_height - 1.0);
+                let mut ray = camera.cast_ray_differential(&mut rng, u, v, du, dv);
+                let first = if options.aovs { Some(&mut first) } else { None };
+                let sample = match options.mode {
+                    Mode::Rgb => ray_color(&mut rng, &ray, world, MAX_DEPTH, first),
+                    Mode::Spectral => {
+                        let lambda = rng.sample(wavelengths);
+                        ray.wavelength = Some(lambda);
+                        let radiance = ray_spectral(&mut rng, &ray, world, MAX_DEPTH, first);
+                        Pixel(observer.to_rgb(lambda, radiance))
+                    }
+                };
+                luminance.add(sample.0.luminance());
+                splat(
+                    &mut splats,
+                    band,
+                    &filter,
+                    (i, film_row),
+                    (dx, dy),
+                    &sample.0,
+                );
             }
-            pixels
-        })
-        .collect_into_vec(&mut rows);
+            luminances.push(luminance);
+            all_first_hits.push(first);
+        }
+    }
 
-    let mut output_buffer: Vec<u8> = Vec::with_capacity(width as usize * height as usize * 4);
-    rows.into_iter()
-        .for_each(|row| output_buffer.extend_from_slice(row.as_slice()));
-    output_buffer
+    let pixels = (ty..ty + tile_height)
+        .flat_map(|y| (tx..tx + tile_width).map(move |x| (x, y)))
+        .map(|(x, y)| {
+            if options.crop.is_some_and(|crop| !crop.contains(x, y)) {
+                return Vec3::zero();
+            }
+            let (old, old_weight) = film.splats[(y * width + x) as usize];
+            let (new, new_weight) =
+                splats[((i64::from(y) - band.1) * band_width + i64::from(x) - band.0) as usize];
+            resolve(old + new, old_weight + new_weight)
+        })
+        .collect();
+    TileResult {
+        tile: Tile {
+            x: tx,
+            y: ty,
+            width: tile_width,
+            height: tile_height,
+            pixels,
+        },
+        band,
+        splats,
+        luminance: luminances,
+        first_hits: all_first_hits,
+    }
 }
 
+// first is only given for camera rays, to record what they hit for the aovs
 fn ray_color<H: Deref<Target = dyn Hittable + Send + Sync>>(
-    rng: &mut ThreadRng,
+    rng: &mut Sampler,
     ray: &Ray,
     world: &H,
     depth: u32,
+    first: Option<&mut FirstHits>,
 ) -> Pixel {
     if depth == 0 {
         return Pixel(Vec3::zero());
     }
-    if let Some(hit) = world.hit(ray, 0.001, f64::INFINITY) {
+    let hit = world.hit(ray, 0.001, f64::INFINITY);
+    if let Some(first) = first {
+        first.add(ray, hit.as_ref());
+    }
+    if let Some(hit) = hit {
         if let Some(scatter) = hit.material.scatter(ray, &hit, rng) {
             return Pixel(
-                scatter.attenuation * ray_color(rng, &scatter.scattered, world, depth - 1).0,
+                scatter.attenuation * ray_color(rng, &scatter.scattered, world, depth - 1, None).0,
             );
         }
         return Pixel(Vec3::zero());
     }
+    Pixel(sky(ray))
+}
+
+fn sky(ray: &Ray) -> Vec3 {
     let unit = ray.direction.unit();
     let t = 0.5 * (unit.y() + 1.0);
-    Pixel((1.0 - t) * Vec3::new(1.0, 1.0, 1.0) + t * Vec3::new(0.5, 0.7, 1.0))
+    (1.0 - t) * Vec3::new(1.0, 1.0, 1.0) + t * Vec3::new(0.5, 0.7, 1.0)
 }
 
-pub fn random_in_hemisphere<R: Rng>(rng: &mut R, normal: &Vec3) -> Vec3 {
-    let in_unit_sphere = Vec3::new_raw(UnitBall.sample(rng));
-    if in_unit_sphere.dot(normal) > 0.0 {
-        in_unit_sphere
-    } else {
-        in_unit_sphere.flip()
+// Same as ray_color but for the single wavelength carried by the ray, rgb colors are upsampled
+fn ray_spectral<H: Deref<Target = dyn Hittable + Send + Sync>>(
+    rng: &mut Sampler,
+    ray: &Ray,
+    world: &H,
+    depth: u32,
+    first: Option<&mut FirstHits>,
+) -> f64 {
+    if depth == 0 {
+        return 0.0;
+    }
+    let lambda = ray.wavelength.unwrap_or(spectrum::LAMBDA_MIN);
+    let hit = world.hit(ray, 0.001, f64::INFINITY);
+    if let Some(first) = first {
+        first.add(ray, hit.as_ref());
+    }
+    if let Some(hit) = hit {
+        if let Some(scatter) = hit.material.scatter(ray, &hit, rng) {
+            // Materials build fresh rays so the wavelength has to be carried over
+            let mut scattered = scatter.scattered;
+            scattered.wavelength = ray.wavelength;
+            return spectrum::rgb_at_wavelength(&scatter.attenuation, lambda)
+                * ray_spectral(rng, &scattered, world, depth - 1, None);
+        }
+        return 0.0;
+    }
+    spectrum::rgb_at_wavelength(&sky(ray), lambda)
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    pub type World = Box<dyn Hittable + Send + Sync>;
+
+    // A couple of spheres small enough to render in tests, with a camera for a 4:3 image
+    pub fn scene() -> (Camera, World) {
+        let mut scene = SceneBuilder::default();
+        let grey = scene.material(Arc::new(Lambertian::new(Arc::new(SolidColor::new(
+            0.5, 0.5, 0.5,
+        )))));
+        let ground = scene.sphere(Vec3::new(0.0, -1000.0, 0.0), 1000.0, &grey);
+        let metal = scene.material(Arc::new(Metal::new(Vec3::new(0.7, 0.6, 0.5), 0.1)));
+        let ball = scene.sphere(Vec3::new(0.0, 1.0, 0.0), 1.0, &metal);
+        let camera = Camera::new(
+            Vec3::new(6.0, 2.0, 4.0),
+            Vec3::new(0.0, 0.8, 0.0),
+            Vec3::new(0.0, 1.0, 0.0),
+            30.0,
+            4.0 / 3.0,
+            0.1,
+            7.0,
+            0.0,
+            1.0,
+        );
+        let world = BVHNode::new(Box::new(ground), Box::new(ball), 0.0, 1.0);
+        (camera, Box::new(world) as World)
+    }
+
+    pub fn options(samples_per_pixel: u32) -> Options {
+        Options {
+            mode: Mode::Rgb,
+            filter: Filter::new_tent(),
+            samples_per_pixel,
+            sampler: Method::Sobol,
+            seed: 3,
+            aovs: true,
+            adaptive: None,
+            tile_size: 8,
+            tile_order: Order::Hilbert,
+            crop: None,
+        }
+    }
+
+    // Renders the samples of an image in passes of the given sizes
+    pub fn render(
+        (camera, world): &(Camera, World),
+        width: u32,
+        height: u32,
+        options: &Options,
+        passes: &[u32],
+    ) -> Film {
+        let mut film = Film::new(width, height);
+        let mut next = 0;
+        for pass in passes {
+            render_pass(
+                &mut film,
+                camera,
+                world,
+                options,
+                next..next + pass,
+                &|_| {},
+            );
+            next += pass;
+        }
+        film
+    }
+
+    fn close(a: f64, b: f64) -> bool {
+        (a - b).abs() <= 1e-12 * a.abs().max(b.abs()).max(1.0)
+    }
+
+    #[test]
+    fn welford_matches_the_direct_formulas() {
+        let xs = [0.5, 2.0, 0.25, 3.5, 1.0, 1.0, 8.0];
+        let mut luminance = Welford::new();
+        for x in xs.iter() {
+            luminance.add(*x);
+        }
+        let n = xs.len() as f64;
+        let mean = xs.iter().sum::<f64>() / n;
+        let sample_variance = xs.iter().map(|x| (x - mean) * (x - mean)).sum::<f64>() / (n - 1.0);
+        assert_eq!(luminance.count, xs.len() as u32);
+        assert!(close(luminance.mean, mean));
+        assert!(close(luminance.variance(), sample_variance / n));
+        assert!(close(
+            luminance.relative_error(),
+            (sample_variance / n).sqrt() / (mean + 0.01)
+        ));
+    }
+
+    #[test]
+    fn welford_needs_two_samples_for_a_variance() {
+        let mut luminance = Welford::new();
+        assert_eq!(luminance.variance(), 0.0);
+        luminance.add(5.0);
+        assert_eq!(luminance.variance(), 0.0);
+        assert_eq!(luminance.relative_error(), 0.0);
+        luminance.add(5.0);
+        assert_eq!(luminance.variance(), 0.0);
+    }
+
+    #[test]
+    fn welford_round_trips() {
+        let mut luminance = Welford::new();
+        for x in [0.1, 0.7, 0.3].iter() {
+            luminance.add(*x);
+        }
+        let mut bytes = Vec::new();
+        luminance.write(&mut bytes).unwrap();
+        let read = Welford::read(&mut &bytes[..]).unwrap();
+        assert_eq!(
+            (read.count, read.mean.to_bits(), read.m2.to_bits()),
+            (
+                luminance.count,
+                luminance.mean.to_bits(),
+                luminance.m2.to_bits()
+            )
+        );
+    }
+
+    #[test]
+    fn adaptive_passes_stop_pixels_where_a_single_pass_does() {
+        let mut options = options(48);
+        options.adaptive = Some(Adaptive {
+            min_samples: 8,
+            threshold: 0.05,
+        });
+        let scene = scene();
+        let single = render(&scene, 24, 18, &options, &[48]).framebuffer(&options);
+        let passes = render(&scene, 24, 18, &options, &[4, 4, 5, 11, 24]).framebuffer(&options);
+        assert_eq!(passes.samples, single.samples);
+        // Some pixels stop early and some go all the way, or this proves nothing
+        assert!(single.samples.iter().any(|n| *n < 48));
+        assert!(single.samples.contains(&48));
+        // The splats are summed in another order
+        for (a, b) in passes.pixels.iter().zip(single.pixels.iter()) {
+            assert!((*a - *b).length() <= 1e-9 * b.length().max(1.0));
+        }
+    }
+
+    fn bits(vectors: &[Vec3]) -> Vec<[u64; 3]> {
+        vectors
+            .iter()
+            .map(|v| [v.x().to_bits(), v.y().to_bits(), v.z().to_bits()])
+            .collect()
+    }
+
+    // Ids are numbered by where they first appear, which moves when only part of the image is there
+    fn renumbered(ids: &[u32]) -> Vec<u32> {
+        let mut numbers = std::collections::HashMap::new();
+        ids.iter()
+            .map(|id| {
+                let next = numbers.len() as u32;
+                *numbers.entry(*id).or_insert(next)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn cropped_pixels_match_the_full_render() {
+        let scene = scene();
+        let mut options = options(8);
+        let full = render(&scene, 30, 20, &options, &[8]).framebuffer(&options);
+        let crop = Crop {
+            x0: 5,
+            y0: 3,
+            x1: 19,
+            y1: 17,
+        };
+        options.crop = Some(crop);
+        let image = render(&scene, 30, 20, &options, &[8]).framebuffer(&options);
+        let (expected, cropped) = (full.crop(&crop), image.crop(&crop));
+        // Tiles start at the crop so splats add up in another order, what isn't splatted comes out exactly the same
+        for (a, b) in cropped.pixels.iter().zip(expected.pixels.iter()) {
+            assert!((*a - *b).length() <= 1e-12 * b.length().max(1.0));
+        }
+        assert_eq!(cropped.samples, expected.samples);
+        let (aovs, expected) = (cropped.aovs.unwrap(), expected.aovs.unwrap());
+        let floats = |values: &[f64]| values.iter().map(|v| v.to_bits()).collect::<Vec<u64>>();
+        assert_eq!(floats(&aovs.depth), floats(&expected.depth));
+        assert_eq!(floats(&aovs.variance), floats(&expected.variance));
+        assert_eq!(bits(&aovs.normal), bits(&expected.normal));
+        assert_eq!(bits(&aovs.albedo), bits(&expected.albedo));
+        assert_eq!(bits(&aovs.position), bits(&expected.position));
+        assert_eq!(
+            renumbered(&aovs.material_id),
+            renumbered(&expected.material_id)
+        );
+        assert_eq!(renumbered(&aovs.object_id), renumbered(&expected.object_id));
+
+        // Nothing is traced outside, and what splats over the edge is left out
+        assert!(image.samples.iter().enumerate().all(|(i, n)| {
+            let (x, y) = (i as u32 % 30, i as u32 / 30);
+            crop.contains(x, y) == (*n > 0)
+        }));
+        assert!(image
+            .pixels
+            .iter()
+            .enumerate()
+            .all(|(i, p)| crop.contains(i as u32 % 30, i as u32 / 30) || p.length() == 0.0));
+    }
+
+    // Positions within a pixel on an n by n grid, including its edges at zero
+    fn grid(n: u32) -> Vec<(f64, f64)> {
+        let steps = (0..n).map(move |k| f64::from(k) / f64::from(n));
+        steps
+            .clone()
+            .flat_map(|dx| (0..n).map(move |k| (dx, f64::from(k) / f64::from(n))))
+            .collect()
+    }
+
+    #[test]
+    fn filter_reach_covers_the_radius() {
+        for filter in crate::filter::tests::filters() {
+            let reach = filter_reach(&filter);
+            for dx in (0..64).map(|k| f64::from(k) / 64.0) {
+                // Pixels past the reach are at least the radius away
+                for k in (reach + 1..reach + 4).flat_map(|k| vec![k, -k]) {
+                    let offset = dx - (k as f64 + 0.5);
+                    assert!(offset.abs() >= filter.radius());
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn samples_on_pixel_edges_stay_in_their_pixel() {
+        let band = (0, 0, 3, 3);
+        let white = Vec3::new(1.0, 1.0, 1.0);
+        // Row 2 is the last of the band, where a sample used to be clamped out
+        for (i, row) in [(1, 1), (1, 2), (0, 0)].iter() {
+            for offset in [(0.0, 0.0), (0.5, 0.5), (0.999, 0.999)].iter() {
+                let mut splats = vec![(Vec3::zero(), 0.0); 9];
+                splat(
+                    &mut splats,
+                    band,
+                    &Filter::new_box(),
+                    (*i, *row),
+                    *offset,
+                    &white,
+                );
+                let weights: Vec<f64> = splats.iter().map(|s| s.1).collect();
+                let own = (row * 3 + i) as usize;
+                assert_eq!(weights[own], 1.0);
+                assert_eq!(weights.iter().sum::<f64>(), 1.0);
+            }
+        }
+    }
+
+    #[test]
+    fn constant_images_stay_constant_with_every_filter() {
+        let (width, height) = (9u32, 7u32);
+        let band = (0, 0, i64::from(width), i64::from(height));
+        let color = Vec3::new(0.25, 0.5, 0.75);
+        for filter in crate::filter::tests::filters() {
+            let mut splats = vec![(Vec3::zero(), 0.0); (width * height) as usize];
+            for row in 0..height {
+                for i in 0..width {
+                    for offset in grid(4) {
+                        splat(&mut splats, band, &filter, (i, row), offset, &color);
+                    }
+                }
+            }
+            for (sum, weight) in splats {
+                assert!(weight > 0.0);
+                assert!((resolve(sum, weight) - color).length() < 1e-12);
+            }
+        }
     }
 }
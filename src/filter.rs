@@ -0,0 +1,157 @@
+use std::f64::consts::PI;
+
+/**
+ * Weights a sample by how far it lands from a pixel's center, in pixels.
+ * All of them are separable so the weight is the product of the same curve along x and y
+ */
+#[derive(Clone, Copy)]
+pub enum Filter {
+    // Every sample within the radius counts the same, a radius of half a pixel keeps samples to their own pixel
+    Box { radius: f64 },
+    // Falls off linearly to zero at the radius
+    Tent { radius: f64 },
+    // Shifted down so that it reaches zero at the radius rather than being cut off
+    Gaussian { radius: f64, sigma: f64 },
+    // The cubic from Mitchell and Netravali's paper, stretched over the radius
+    Mitchell { radius: f64, b: f64, c: f64 },
+    // Sinc windowed by a wider sinc that reaches zero at the radius
+    Lanczos { radius: f64 },
+}
+
+impl Filter {
+    pub fn new_box() -> Filter {
+        Filter::Box { radius: 0.5 }
+    }
+
+    pub fn new_tent() -> Filter {
+        Filter::Tent { radius: 1.0 }
+    }
+
+    pub fn new_gaussian() -> Filter {
+        Filter::Gaussian {
+            radius: 1.5,
+            sigma: 0.5,
+        }
+    }
+
+    // The b and c the paper recommends
+    pub fn new_mitchell() -> Filter {
+        Filter::Mitchell {
+            radius: 2.0,
+            b: 1.0 / 3.0,
+            c: 1.0 / 3.0,
+        }
+    }
+
+    pub fn new_lanczos() -> Filter {
+        Filter::Lanczos { radius: 3.0 }
+    }
+
+    // The same shape over a different radius, a gaussian keeps its sigma in proportion
+    pub fn with_radius(self, r: f64) -> Filter {
+        match self {
+            Filter::Box { .. } => Filter::Box { radius: r },
+            Filter::Tent { .. } => Filter::Tent { radius: r },
+            Filter::Gaussian { radius, sigma } => Filter::Gaussian {
+                radius: r,
+                sigma: sigma * r / radius,
+            },
+            Filter::Mitchell { b, c, .. } => Filter::Mitchell { radius: r, b, c },
+            Filter::Lanczos { .. } => Filter::Lanczos { radius: r },
+        }
+    }
+
+    pub fn radius(&self) -> f64 {
+        match *self {
+            Filter::Box { radius }
+            | Filter::Tent { radius }
+            | Filter::Gaussian { radius, .. }
+            | Filter::Mitchell { radius, .. }
+            | Filter::Lanczos { radius } => radius,
+        }
+    }
+
+    // Zero outside the radius, Mitchell and Lanczos go negative just inside it to sharpen
+    pub fn weight(&self, x: f64, y: f64) -> f64 {
+        self.weight_1d(x) * self.weight_1d(y)
+    }
+
+    fn weight_1d(&self, x: f64) -> f64 {
+        let x = x.abs();
+        if x > self.radius() {
+            return 0.0;
+        }
+        match *self {
+            Filter::Box { .. } => 1.0,
+            Filter::Tent { radius } => radius - x,
+            Filter::Gaussian { radius, sigma } => {
+                let gaussian = |x: f64| (-x * x / (2.0 * sigma * sigma)).exp();
+                (gaussian(x) - gaussian(radius)).max(0.0)
+            }
+            Filter::Mitchell { radius, b, c } => {
+                // The cubic is defined over [-2, 2]
+                let x = 2.0 * x / radius;
+                if x < 1.0 {
+                    ((12.0 - 9.0 * b - 6.0 * c) * x * x * x
+                        + (-18.0 + 12.0 * b + 6.0 * c) * x * x
+                        + (6.0 - 2.0 * b))
+                        / 6.0
+                } else {
+                    ((-b - 6.0 * c) * x * x * x
+                        + (6.0 * b + 30.0 * c) * x * x
+                        + (-12.0 * b - 48.0 * c) * x
+                        + (8.0 * b + 24.0 * c))
+                        / 6.0
+                }
+            }
+            Filter::Lanczos { radius } => sinc(x) * sinc(x / radius),
+        }
+    }
+}
+
+fn sinc(x: f64) -> f64 {
+    if x < 1e-5 {
+        return 1.0;
+    }
+    (PI * x).sin() / (PI * x)
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    // The default of each filter and the same shape over a couple of other radii
+    pub fn filters() -> Vec<Filter> {
+        let defaults = [
+            Filter::new_box(),
+            Filter::new_tent(),
+            Filter::new_gaussian(),
+            Filter::new_mitchell(),
+            Filter::new_lanczos(),
+        ];
+        let mut filters = defaults.to_vec();
+        for radius in [0.75, 1.5, 2.5].iter() {
+            filters.extend(defaults.iter().map(|f| f.with_radius(*radius)));
+        }
+        filters
+    }
+
+    #[test]
+    fn filters_are_zero_at_and_beyond_their_radius() {
+        for filter in filters() {
+            let r = filter.radius();
+            for d in [r * (1.0 + 1e-9), r + 0.01, r + 0.5, 2.0 * r, 100.0].iter() {
+                assert_eq!(filter.weight(*d, 0.0), 0.0);
+                assert_eq!(filter.weight(0.0, -*d), 0.0);
+                assert_eq!(filter.weight(*d, *d), 0.0);
+            }
+            // A box counts samples right on its edge, so the edges of pixels are not left out
+            let at = filter.weight(r, 0.0);
+            match filter {
+                Filter::Box { .. } => assert_eq!(at, 1.0),
+                _ => assert!(at.abs() < 1e-12),
+            }
+            assert!(filter.weight(0.0, 0.0) > 0.0);
+        }
+    }
+}
@@ -1,5 +1,7 @@
+use super::sampler::Sampler;
+use super::thinfilm::ThinFilm;
 use rand::distributions::{Distribution, Uniform};
-use rand::rngs::ThreadRng;
+use rand::rngs::StdRng;
 use rand::Rng;
 use rand_distr::UnitBall;
 use std::cmp::Ordering;
@@ -77,11 +79,20 @@ impl Vec3 {
         Vec3::new(-self.x(), -self.y(), -self.z())
     }
 
-    pub fn random_ball(rng: &mut ThreadRng) -> Vec3 {
+    // Relative luminance of a linear rec.709 color
+    pub fn luminance(&self) -> f64 {
+        0.2126 * self.x() + 0.7152 * self.y() + 0.0722 * self.z()
+    }
+
+    pub fn map<F: Fn(f64) -> f64>(&self, f: F) -> Vec3 {
+        Vec3::new(f(self.x()), f(self.y()), f(self.z()))
+    }
+
+    pub fn random_ball(rng: &mut StdRng) -> Vec3 {
         Vec3::new_raw(UnitBall.sample(rng))
     }
 
-    pub fn random_dist<D: Distribution<f64>>(rng: &mut ThreadRng, dist: &D) -> Vec3 {
+    pub fn random_dist<D: Distribution<f64>>(rng: &mut StdRng, dist: &D) -> Vec3 {
         Vec3::new(dist.sample(rng), dist.sample(rng), dist.sample(rng))
     }
 }
@@ -195,26 +206,74 @@ impl Add<f64> for Vec3 {
     }
 }
 
+// An orthonormal basis around a normal so that shading can happen with the normal along +z
+pub struct Onb {
+    s: Vec3,
+    t: Vec3,
+    n: Vec3,
+}
+
+impl Onb {
+    // Branchless construction from Duff et al. "Building an Orthonormal Basis, Revisited"
+    pub fn from_normal(n: &Vec3) -> Onb {
+        let sign = 1.0f64.copysign(n.z());
+        let a = -1.0 / (sign + n.z());
+        let b = n.x() * n.y() * a;
+        Onb {
+            s: Vec3::new(1.0 + sign * n.x() * n.x() * a, sign * b, -sign * n.x()),
+            t: Vec3::new(b, sign + n.y() * n.y() * a, -n.y()),
+            n: *n,
+        }
+    }
+
+    // Aligned so that s follows tangent, falling back to an arbitrary frame if it is degenerate
+    pub fn from_normal_tangent(n: &Vec3, tangent: &Vec3) -> Onb {
+        let s = *tangent - n.dot(tangent) * *n;
+        if s.length_squared() < 1e-12 {
+            return Onb::from_normal(n);
+        }
+        let s = s.unit();
+        Onb {
+            s,
+            t: n.cross(&s),
+            n: *n,
+        }
+    }
+
+    pub fn to_local(&self, v: &Vec3) -> Vec3 {
+        Vec3::new(v.dot(&self.s), v.dot(&self.t), v.dot(&self.n))
+    }
+
+    pub fn to_world(&self, v: &Vec3) -> Vec3 {
+        v.x() * self.s + v.y() * self.t + v.z() * self.n
+    }
+}
+
+// Cosine weighted direction about +z from two uniform samples
+pub fn cosine_hemisphere(u1: f64, u2: f64) -> Vec3 {
+    let r = u1.sqrt();
+    let phi = 2.0 * PI * u2;
+    Vec3::new(r * phi.cos(), r * phi.sin(), (1.0 - u1).max(0.0).sqrt())
+}
+
 pub struct Ray {
     pub origin: Vec3,
     pub direction: Vec3,
     pub time: f64,
+    // In nanometers, only set when rendering spectrally
+    pub wavelength: Option<f64>,
+    // Only set on camera rays and what they turn into at specular bounces
+    pub differentials: Option<Differentials>,
 }
 
 impl Ray {
-    pub fn new(origin: Vec3, direction: Vec3) -> Ray {
-        Ray {
-            origin,
-            direction,
-            time: 0.0,
-        }
-    }
-
     pub fn new_at(origin: Vec3, direction: Vec3, at: f64) -> Ray {
         Ray {
             origin,
             direction,
             time: at,
+            wavelength: None,
+            differentials: None,
         }
     }
 
@@ -223,14 +282,92 @@ impl Ray {
     }
 }
 
+/**
+ * Rays offset by one pixel (scaled by the sample count) in x and y, tracked alongside a ray to know
+ * how much of a surface a sample covers and so how much to filter textures
+ */
+#[derive(Clone, Copy)]
+pub struct Differentials {
+    pub rx_origin: Vec3,
+    pub rx_direction: Vec3,
+    pub ry_origin: Vec3,
+    pub ry_direction: Vec3,
+}
+
+/**
+ * Differentials of the mirror reflection of ray at hit, following pbrt.
+ * Curvature of the surface spreads them through the derivatives of the normal
+ */
+pub fn reflect_differentials(ray: &Ray, hit: &Hit) -> Option<Differentials> {
+    let d = ray.differentials?;
+    let n = hit.normal;
+    let wo = ray.direction.unit().flip();
+    let cos = wo.dot(&n);
+    let wi = 2.0 * cos * n - wo;
+    let (dudx, dvdx, dudy, dvdy) = hit.uv_derivatives();
+    let offset = |origin_offset: Vec3, direction: Vec3, du: f64, dv: f64| {
+        let dndx = du * hit.dndu + dv * hit.dndv;
+        let dwodx = direction.unit().flip() - wo;
+        let dcosdx = dwodx.dot(&n) + wo.dot(&dndx);
+        (
+            hit.point + origin_offset,
+            wi - dwodx + 2.0 * (cos * dndx + dcosdx * n),
+        )
+    };
+    let (rx_origin, rx_direction) = offset(hit.dpdx, d.rx_direction, dudx, dvdx);
+    let (ry_origin, ry_direction) = offset(hit.dpdy, d.ry_direction, dudy, dvdy);
+    Some(Differentials {
+        rx_origin,
+        rx_direction,
+        ry_origin,
+        ry_direction,
+    })
+}
+
+// Differentials of the refraction of ray at hit with the ratio of indices the refraction used
+pub fn refract_differentials(ray: &Ray, hit: &Hit, etai_over_etat: f64) -> Option<Differentials> {
+    let d = ray.differentials?;
+    let n = hit.normal;
+    let eta = etai_over_etat;
+    let wo = ray.direction.unit().flip();
+    let wi = refact(&wo.flip(), &n, eta);
+    let cos_i = wo.dot(&n);
+    let cos_t = wi.dot(&n).abs().max(1e-6);
+    let mu = eta * cos_i - cos_t;
+    let (dudx, dvdx, dudy, dvdy) = hit.uv_derivatives();
+    let offset = |origin_offset: Vec3, direction: Vec3, du: f64, dv: f64| {
+        let dndx = du * hit.dndu + dv * hit.dndv;
+        let dwodx = direction.unit().flip() - wo;
+        let dcosdx = dwodx.dot(&n) + wo.dot(&dndx);
+        let dmudx = (eta - eta * eta * cos_i / cos_t) * dcosdx;
+        (
+            hit.point + origin_offset,
+            wi - eta * dwodx + mu * dndx + dmudx * n,
+        )
+    };
+    let (rx_origin, rx_direction) = offset(hit.dpdx, d.rx_direction, dudx, dvdx);
+    let (ry_origin, ry_direction) = offset(hit.dpdy, d.ry_direction, dudy, dvdy);
+    Some(Differentials {
+        rx_origin,
+        rx_direction,
+        ry_origin,
+        ry_direction,
+    })
+}
+
 pub struct Scatter {
     pub scattered: Ray,
     pub attenuation: Vec3, // a color
 }
 
 pub trait Material: Sync {
-    // We hardcode the dep on ThreadRng...
-    fn scatter(&self, ray: &Ray, hit: &Hit, rng: &mut ThreadRng) -> Option<Scatter>;
+    // Each draw from the sampler takes the next dimension of the sample being traced
+    fn scatter(&self, ray: &Ray, hit: &Hit, rng: &mut Sampler) -> Option<Scatter>;
+
+    // The color of the surface for AOVs and denoising, white unless the material has an obvious one
+    fn albedo(&self, _hit: &Hit) -> Vec3 {
+        Vec3::new(1.0, 1.0, 1.0)
+    }
 }
 
 pub struct Lambertian {
@@ -244,13 +381,73 @@ impl Lambertian {
 }
 
 impl Material for Lambertian {
-    fn scatter(&self, ray: &Ray, hit: &Hit, rng: &mut ThreadRng) -> Option<Scatter> {
-        let scatter_direction = hit.normal + Vec3::new_raw(UnitBall.sample(rng));
+    fn scatter(&self, ray: &Ray, hit: &Hit, rng: &mut Sampler) -> Option<Scatter> {
+        let scatter_direction = hit.normal + rng.ball();
         Some(Scatter {
             scattered: Ray::new_at(hit.point, scatter_direction, ray.time),
-            attenuation: self.albedo.color(hit.u, hit.v, &hit.point),
+            attenuation: self.albedo(hit),
         })
     }
+
+    fn albedo(&self, hit: &Hit) -> Vec3 {
+        self.albedo
+            .color_filtered(hit.u, hit.v, &hit.point, &hit.normal, hit.footprint())
+    }
+}
+
+/**
+ * Rough diffuse surfaces such as clay or plaster which look flatter than Lambertian and brighten
+ * towards the light. roughness is the standard deviation of the facet angle in radians
+ */
+pub struct OrenNayar {
+    albedo: Arc<dyn Texture + Sync + Send>,
+    a: f64,
+    b: f64,
+}
+
+impl OrenNayar {
+    pub fn new(albedo: Arc<dyn Texture + Sync + Send>, roughness: f64) -> OrenNayar {
+        let sigma2 = roughness * roughness;
+        OrenNayar {
+            albedo,
+            a: 1.0 - 0.5 * sigma2 / (sigma2 + 0.33),
+            b: 0.45 * sigma2 / (sigma2 + 0.09),
+        }
+    }
+}
+
+impl Material for OrenNayar {
+    fn scatter(&self, ray: &Ray, hit: &Hit, rng: &mut Sampler) -> Option<Scatter> {
+        let unit = Uniform::new(0.0, 1.0);
+        let frame = Onb::from_normal(&hit.normal);
+        let wo = frame.to_local(&ray.direction.unit().flip());
+        let wi = cosine_hemisphere(rng.sample(unit), rng.sample(unit));
+
+        let sin_o = (1.0 - wo.z() * wo.z()).max(0.0).sqrt();
+        let sin_i = (1.0 - wi.z() * wi.z()).max(0.0).sqrt();
+        let cos_phi = if sin_o > 1e-6 && sin_i > 1e-6 {
+            ((wo.x() * wi.x() + wo.y() * wi.y()) / (sin_o * sin_i)).max(0.0)
+        } else {
+            0.0
+        };
+        // sin(alpha) * tan(beta) where alpha is the larger of the two angles from the normal
+        let (sin_alpha, tan_beta) = if wi.z().abs() > wo.z().abs() {
+            (sin_o, sin_i / wi.z().abs().max(1e-6))
+        } else {
+            (sin_i, sin_o / wo.z().abs().max(1e-6))
+        };
+
+        // Cosine sampling cancels the cosine and the 1/pi leaving the bracketed term
+        Some(Scatter {
+            scattered: Ray::new_at(hit.point, frame.to_world(&wi), ray.time),
+            attenuation: (self.a + self.b * cos_phi * sin_alpha * tan_beta) * self.albedo(hit),
+        })
+    }
+
+    fn albedo(&self, hit: &Hit) -> Vec3 {
+        self.albedo
+            .color_filtered(hit.u, hit.v, &hit.point, &hit.normal, hit.footprint())
+    }
 }
 
 pub struct Metal {
@@ -265,18 +462,17 @@ impl Metal {
     }
 }
 
-fn reflect(v: &Vec3, n: &Vec3) -> Vec3 {
+pub fn reflect(v: &Vec3, n: &Vec3) -> Vec3 {
     *v - 2.0 * v.dot(n) * *n
 }
 
 impl Material for Metal {
-    fn scatter(&self, ray: &Ray, hit: &Hit, rng: &mut ThreadRng) -> Option<Scatter> {
+    fn scatter(&self, ray: &Ray, hit: &Hit, rng: &mut Sampler) -> Option<Scatter> {
         let reflected = reflect(&ray.direction.unit(), &hit.normal);
-        let scattered = Ray::new_at(
-            hit.point,
-            reflected + self.fuzz * Vec3::new_raw(UnitBall.sample(rng)),
-            ray.time,
-        );
+        let mut scattered = Ray::new_at(hit.point, reflected + self.fuzz * rng.ball(), ray.time);
+        if self.fuzz == 0.0 {
+            scattered.differentials = reflect_differentials(ray, hit);
+        }
         if scattered.direction.dot(&hit.normal) > 0.0 {
             Some(Scatter {
                 scattered,
@@ -286,22 +482,127 @@ impl Material for Metal {
             None
         }
     }
+
+    fn albedo(&self, _hit: &Hit) -> Vec3 {
+        self.albedo
+    }
 }
 
-fn refact(uv: &Vec3, n: &Vec3, etai_over_etat: f64) -> Vec3 {
+pub fn refact(uv: &Vec3, n: &Vec3, etai_over_etat: f64) -> Vec3 {
     let cos_theta = uv.flip().dot(n);
     let r_out_parallel = etai_over_etat * (*uv + cos_theta * *n);
     let r_out_perp = -(1.0 - r_out_parallel.length_squared()).sqrt() * *n;
     r_out_parallel + r_out_perp
 }
 
+/**
+ * Index of refraction as a function of wavelength for dispersive dielectrics.
+ * Wavelengths for the fitted models are in micrometers as is conventional for the coefficients
+ */
+#[derive(Clone, Copy)]
+pub enum Ior {
+    Constant(f64),
+    Cauchy { a: f64, b: f64 },
+    Sellmeier { b: [f64; 3], c: [f64; 3] },
+}
+
+// The sodium d-line, where the nominal index of a glass is quoted
+const D_LINE_NM: f64 = 587.6;
+
+impl Ior {
+    pub fn bk7() -> Ior {
+        Ior::Sellmeier {
+            b: [1.039_612_12, 0.231_792_344, 1.010_469_45],
+            c: [0.006_000_698_67, 0.020_017_914_4, 103.560_653],
+        }
+    }
+
+    pub fn dense_flint() -> Ior {
+        Ior::Sellmeier {
+            b: [1.737_596_95, 0.313_747_346, 1.898_781_01],
+            c: [0.013_188_707, 0.062_306_814_2, 155.236_29],
+        }
+    }
+
+    pub fn diamond() -> Ior {
+        Ior::Sellmeier {
+            b: [0.3306, 4.3356, 0.0],
+            c: [0.175 * 0.175, 0.106 * 0.106, 0.0],
+        }
+    }
+
+    // Rays without a wavelength are rendering rgb and see the nominal index
+    pub fn at(&self, wavelength: Option<f64>) -> f64 {
+        let lambda = wavelength.unwrap_or(D_LINE_NM) / 1000.0;
+        match self {
+            Ior::Constant(n) => *n,
+            Ior::Cauchy { a, b } => a + b / (lambda * lambda),
+            Ior::Sellmeier { b, c } => {
+                let l2 = lambda * lambda;
+                let n2 = 1.0
+                    + b.iter()
+                        .zip(c.iter())
+                        .map(|(b, c)| b * l2 / (l2 - c))
+                        .sum::<f64>();
+                n2.sqrt()
+            }
+        }
+    }
+}
+
 pub struct Dielectric {
-    ref_idx: f64,
+    ior: Ior,
+    absorption: Vec3,
+    film: Option<ThinFilm>,
 }
 
 impl Dielectric {
     pub fn new(ref_idx: f64) -> Dielectric {
-        Dielectric { ref_idx }
+        Dielectric::new_absorbing(ref_idx, Vec3::zero())
+    }
+
+    // absorption is the Beer-Lambert coefficient per unit of distance traveled inside
+    pub fn new_absorbing(ref_idx: f64, absorption: Vec3) -> Dielectric {
+        Dielectric {
+            ior: Ior::Constant(ref_idx),
+            absorption,
+            film: None,
+        }
+    }
+
+    pub fn new_dispersive(ior: Ior) -> Dielectric {
+        Dielectric {
+            ior,
+            absorption: Vec3::zero(),
+            film: None,
+        }
+    }
+
+    // A ref_idx of 1 leaves just the film, which is a soap bubble
+    pub fn new_iridescent(ref_idx: f64, film: ThinFilm) -> Dielectric {
+        Dielectric {
+            ior: Ior::Constant(ref_idx),
+            absorption: Vec3::zero(),
+            film: Some(film),
+        }
+    }
+}
+
+// The absorption coefficient that tints light to color after traveling distance through a medium
+pub fn absorption_from_color(color: Vec3, distance: f64) -> Vec3 {
+    color.map(|c| -c.max(1e-6).ln() / distance)
+}
+
+/**
+ * Beer-Lambert transmittance for a ray that has just struck the inside of a boundary.
+ * The ray started on the boundary where it entered so the hit distance is the path length
+ */
+pub fn interior_transmittance(absorption: &Vec3, ray: &Ray, hit: &Hit) -> Vec3 {
+    if hit.front_face {
+        Vec3::new(1.0, 1.0, 1.0)
+    } else {
+        let distance = hit.t * ray.direction.length();
+        absorption.map(|a| (-a * distance).exp())
     }
 }
 
@@ -311,31 +612,66 @@ fn schlick(cosine: f64, ref_idx: f64) -> f64 {
 }
 
 impl Material for Dielectric {
-    fn scatter(&self, ray: &Ray, hit: &Hit, rng: &mut ThreadRng) -> Option<Scatter> {
-        let attenuation = Vec3::new(1.0, 1.0, 1.0);
+    fn scatter(&self, ray: &Ray, hit: &Hit, rng: &mut Sampler) -> Option<Scatter> {
+        let attenuation = interior_transmittance(&self.absorption, ray, hit);
+        let ref_idx = self.ior.at(ray.wavelength);
         let etai_over_etat = if hit.front_face {
-            1.0 / self.ref_idx
+            1.0 / ref_idx
         } else {
-            self.ref_idx
+            ref_idx
         };
         let unit_direction = ray.direction.unit();
         let cos_theta = (unit_direction.flip().dot(&hit.normal)).min(1.0);
         let sin_theta = (1.0 - cos_theta.powi(2)).sqrt();
-        let reflect_prob = schlick(cos_theta, etai_over_etat);
+        let white = Vec3::new(1.0, 1.0, 1.0);
+        // A colored reflectance is sampled by its average and the difference is made up in the weights
+        let (reflect_prob, reflect_weight, refract_weight) = match &self.film {
+            None => (schlick(cos_theta, etai_over_etat), white, white),
+            Some(film) => {
+                let (outer, substrate) = if hit.front_face {
+                    (1.0, ref_idx)
+                } else {
+                    (ref_idx, 1.0)
+                };
+                let reflectance = film.reflectance(
+                    cos_theta,
+                    outer,
+                    Vec3::new(substrate, substrate, substrate),
+                    Vec3::zero(),
+                    ray.wavelength,
+                );
+                let prob = (reflectance.dot(&white) / 3.0).clamp(1e-3, 1.0 - 1e-3);
+                (
+                    prob,
+                    reflectance / prob,
+                    (white - reflectance) / (1.0 - prob),
+                )
+            }
+        };
 
-        if etai_over_etat * sin_theta > 1.0 || rng.sample(Uniform::new(0.0, 1.0)) < reflect_prob {
+        if etai_over_etat * sin_theta > 1.0 {
             let reflected = reflect(&unit_direction, &hit.normal);
-            let scattered = Ray::new_at(hit.point, reflected, ray.time);
+            let mut scattered = Ray::new_at(hit.point, reflected, ray.time);
+            scattered.differentials = reflect_differentials(ray, hit);
             Some(Scatter {
                 scattered,
                 attenuation,
             })
+        } else if rng.sample(Uniform::new(0.0, 1.0)) < reflect_prob {
+            let reflected = reflect(&unit_direction, &hit.normal);
+            let mut scattered = Ray::new_at(hit.point, reflected, ray.time);
+            scattered.differentials = reflect_differentials(ray, hit);
+            Some(Scatter {
+                scattered,
+                attenuation: attenuation * reflect_weight,
+            })
         } else {
             let refacted = refact(&unit_direction, &hit.normal, etai_over_etat);
-            let scattered = Ray::new_at(hit.point, refacted, ray.time);
+            let mut scattered = Ray::new_at(hit.point, refacted, ray.time);
+            scattered.differentials = refract_differentials(ray, hit, etai_over_etat);
             Some(Scatter {
                 scattered,
-                attenuation,
+                attenuation: attenuation * refract_weight,
             })
         }
     }
@@ -347,17 +683,32 @@ pub struct Hit<'ma> {
     pub t: f64,
     pub u: f64,
     pub v: f64,
+    // Partial derivatives of the surface position along u and v, the tangent frame of the hit
+    pub dpdu: Vec3,
+    pub dpdv: Vec3,
+    // How the normal turns along u and v, zero unless the shape sets it
+    pub dndu: Vec3,
+    pub dndv: Vec3,
+    // Offsets to where the ray's differentials hit, zero when it has none
+    pub dpdx: Vec3,
+    pub dpdy: Vec3,
     pub front_face: bool,
     pub material: &'ma dyn Material,
+    // Tell primitives and their materials apart for the id AOVs, zero when they have no ids
+    pub object: u32,
+    pub material_id: u32,
 }
 
 impl<'ma> Hit<'ma> {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         point: Vec3,
         outward_normal: Vec3,
         t: f64,
         u: f64,
         v: f64,
+        dpdu: Vec3,
+        dpdv: Vec3,
         ray: &Ray,
         material: &'ma dyn Material,
     ) -> Hit<'ma> {
@@ -367,20 +718,91 @@ impl<'ma> Hit<'ma> {
         } else {
             outward_normal.flip()
         };
+        // Intersect the differential rays with the tangent plane at the hit
+        let (dpdx, dpdy) = match &ray.differentials {
+            Some(d) => {
+                let offset = |origin: Vec3, direction: Vec3| {
+                    let denominator = normal.dot(&direction);
+                    if denominator.abs() < 1e-12 {
+                        return Vec3::zero();
+                    }
+                    let t = normal.dot(&(point - origin)) / denominator;
+                    origin + t * direction - point
+                };
+                (
+                    offset(d.rx_origin, d.rx_direction),
+                    offset(d.ry_origin, d.ry_direction),
+                )
+            }
+            None => (Vec3::zero(), Vec3::zero()),
+        };
         Hit {
             point,
             normal,
             t,
             u,
             v,
+            dpdu,
+            dpdv,
+            dndu: Vec3::zero(),
+            dndv: Vec3::zero(),
+            dpdx,
+            dpdy,
             front_face,
             material,
+            object: 0,
+            material_id: 0,
+        }
+    }
+
+    // Derivatives of the outward normal, flipped along with the normal for back faces
+    pub fn with_normal_derivatives(self, dndu: Vec3, dndv: Vec3) -> Hit<'ma> {
+        let sign = if self.front_face { 1.0 } else { -1.0 };
+        Hit {
+            dndu: sign * dndu,
+            dndv: sign * dndv,
+            ..self
+        }
+    }
+
+    pub fn with_ids(self, object: u32, material_id: u32) -> Hit<'ma> {
+        Hit {
+            object,
+            material_id,
+            ..self
         }
     }
+
+    // (du/dx, dv/dx, du/dy, dv/dy) from a least squares fit of dpdx and dpdy to the tangents
+    pub fn uv_derivatives(&self) -> (f64, f64, f64, f64) {
+        let a00 = self.dpdu.dot(&self.dpdu);
+        let a01 = self.dpdu.dot(&self.dpdv);
+        let a11 = self.dpdv.dot(&self.dpdv);
+        let det = a00 * a11 - a01 * a01;
+        if det.abs() < 1e-12 {
+            return (0.0, 0.0, 0.0, 0.0);
+        }
+        let solve = |dp: &Vec3| {
+            let b0 = self.dpdu.dot(dp);
+            let b1 = self.dpdv.dot(dp);
+            ((a11 * b0 - a01 * b1) / det, (a00 * b1 - a01 * b0) / det)
+        };
+        let (dudx, dvdx) = solve(&self.dpdx);
+        let (dudy, dvdy) = solve(&self.dpdy);
+        (dudx, dvdx, dudy, dvdy)
+    }
+
+    // Width in texture space covered by the sample, for filtering texture lookups
+    pub fn footprint(&self) -> f64 {
+        let (dudx, dvdx, dudy, dvdy) = self.uv_derivatives();
+        (dudx * dudx + dvdx * dvdx)
+            .max(dudy * dudy + dvdy * dvdy)
+            .sqrt()
+    }
 }
 
 pub trait Hittable {
-    fn hit(&self, ray: &Ray, min_t: f64, max_t: f64) -> Option<Hit>;
+    fn hit(&self, ray: &Ray, min_t: f64, max_t: f64) -> Option<Hit<'_>>;
     fn bounding_box(&self, t0: f64, t1: f64) -> Option<AABB>;
 }
 
@@ -398,43 +820,76 @@ impl<A> Timed<A> {
     }
 }
 
-pub struct Sphere {
-    center0: Timed<Vec3>,
-    center1: Timed<Vec3>,
-    radius: f64,
+// A material as the objects of a scene hold it, clones of it share the id it was given
+#[derive(Clone)]
+pub struct SceneMaterial {
     material: Arc<dyn Material + Send + Sync>,
+    id: u32,
 }
 
-impl Sphere {
-    pub fn new(center: Vec3, radius: f64, material: Arc<dyn Material + Send + Sync>) -> Sphere {
-        Sphere {
-            center0: Timed {
+/**
+ * Makes the objects of a scene and gives them and their materials ids, counted from one in the order they are made.
+ * Scenes are built the same way every time, so their ids match from build to build and process to process
+ */
+#[derive(Default)]
+pub struct SceneBuilder {
+    objects: u32,
+    materials: u32,
+}
+
+impl SceneBuilder {
+    pub fn material(&mut self, material: Arc<dyn Material + Send + Sync>) -> SceneMaterial {
+        self.materials += 1;
+        SceneMaterial {
+            material,
+            id: self.materials,
+        }
+    }
+
+    pub fn sphere(&mut self, center: Vec3, radius: f64, material: &SceneMaterial) -> Sphere {
+        self.moving_sphere(
+            Timed {
                 value: center,
                 time: 0.0,
             },
-            center1: Timed {
+            Timed {
                 value: center,
                 time: f64::INFINITY,
             },
             radius,
             material,
-        }
+        )
     }
 
-    pub fn new_moving(
+    pub fn moving_sphere(
+        &mut self,
         center0: Timed<Vec3>,
         center1: Timed<Vec3>,
         radius: f64,
-        material: Arc<dyn Material + Send + Sync>,
+        material: &SceneMaterial,
     ) -> Sphere {
+        self.objects += 1;
         Sphere {
             center0,
             center1,
             radius,
-            material,
+            material: material.material.clone(),
+            id: self.objects,
+            material_id: material.id,
         }
     }
+}
 
+pub struct Sphere {
+    center0: Timed<Vec3>,
+    center1: Timed<Vec3>,
+    radius: f64,
+    material: Arc<dyn Material + Send + Sync>,
+    id: u32,
+    material_id: u32,
+}
+
+impl Sphere {
     fn center(&self, time: f64) -> Vec3 {
         self.center0.value
             + ((time - self.center0.time) / (self.center1.time - self.center0.time))
@@ -448,8 +903,48 @@ fn get_sphere_uv(point: &Vec3) -> (f64, f64) {
     (1.0 - (phi + PI) / (2.0 * PI), (theta + PI / 2.0) / PI)
 }
 
+// Derivatives of get_sphere_uv's parameterization for a point on the unit sphere scaled by radius
+fn get_sphere_tangents(point: &Vec3, radius: f64) -> (Vec3, Vec3) {
+    let rho = (point.x().powi(2) + point.z().powi(2)).sqrt();
+    let dpdu = 2.0 * PI * radius * Vec3::new(point.z(), 0.0, -point.x());
+    let dpdv = if rho > 1e-9 {
+        PI * radius
+            * Vec3::new(
+                -point.y() * point.x() / rho,
+                rho,
+                -point.y() * point.z() / rho,
+            )
+    } else {
+        // At the poles u is degenerate, any direction in the tangent plane will do
+        PI * radius * Vec3::new(1.0, 0.0, 0.0)
+    };
+    (dpdu, dpdv)
+}
+
+impl Sphere {
+    fn hit_at(&self, ray: &Ray, t: f64) -> Hit<'_> {
+        let point = ray.at(t);
+        let outward_normal = (point - self.center(ray.time)) / self.radius;
+        let (u, v) = get_sphere_uv(&outward_normal);
+        let (dpdu, dpdv) = get_sphere_tangents(&outward_normal, self.radius);
+        Hit::new(
+            point,
+            outward_normal,
+            t,
+            u,
+            v,
+            dpdu,
+            dpdv,
+            ray,
+            self.material.as_ref(),
+        )
+        .with_normal_derivatives(dpdu / self.radius, dpdv / self.radius)
+        .with_ids(self.id, self.material_id)
+    }
+}
+
 impl Hittable for Sphere {
-    fn hit(&self, ray: &Ray, min_t: f64, max_t: f64) -> Option<Hit> {
+    fn hit(&self, ray: &Ray, min_t: f64, max_t: f64) -> Option<Hit<'_>> {
         let oc = ray.origin - self.center(ray.time);
         let a = ray.direction.length_squared();
         let half_b = oc.dot(&ray.direction);
@@ -461,33 +956,11 @@ impl Hittable for Sphere {
             let root = discriminant.sqrt();
             let t = (-half_b - root) / a;
             if t < max_t && t > min_t {
-                let point = ray.at(t);
-                let outward_normal = (point - self.center(ray.time)) / self.radius;
-                let (u, v) = get_sphere_uv(&(point - self.center(ray.time)));
-                return Some(Hit::new(
-                    point,
-                    outward_normal,
-                    t,
-                    u,
-                    v,
-                    ray,
-                    self.material.as_ref(),
-                ));
+                return Some(self.hit_at(ray, t));
             }
             let t = (-half_b + root) / a;
             if t < max_t && t > min_t {
-                let point = ray.at(t);
-                let (u, v) = get_sphere_uv(&(point - self.center(ray.time)));
-                let outward_normal = (point - self.center(ray.time)) / self.radius;
-                return Some(Hit::new(
-                    point,
-                    outward_normal,
-                    t,
-                    u,
-                    v,
-                    ray,
-                    self.material.as_ref(),
-                ));
+                return Some(self.hit_at(ray, t));
             }
             None
         }
@@ -520,41 +993,105 @@ fn surrounding_box(box0: &AABB, box1: &AABB) -> AABB {
     AABB::new(small, big)
 }
 
-pub struct Collection(Vec<Box<dyn Hittable + Send + Sync>>);
+pub enum AlphaMode {
+    // Hits where the opacity is below the threshold are skipped
+    Threshold(f64),
+    // Hits are kept with probability equal to the opacity
+    Stochastic,
+}
 
-impl Collection {
-    pub fn new(v: Vec<Box<dyn Hittable + Send + Sync>>) -> Collection {
-        Collection(v)
-    }
+/**
+ * Makes parts of any hittable see-through based on the first channel of an opacity texture,
+ * so that a leaf or a fence can be a simple primitive with a mask.
+ * Rejected hits are skipped by looking again just past them
+ */
+pub struct Cutout {
+    inner: Box<dyn Hittable + Send + Sync>,
+    opacity: Arc<dyn Texture + Send + Sync>,
+    mode: AlphaMode,
 }
 
-impl Hittable for Collection {
-    fn hit(&self, ray: &Ray, min_t: f64, max_t: f64) -> Option<Hit> {
-        let mut current_hit: Option<Hit> = None;
-        for hittable in self.0.iter() {
-            let _max_t = current_hit.as_ref().map(|h| h.t).unwrap_or(max_t);
-            current_hit = hittable.hit(ray, min_t, _max_t).or(current_hit);
+impl Cutout {
+    pub fn new(
+        inner: Box<dyn Hittable + Send + Sync>,
+        opacity: Arc<dyn Texture + Send + Sync>,
+        mode: AlphaMode,
+    ) -> Cutout {
+        Cutout {
+            inner,
+            opacity,
+            mode,
         }
-        return current_hit;
     }
+}
 
-    fn bounding_box(&self, t0: f64, t1: f64) -> Option<AABB> {
-        let mut bound: Option<AABB> = None;
-        for hit_bound in self.0.iter().map(|h| h.bounding_box(t0, t1)) {
-            bound = match (bound, hit_bound) {
-                (Some(b), Some(h)) => Some(surrounding_box(&b, &h)),
-                (Some(b), None) => Some(b),
-                (None, Some(h)) => Some(h),
-                _ => None,
+// Spreads every bit of v over all of the result, the finalizer from pbrt
+pub fn mix_bits(mut v: u64) -> u64 {
+    v ^= v >> 31;
+    v = v.wrapping_mul(0x7fb5_d329_728e_a185);
+    v ^= v >> 27;
+    v = v.wrapping_mul(0x81da_def4_bc2d_d44d);
+    v ^ (v >> 33)
+}
+
+// Hashes the values in order, anything that needs reproducible randomness without an rng goes through this
+pub fn hash_bits(values: &[u64]) -> u64 {
+    values.iter().fold(0x243f_6a88_85a3_08d3, |h, v| {
+        mix_bits(h.rotate_left(23) ^ v.wrapping_mul(0x9e37_79b9_7f4a_7c15))
+    })
+}
+
+// The top 53 bits of a hash as a value in [0, 1)
+pub fn bits_to_unit(h: u64) -> f64 {
+    (h >> 11) as f64 / (1u64 << 53) as f64
+}
+
+// Hittable::hit has no rng so fractional alpha uses a hash of the ray and the hit instead
+fn hash_unit(values: &[f64]) -> f64 {
+    let bits: Vec<u64> = values.iter().map(|v| v.to_bits()).collect();
+    bits_to_unit(hash_bits(&bits))
+}
+
+impl Hittable for Cutout {
+    fn hit(&self, ray: &Ray, min_t: f64, max_t: f64) -> Option<Hit<'_>> {
+        let mut min_t = min_t;
+        loop {
+            let hit = self.inner.hit(ray, min_t, max_t)?;
+            let alpha = self
+                .opacity
+                .color(hit.u, hit.v, &hit.point, &hit.normal)
+                .x();
+            let keep = match self.mode {
+                AlphaMode::Threshold(threshold) => alpha >= threshold,
+                AlphaMode::Stochastic => {
+                    alpha
+                        > hash_unit(&[
+                            ray.origin.x(),
+                            ray.origin.y(),
+                            ray.origin.z(),
+                            ray.direction.x(),
+                            ray.direction.y(),
+                            ray.direction.z(),
+                            hit.t,
+                        ])
+                }
+            };
+            if keep {
+                return Some(hit);
             }
+            min_t = hit.t;
         }
-        bound
+    }
+
+    fn bounding_box(&self, t0: f64, t1: f64) -> Option<AABB> {
+        self.inner.bounding_box(t0, t1)
     }
 }
 
 /**
  * The bounding box thingamajig
  */
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Clone, Copy)]
 pub struct AABB {
     min: Vec3,
@@ -587,8 +1124,6 @@ impl AABB {
 pub struct BVHNode {
     left: Box<dyn Hittable + Send + Sync>,
     right: Box<dyn Hittable + Send + Sync>,
-    time0: f64,
-    time1: f64,
     aabb: Option<AABB>,
 }
 
@@ -610,8 +1145,6 @@ impl BVHNode {
         BVHNode {
             left,
             right,
-            time0,
-            time1,
             aabb: bound,
         }
     }
@@ -620,17 +1153,17 @@ impl BVHNode {
 struct Ephemeral;
 
 impl Hittable for Ephemeral {
-    fn hit(&self, ray: &Ray, min_t: f64, max_t: f64) -> Option<Hit> {
+    fn hit(&self, _ray: &Ray, _min_t: f64, _max_t: f64) -> Option<Hit<'_>> {
         None
     }
 
-    fn bounding_box(&self, t0: f64, t1: f64) -> Option<AABB> {
+    fn bounding_box(&self, _t0: f64, _t1: f64) -> Option<AABB> {
         None
     }
 }
 
 pub fn bvh_split_hittables(
-    rng: &mut ThreadRng,
+    rng: &mut StdRng,
     mut hittables: Vec<Box<dyn Hittable + Send + Sync>>,
     t0: f64,
     t1: f64,
@@ -678,7 +1211,7 @@ enum Axis {
 }
 
 impl Axis {
-    pub fn random(rng: &mut ThreadRng) -> Axis {
+    pub fn random(rng: &mut StdRng) -> Axis {
         match rng.gen_range(0, 3) {
             0 => Axis::X,
             1 => Axis::Y,
@@ -689,7 +1222,7 @@ impl Axis {
 }
 
 impl Hittable for BVHNode {
-    fn hit(&self, ray: &Ray, min_t: f64, max_t: f64) -> Option<Hit> {
+    fn hit(&self, ray: &Ray, min_t: f64, max_t: f64) -> Option<Hit<'_>> {
         if let Some(aabb) = self.aabb.as_ref() {
             if aabb.hit(ray, min_t, max_t) {
                 let hit_left = self.left.hit(ray, min_t, max_t);
@@ -702,12 +1235,36 @@ impl Hittable for BVHNode {
     }
 
     fn bounding_box(&self, _t0: f64, _t1: f64) -> Option<AABB> {
-        self.aabb.clone()
+        self.aabb
     }
 }
 
 pub trait Texture {
-    fn color(&self, u: f64, v: f64, point: &Vec3) -> Vec3;
+    fn color(&self, u: f64, v: f64, point: &Vec3, normal: &Vec3) -> Vec3;
+
+    // Averaged over about width of texture space around (u, v), textures that don't alias ignore it
+    fn color_filtered(&self, u: f64, v: f64, point: &Vec3, normal: &Vec3, _width: f64) -> Vec3 {
+        self.color(u, v, point, normal)
+    }
+}
+
+// A scalar material input that is either fixed or read from the first channel of a texture
+#[derive(Clone)]
+pub enum Parameter {
+    Constant(f64),
+    Texture(Arc<dyn Texture + Send + Sync>),
+}
+
+impl Parameter {
+    pub fn eval(&self, hit: &Hit) -> f64 {
+        match self {
+            Parameter::Constant(c) => *c,
+            Parameter::Texture(t) => t
+                .color_filtered(hit.u, hit.v, &hit.point, &hit.normal, hit.footprint())
+                .x(),
+        }
+        .clamp(0.0, 1.0)
+    }
 }
 
 pub struct SolidColor {
@@ -727,7 +1284,7 @@ impl SolidColor {
 }
 
 impl Texture for SolidColor {
-    fn color(&self, _u: f64, _v: f64, _point: &Vec3) -> Vec3 {
+    fn color(&self, _u: f64, _v: f64, _point: &Vec3, _normal: &Vec3) -> Vec3 {
         self.color
     }
 }
@@ -747,12 +1304,172 @@ impl CheckerTexture {
 }
 
 impl Texture for CheckerTexture {
-    fn color(&self, u: f64, v: f64, point: &Vec3) -> Vec3 {
+    fn color(&self, u: f64, v: f64, point: &Vec3, normal: &Vec3) -> Vec3 {
         let sines = (10.0 * point.x()).sin() * (10.0 * point.y()).sin() * (10.0 * point.z()).sin();
         if sines < 0.0 {
-            self.odd.color(u, v, point)
+            self.odd.color(u, v, point, normal)
         } else {
-            self.even.color(u, v, point)
+            self.even.color(u, v, point, normal)
         }
     }
 }
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::sampler::Method;
+
+    // For comparing vectors in assertions
+    pub fn components(v: &Vec3) -> [f64; 3] {
+        [v.x(), v.y(), v.z()]
+    }
+
+    // Where a ray arriving from the direction wo, at distance t, hits the z = 0 plane whose outward normal is +z
+    pub fn hit(material: &dyn Material, wo: Vec3, t: f64) -> (Ray, Hit<'_>) {
+        let wo = wo.unit();
+        let ray = Ray::new_at(t * wo, wo.flip(), 0.0);
+        let hit = Hit::new(
+            Vec3::zero(),
+            Vec3::new(0.0, 0.0, 1.0),
+            t,
+            0.5,
+            0.5,
+            Vec3::new(1.0, 0.0, 0.0),
+            Vec3::new(0.0, 1.0, 0.0),
+            &ray,
+            material,
+        );
+        (ray, hit)
+    }
+
+    // n scatters of a ray arriving from wo, each a different sample of the same pixel
+    pub fn scatters(material: &dyn Material, wo: Vec3, n: u32) -> Vec<Option<Scatter>> {
+        let (ray, hit) = hit(material, wo, 1.0);
+        let mut sampler = Sampler::new(Method::Independent, n, 1);
+        (0..n)
+            .map(|index| {
+                sampler.start_pixel_sample(0, 0, index);
+                material.scatter(&ray, &hit, &mut sampler)
+            })
+            .collect()
+    }
+
+    // Directions above the surface from straight down the normal to close to grazing
+    pub fn directions() -> Vec<Vec3> {
+        [1.0, 0.7, 0.3, 0.05]
+            .iter()
+            .map(|cos: &f64| Vec3::new((1.0 - cos * cos).sqrt(), 0.0, *cos))
+            .collect()
+    }
+
+    #[test]
+    fn sellmeier_gives_the_quoted_index_at_the_d_line() {
+        assert!((Ior::bk7().at(Some(587.6)) - 1.5168).abs() < 1e-4);
+        assert_eq!(Ior::bk7().at(None), Ior::bk7().at(Some(587.6)));
+        assert!((Ior::dense_flint().at(None) - 1.7847).abs() < 1e-3);
+        // Normal dispersion, blue bends more than red
+        for ior in [Ior::bk7(), Ior::dense_flint(), Ior::diamond()] {
+            assert!(ior.at(Some(450.0)) > ior.at(Some(650.0)));
+        }
+    }
+
+    #[test]
+    fn smooth_oren_nayar_is_lambertian() {
+        let albedo = Arc::new(SolidColor::new(0.8, 0.5, 0.2));
+        let oren_nayar = OrenNayar::new(albedo.clone(), 0.0);
+        let lambertian = Lambertian::new(albedo);
+        for wo in directions() {
+            let (_, hit) = hit(&lambertian, wo, 1.0);
+            let expected = components(&lambertian.albedo(&hit));
+            for scatter in scatters(&oren_nayar, wo, 200) {
+                let scatter = scatter.unwrap();
+                assert_eq!(components(&scatter.attenuation), expected);
+                assert!(scatter.scattered.direction.z() >= 0.0);
+            }
+            for scatter in scatters(&lambertian, wo, 200) {
+                assert_eq!(components(&scatter.unwrap().attenuation), expected);
+            }
+        }
+    }
+
+    #[test]
+    fn scene_ids_count_objects_and_share_materials() {
+        // The object and material ids a ray along +z sees on each of the spheres
+        let ids = || {
+            let mut scene = SceneBuilder::default();
+            let grey = scene.material(Arc::new(Lambertian::new(Arc::new(SolidColor::new(
+                0.5, 0.5, 0.5,
+            )))));
+            let glass = scene.material(Arc::new(Dielectric::new(1.5)));
+            let spheres = [
+                (0.0, scene.sphere(Vec3::new(0.0, 0.0, 0.0), 1.0, &grey)),
+                (3.0, scene.sphere(Vec3::new(3.0, 0.0, 0.0), 1.0, &glass)),
+                (
+                    6.0,
+                    scene.sphere(Vec3::new(6.0, 0.0, 0.0), 1.0, &grey.clone()),
+                ),
+            ];
+            spheres
+                .iter()
+                .map(|(x, sphere)| {
+                    let ray = Ray::new_at(Vec3::new(*x, 0.0, -5.0), Vec3::new(0.0, 0.0, 1.0), 0.0);
+                    let hit = sphere.hit(&ray, 0.001, f64::INFINITY).unwrap();
+                    (hit.object, hit.material_id)
+                })
+                .collect::<Vec<(u32, u32)>>()
+        };
+        assert_eq!(ids(), vec![(1, 1), (2, 2), (3, 1)]);
+        assert_eq!(ids(), ids());
+    }
+
+    // A unit sphere at the origin behind a cutout, and a solid one further along +z
+    fn cutout_scene(opacity: f64, mode: AlphaMode) -> BVHNode {
+        let mut scene = SceneBuilder::default();
+        let material = scene.material(Arc::new(Lambertian::new(Arc::new(SolidColor::new(
+            0.5, 0.5, 0.5,
+        )))));
+        let cut = scene.sphere(Vec3::zero(), 1.0, &material);
+        let opacity = Arc::new(SolidColor::new(opacity, opacity, opacity));
+        BVHNode::new(
+            Box::new(Cutout::new(Box::new(cut), opacity, mode)),
+            Box::new(scene.sphere(Vec3::new(0.0, 0.0, 5.0), 1.0, &material)),
+            0.0,
+            1.0,
+        )
+    }
+
+    #[test]
+    fn cutout_below_threshold_shows_what_is_behind() {
+        let ray = Ray::new_at(Vec3::new(0.0, 0.0, -5.0), Vec3::new(0.0, 0.0, 1.0), 0.0);
+        // Through both sides of the cut sphere and onto the solid one
+        for (opacity, t) in [(0.3, 9.0), (0.7, 4.0)] {
+            let scene = cutout_scene(opacity, AlphaMode::Threshold(0.5));
+            let hit = scene.hit(&ray, 0.001, f64::INFINITY).unwrap();
+            assert!((hit.t - t).abs() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn stochastic_cutout_is_the_same_for_the_same_ray() {
+        let scene = cutout_scene(0.5, AlphaMode::Stochastic);
+        let rays: Vec<Ray> = (0..400)
+            .map(|i| {
+                let (x, y) = (
+                    f64::from(i % 20) / 40.0 - 0.25,
+                    f64::from(i / 20) / 40.0 - 0.25,
+                );
+                Ray::new_at(Vec3::new(x, y, -5.0), Vec3::new(0.0, 0.0, 1.0), 0.0)
+            })
+            .collect();
+        let distances = || -> Vec<f64> {
+            (rays.iter())
+                .map(|ray| scene.hit(ray, 0.001, f64::INFINITY).unwrap().t)
+                .collect()
+        };
+        let first = distances();
+        assert_eq!(distances(), first);
+        // Half of the rays stop at the front of the cut sphere
+        let front = first.iter().filter(|t| **t < 4.5).count();
+        assert!((150..250).contains(&front), "{}", front);
+    }
+}
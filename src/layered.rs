@@ -0,0 +1,174 @@
+use super::geom::*;
+use super::microfacet::*;
+use super::sampler::Sampler;
+use rand::distributions::Uniform;
+use rand::Rng;
+use std::sync::Arc;
+
+/**
+ * Picks one of two materials per scatter, b with probability weight.
+ * Since each is sampled in proportion to its share the weight never shows up in the attenuation
+ */
+pub struct MixMaterial {
+    a: Arc<dyn Material + Send + Sync>,
+    b: Arc<dyn Material + Send + Sync>,
+    weight: Parameter,
+}
+
+impl MixMaterial {
+    pub fn new(
+        a: Arc<dyn Material + Send + Sync>,
+        b: Arc<dyn Material + Send + Sync>,
+        weight: Parameter,
+    ) -> MixMaterial {
+        MixMaterial { a, b, weight }
+    }
+}
+
+impl Material for MixMaterial {
+    fn scatter(&self, ray: &Ray, hit: &Hit, rng: &mut Sampler) -> Option<Scatter> {
+        if rng.sample(Uniform::new(0.0, 1.0)) < self.weight.eval(hit) {
+            self.b.scatter(ray, hit, rng)
+        } else {
+            self.a.scatter(ray, hit, rng)
+        }
+    }
+
+    fn albedo(&self, hit: &Hit) -> Vec3 {
+        let weight = self.weight.eval(hit);
+        (1.0 - weight) * self.a.albedo(hit) + weight * self.b.albedo(hit)
+    }
+}
+
+/**
+ * A thin dielectric layer such as varnish or a clear coat over any other material.
+ * Light either reflects off the coat according to fresnel or passes through to the base,
+ * picking up the tint of the layer on the way in and out
+ */
+pub struct Coated {
+    base: Arc<dyn Material + Send + Sync>,
+    distribution: TrowbridgeReitz,
+    ref_idx: f64,
+    // Color seen through the layer once at normal incidence
+    tint: Vec3,
+}
+
+impl Coated {
+    pub fn new(base: Arc<dyn Material + Send + Sync>, ref_idx: f64, roughness: f64) -> Coated {
+        Coated::new_tinted(base, ref_idx, roughness, Vec3::new(1.0, 1.0, 1.0))
+    }
+
+    pub fn new_tinted(
+        base: Arc<dyn Material + Send + Sync>,
+        ref_idx: f64,
+        roughness: f64,
+        tint: Vec3,
+    ) -> Coated {
+        Coated {
+            base,
+            distribution: TrowbridgeReitz::from_roughness(roughness, roughness),
+            ref_idx,
+            tint,
+        }
+    }
+}
+
+impl Material for Coated {
+    fn scatter(&self, ray: &Ray, hit: &Hit, rng: &mut Sampler) -> Option<Scatter> {
+        // The coat only exists on the outside, anything inside is the base's business
+        if !hit.front_face {
+            return self.base.scatter(ray, hit, rng);
+        }
+        let unit = Uniform::new(0.0, 1.0);
+        let frame = Onb::from_normal(&hit.normal);
+        let wo = frame.to_local(&ray.direction.unit().flip());
+        if wo.z() <= 0.0 {
+            return None;
+        }
+
+        let wm = self
+            .distribution
+            .sample_visible(&wo, rng.sample(unit), rng.sample(unit));
+        let reflect_prob = fresnel_dielectric(wo.dot(&wm), 1.0 / self.ref_idx);
+        if rng.sample(unit) < reflect_prob {
+            let wi = reflect_about(&wo, &wm);
+            if wi.z() <= 0.0 {
+                return None;
+            }
+            return Some(Scatter {
+                scattered: Ray::new_at(hit.point, frame.to_world(&wi), ray.time),
+                attenuation: Vec3::new(1.0, 1.0, 1.0) * self.distribution.g(&wo, &wi)
+                    / self.distribution.g1(&wo),
+            });
+        }
+
+        // Entering the layer was already paid for by the choice above, leaving it is not
+        let scatter = self.base.scatter(ray, hit, rng)?;
+        let cos_i = scatter.scattered.direction.unit().dot(&hit.normal);
+        if cos_i <= 0.0 {
+            return Some(scatter);
+        }
+        let exit = 1.0 - fresnel_dielectric(cos_i, 1.0 / self.ref_idx);
+        let path = 0.5 * (1.0 / wo.z() + 1.0 / cos_i);
+        Some(Scatter {
+            scattered: scatter.scattered,
+            attenuation: exit * scatter.attenuation * self.tint.map(|t| t.powf(path)),
+        })
+    }
+
+    fn albedo(&self, hit: &Hit) -> Vec3 {
+        self.base.albedo(hit) * self.tint
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::geom::tests::*;
+
+    #[test]
+    fn mix_at_either_end_is_one_material() {
+        // Smooth metals don't draw from the sampler, so each always scatters the same way
+        let gold: Arc<dyn Material + Send + Sync> =
+            Arc::new(Conductor::new(ComplexIor::gold(), 0.0));
+        let silver: Arc<dyn Material + Send + Sync> =
+            Arc::new(Conductor::new(ComplexIor::silver(), 0.0));
+        let wo = Vec3::new(0.3, 0.0, 1.0);
+        let (_, hit) = hit(gold.as_ref(), wo, 1.0);
+        for (weight, only) in [(0.0, &gold), (1.0, &silver)] {
+            let mix = MixMaterial::new(gold.clone(), silver.clone(), Parameter::Constant(weight));
+            let expected = scatters(only.as_ref(), wo, 1)[0]
+                .as_ref()
+                .unwrap()
+                .attenuation;
+            for scatter in scatters(&mix, wo, 100) {
+                assert_eq!(
+                    components(&scatter.unwrap().attenuation),
+                    components(&expected)
+                );
+            }
+            assert_eq!(
+                components(&mix.albedo(&hit)),
+                components(&only.albedo(&hit))
+            );
+        }
+    }
+
+    #[test]
+    fn coat_never_adds_energy() {
+        let white: Arc<dyn Material + Send + Sync> =
+            Arc::new(Lambertian::new(Arc::new(SolidColor::new(1.0, 1.0, 1.0))));
+        for roughness in [0.0, 0.3, 0.8] {
+            let coated = Coated::new(white.clone(), 1.5, roughness);
+            for wo in directions() {
+                let n = 2000;
+                let mut total = 0.0;
+                for scatter in scatters(&coated, wo, n).into_iter().flatten() {
+                    assert!(scatter.attenuation.x() <= 1.0);
+                    total += scatter.attenuation.x();
+                }
+                assert!(total / f64::from(n) <= 1.0);
+            }
+        }
+    }
+}
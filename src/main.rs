@@ -1,47 +1,289 @@
 #![warn(clippy::all)]
-use anyhow::{Context, Result};
-use clap::{value_t, App, Arg};
+use anyhow::{bail, Context, Result};
+use clap::{value_t, App, AppSettings, Arg, ArgMatches, SubCommand};
 use rand::distributions::*;
+use rand::rngs::StdRng;
 use rand::*;
-use std::fs::File;
-use std::io::BufWriter;
-use std::path::Path;
-use std::sync::Arc;
+use std::net::TcpListener;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
 mod geom;
 use geom::*;
+mod bump;
+use bump::*;
+mod cache;
+use cache::*;
+mod checkpoint;
+mod denoise;
+mod distributed;
 mod draw;
+mod filter;
+mod medium;
+use medium::*;
+mod microfacet;
+use microfacet::*;
+mod layered;
+mod output;
+use layered::*;
+mod principled;
+use principled::*;
+mod procedural;
+use procedural::*;
+mod sampler;
+mod spectrum;
+mod texture;
+use texture::*;
+mod thinfilm;
+use thinfilm::*;
+mod tiles;
+mod tonemap;
 
 const IMAGE_WIDTH: u32 = 1600;
 const IMAGE_HEIGHT: u32 = 800;
+// Scenes are laid out at random but the same way every run, so that checkpoints can be resumed
+const SCENE_SEED: u64 = 0;
 
 fn main() -> Result<()> {
     let matches = App::new("raytracing")
         .version("chapter-1")
         .author("Ryan Zeigler <zeiglerr@gmail.com>")
         .about("Implementation of the raytracing from https://raytracing.github.io/ to learn Rust")
+        .setting(AppSettings::SubcommandsNegateReqs)
+        .setting(AppSettings::ArgsNegateSubcommands)
+        .args(&render_args())
+        .arg(out_arg())
+        .subcommand(serve_command())
+        .subcommand(
+            SubCommand::with_name("worker")
+                .about("Renders tiles for a coordinator started with serve")
+                .arg(
+                    Arg::with_name("coordinator")
+                        .value_name("ADDRESS")
+                        .required(true)
+                        .help("Where the coordinator is listening, like 127.0.0.1:7878"),
+                ),
+        )
+        .get_matches();
+    match matches.subcommand() {
+        ("worker", Some(worker)) => {
+            distributed::work(worker.value_of("coordinator").unwrap(), |args| {
+                // Workers take the options the coordinator was started with, anything about output goes unused
+                let matches = serve_command().get_matches_from_safe(
+                    std::iter::once("serve").chain(args.iter().map(String::as_str)),
+                )?;
+                job(&matches)
+            })
+        }
+        ("serve", Some(serve)) => render(serve),
+        _ => render(&matches),
+    }
+}
+
+fn serve_command() -> App<'static, 'static> {
+    SubCommand::with_name("serve")
+        .about(
+            "Renders with workers that connect over TCP, taking the same options as a local render",
+        )
+        .args(&render_args())
+        .arg(out_arg())
+        .arg(
+            Arg::with_name("listen")
+                .long("listen")
+                .takes_value(true)
+                .value_name("ADDRESS")
+                .default_value("127.0.0.1:7878")
+                .help("Where workers connect to"),
+        )
         .arg(
-            Arg::with_name("width")
+            Arg::with_name("worker-timeout")
+                .long("worker-timeout")
+                .takes_value(true)
+                .value_name("SECONDS")
+                .default_value("60")
+                .help("How long a worker can go without being heard from before its tiles go to the others"),
+        )
+}
+
+fn out_arg() -> Arg<'static, 'static> {
+    Arg::with_name("out")
+        .value_name("FILE")
+        .takes_value(true)
+        .required(true)
+        .help("The path to write output too, .exr, .hdr and .pfm are high dynamic range and anything else is png")
+}
+
+fn render_args() -> Vec<Arg<'static, 'static>> {
+    vec![
+        Arg::with_name("width")
                 .short("w")
                 .long("width")
                 .takes_value(true)
                 .help("The width in pixels of the generated image"),
-        )
-        .arg(
-            Arg::with_name("height")
+        Arg::with_name("height")
                 .short("h")
                 .long("height")
                 .takes_value(true)
                 .help("The height in pixels of the generated image"),
-        )
-        .arg(
-            Arg::with_name("out")
+        Arg::with_name("scale")
+                .long("scale")
+                .takes_value(true)
+                .help("Multiplies the width and height, for quick low resolution renders of the same framing"),
+        Arg::with_name("crop")
+                .long("crop")
+                .takes_value(true)
+                .value_name("X0,Y0,X1,Y1")
+                .conflicts_with("crop-window")
+                .help("Only render the pixels from X0,Y0 up to X1,Y1 of the scaled image, counting from the top left"),
+        Arg::with_name("crop-window")
+                .long("crop-window")
+                .takes_value(true)
+                .value_name("X0,Y0,X1,Y1")
+                .help("Like --crop in fractions of the width and height, so it stays put at any scale"),
+        Arg::with_name("composite")
+                .long("composite")
+                .help("Write the whole frame with black around the crop rather than just the cropped pixels"),
+        Arg::with_name("scene")
+                .short("s")
+                .long("scene")
+                .takes_value(true)
+                .possible_values(&["large", "materials", "textures"])
+                .default_value("large")
+                .help("The scene to render"),
+        Arg::with_name("spectral")
+                .long("spectral")
+                .help("Trace a single wavelength per path so that dispersion is visible"),
+        Arg::with_name("texture-memory")
+                .long("texture-memory")
+                .takes_value(true)
+                .value_name("MB")
+                .default_value("512")
+                .help("How much memory tiles of image textures expanded for lookups may use before they are evicted"),
+        Arg::with_name("exposure")
+                .long("exposure")
+                .takes_value(true)
+                .allow_hyphen_values(true)
+                .default_value("0")
+                .help("Stops to brighten, or darken when negative, png output by"),
+        Arg::with_name("tonemap")
+                .long("tonemap")
+                .takes_value(true)
+                .possible_values(&["clamp", "reinhard", "aces", "agx"])
+                .default_value("clamp")
+                .help("How radiance is compressed into png output"),
+        Arg::with_name("white")
+                .long("white")
+                .takes_value(true)
+                .default_value("4")
+                .help("The luminance that reinhard tone mapping maps to white"),
+        Arg::with_name("exr-pixel")
+                .long("exr-pixel")
+                .takes_value(true)
+                .possible_values(&["half", "float"])
+                .default_value("half")
+                .help("The precision of channels in exr output"),
+        Arg::with_name("exr-compression")
+                .long("exr-compression")
+                .takes_value(true)
+                .possible_values(&["none", "zip"])
+                .default_value("zip")
+                .help("How exr output is compressed, both are lossless"),
+        Arg::with_name("samples")
+                .long("samples")
+                .takes_value(true)
+                .default_value("100")
+                .help("Camera rays per pixel, the most any pixel gets with adaptive sampling"),
+        Arg::with_name("pass-samples")
+                .long("pass-samples")
+                .takes_value(true)
+                .help("Render progressively in passes of this many samples per pixel, 4 by default, writing the image as it improves"),
+        Arg::with_name("snapshot-every")
+                .long("snapshot-every")
+                .takes_value(true)
+                .value_name("SECONDS")
+                .help("Only write progressive images this often instead of after every pass"),
+        Arg::with_name("time-budget")
+                .long("time-budget")
+                .takes_value(true)
+                .value_name("SECONDS")
+                .help("Stop rendering progressively after about this long and write the image as it is"),
+        Arg::with_name("checkpoint")
+                .long("checkpoint")
+                .takes_value(true)
                 .value_name("FILE")
+                .help("Render progressively and save everything needed to resume to this file now and then"),
+        Arg::with_name("checkpoint-every")
+                .long("checkpoint-every")
                 .takes_value(true)
-                .required(true)
-                .help("The path to write output too"),
-        )
-        .get_matches();
+                .value_name("SECONDS")
+                .help("How often to save the checkpoint, 60 by default, it is also saved when the time budget runs out"),
+        Arg::with_name("resume")
+                .long("resume")
+                .takes_value(true)
+                .value_name("CHECKPOINT")
+                .help("Carry on with a checkpointed render, the other options have to be the same as when it started"),
+        Arg::with_name("tile-size")
+                .long("tile-size")
+                .takes_value(true)
+                .default_value("32")
+                .help("Edge length in pixels of the square tiles the image is rendered in"),
+        Arg::with_name("tile-order")
+                .long("tile-order")
+                .takes_value(true)
+                .possible_values(&["scanline", "spiral", "hilbert"])
+                .default_value("spiral")
+                .help("The order tiles are rendered in"),
+        Arg::with_name("progress")
+                .long("progress")
+                .help("Report each tile as it is finished"),
+        Arg::with_name("preview")
+                .long("preview")
+                .takes_value(true)
+                .value_name("FILE")
+                .help("Write the image as tiles finish to this file, at most once a second"),
+        Arg::with_name("adaptive")
+                .long("adaptive")
+                .takes_value(true)
+                .value_name("ERROR")
+                .help("Stop sampling pixels once their standard error is below this fraction of their brightness, --samples becomes the most any pixel gets and a heatmap of the counts is written"),
+        Arg::with_name("min-samples")
+                .long("min-samples")
+                .takes_value(true)
+                .default_value("16")
+                .help("The fewest camera rays a pixel gets with adaptive sampling"),
+        Arg::with_name("sampler")
+                .long("sampler")
+                .takes_value(true)
+                .possible_values(&["independent", "stratified", "halton", "sobol"])
+                .default_value("independent")
+                .help("Where in each pixel, lens and bounce the samples go, all but independent converge faster"),
+        Arg::with_name("seed")
+                .long("seed")
+                .takes_value(true)
+                .default_value("0")
+                .help("Picks the samples, the same seed and options give the same image"),
+        Arg::with_name("filter")
+                .long("filter")
+                .takes_value(true)
+                .possible_values(&["box", "tent", "gaussian", "mitchell", "lanczos"])
+                .default_value("box")
+                .help("How samples are weighted into the pixels around them"),
+        Arg::with_name("filter-radius")
+                .long("filter-radius")
+                .takes_value(true)
+                .help("In pixels, each filter has its own default"),
+        Arg::with_name("aovs")
+                .long("aovs")
+                .help("Also output depth, normal, albedo, position, material and object ids, sample counts and variance, as layers in exr or as files next to the image otherwise"),
+        Arg::with_name("denoise")
+                .long("denoise")
+                .help("Filter out noise guided by the albedo, normals and depth the camera saw"),
+    ]
+}
+
+// Builds the scene, camera and render options from the command line
+fn job(matches: &ArgMatches) -> Result<distributed::Job> {
     let width = if matches.is_present("width") {
         value_t!(matches, "width", u32).with_context(|| "invalid width")?
     } else {
@@ -52,50 +294,405 @@ fn main() -> Result<()> {
     } else {
         IMAGE_HEIGHT
     };
-    let out_path = Path::new(matches.value_of("out").unwrap());
+    let (width, height) = if matches.is_present("scale") {
+        let scale = value_t!(matches, "scale", f64).with_context(|| "invalid scale")?;
+        if scale <= 0.0 {
+            bail!("scale has to be positive, not {}", scale);
+        }
+        let scaled = |size: u32| ((f64::from(size) * scale).round() as u32).max(1);
+        (scaled(width), scaled(height))
+    } else {
+        (width, height)
+    };
+    let crop = match (matches.value_of("crop"), matches.value_of("crop-window")) {
+        (Some(pixels), _) => Some(parse_crop(pixels, width, height, false)?),
+        (_, Some(fractions)) => Some(parse_crop(fractions, width, height, true)?),
+        _ => None,
+    };
+    let texture_memory =
+        value_t!(matches, "texture-memory", usize).with_context(|| "invalid texture memory")?;
 
     let image_width = f64::from(width);
     let image_height = f64::from(height);
     let aspect_ratio = image_width / image_height;
-    let lookfrom = Vec3::new(13.0, 2.0, 3.0);
-    let lookat = Vec3::new(0.0, 0.0, 0.0);
+    let mut scene = SceneBuilder::default();
+    let (world, view) = match matches.value_of("scene").unwrap() {
+        "materials" => (create_materials(&mut scene), View::rows()),
+        "textures" => (
+            create_textures(&mut scene, &TextureCache::new(texture_memory << 20))?,
+            View::rows(),
+        ),
+        _ => (create_large(&mut scene), View::large()),
+    };
+
     let vup = Vec3::new(0.0, 1.0, 0.0);
-    let dist_to_focus = 10.0;
     let aperture = 0.1;
     let camera = draw::Camera::new(
-        lookfrom,
-        lookat,
+        view.lookfrom,
+        view.lookat,
         vup,
-        20.0,
+        view.vfov,
         aspect_ratio,
         aperture,
-        dist_to_focus,
+        view.dist_to_focus,
         0.0,
         1.0,
     );
 
-    let world = create_large();
+    let mut filter = match matches.value_of("filter").unwrap() {
+        "tent" => filter::Filter::new_tent(),
+        "gaussian" => filter::Filter::new_gaussian(),
+        "mitchell" => filter::Filter::new_mitchell(),
+        "lanczos" => filter::Filter::new_lanczos(),
+        _ => filter::Filter::new_box(),
+    };
+    if matches.is_present("filter-radius") {
+        let radius =
+            value_t!(matches, "filter-radius", f64).with_context(|| "invalid filter radius")?;
+        if radius <= 0.0 {
+            bail!("filter radius must be positive");
+        }
+        filter = filter.with_radius(radius);
+    }
+    let adaptive = if matches.is_present("adaptive") {
+        Some(draw::Adaptive {
+            min_samples: value_t!(matches, "min-samples", u32)
+                .with_context(|| "invalid min samples")?
+                .max(2),
+            threshold: value_t!(matches, "adaptive", f64)
+                .with_context(|| "invalid adaptive error")?,
+        })
+    } else {
+        None
+    };
+    let options = draw::Options {
+        mode: if matches.is_present("spectral") {
+            draw::Mode::Spectral
+        } else {
+            draw::Mode::Rgb
+        },
+        filter,
+        sampler: match matches.value_of("sampler").unwrap() {
+            "stratified" => sampler::Method::Stratified,
+            "halton" => sampler::Method::Halton,
+            "sobol" => sampler::Method::Sobol,
+            _ => sampler::Method::Independent,
+        },
+        seed: value_t!(matches, "seed", u64).with_context(|| "invalid seed")?,
+        samples_per_pixel: value_t!(matches, "samples", u32)
+            .with_context(|| "invalid samples")?
+            .max(1),
+        // The denoiser is guided by the aovs even when they are not written out
+        aovs: matches.is_present("aovs") || matches.is_present("denoise"),
+        adaptive,
+        tile_size: value_t!(matches, "tile-size", u32)
+            .with_context(|| "invalid tile size")?
+            .max(1),
+        tile_order: match matches.value_of("tile-order").unwrap() {
+            "scanline" => tiles::Order::Scanline,
+            "hilbert" => tiles::Order::Hilbert,
+            _ => tiles::Order::Spiral,
+        },
+        crop,
+    };
+    Ok(distributed::Job {
+        width,
+        height,
+        camera,
+        world,
+        options,
+    })
+}
+
+// Renders here, or hands the tiles out to workers when serving, and writes the result
+fn render(matches: &ArgMatches) -> Result<()> {
+    let job = job(matches)?;
+    let out_path = Path::new(matches.value_of("out").unwrap());
+    let adaptive = job.options.adaptive;
+    let crop = job.options.crop;
+    let exposure = value_t!(matches, "exposure", f64).with_context(|| "invalid exposure")?;
+    let white = value_t!(matches, "white", f64).with_context(|| "invalid white")?;
+    let output_options = output::Options {
+        tonemap: tonemap::ToneMap::new(
+            exposure,
+            match matches.value_of("tonemap").unwrap() {
+                "reinhard" => tonemap::Operator::Reinhard { white },
+                "aces" => tonemap::Operator::Aces,
+                "agx" => tonemap::Operator::Agx,
+                _ => tonemap::Operator::Clamp,
+            },
+        ),
+        exr_pixel: match matches.value_of("exr-pixel").unwrap() {
+            "float" => output::ExrPixel::Float,
+            _ => output::ExrPixel::Half,
+        },
+        exr_compression: match matches.value_of("exr-compression").unwrap() {
+            "none" => output::ExrCompression::None,
+            _ => output::ExrCompression::Zip,
+        },
+        heatmap: adaptive.is_some(),
+    };
 
-    let content = draw::draw(width, height, &camera, &world);
-    write_png(width, height, &content, out_path)
+    // Cropped renders are written at the size of the crop unless they are composited into the frame
+    let framed = |image: draw::Framebuffer| match &crop {
+        Some(crop) if !matches.is_present("composite") => image.crop(crop),
+        _ => image,
+    };
+    let finish = |image: draw::Framebuffer| {
+        let mut image = framed(image);
+        if matches.is_present("denoise") {
+            if let Some(aovs) = &image.aovs {
+                image.pixels = denoise::denoise(&image, aovs);
+            }
+            if !matches.is_present("aovs") {
+                image.aovs = None;
+            }
+        }
+        output::write(&image, out_path, &output_options)
+    };
+    let progressive = [
+        "pass-samples",
+        "snapshot-every",
+        "time-budget",
+        "checkpoint",
+        "resume",
+    ]
+    .iter()
+    .any(|a| matches.is_present(a));
+    if let Some(address) = matches.value_of("listen") {
+        if progressive || matches.is_present("preview") {
+            bail!("serving renders every tile in one go, it can't be progressive or previewed");
+        }
+        // Everything after serve, workers parse it just the same
+        let args: Vec<String> = std::env::args().skip(2).collect();
+        let timeout =
+            value_t!(matches, "worker-timeout", f64).with_context(|| "invalid worker timeout")?;
+        // Too short and workers couldn't keep up with saying they are still there
+        let timeout = Duration::from_secs_f64(timeout.max(0.1));
+        let listener = TcpListener::bind(address)
+            .with_context(|| format!("failed to listen on {}", address))?;
+        let film = distributed::serve(
+            listener,
+            args,
+            &job,
+            timeout,
+            matches.is_present("progress"),
+        )?;
+        return finish(film.framebuffer(&job.options));
+    }
+    let distributed::Job {
+        width,
+        height,
+        camera,
+        world,
+        options,
+    } = job;
+    // Anything but a progressive render is a single pass
+    let pass_samples = if matches.is_present("pass-samples") {
+        value_t!(matches, "pass-samples", u32)
+            .with_context(|| "invalid pass samples")?
+            .max(1)
+    } else if progressive {
+        4
+    } else {
+        options.samples_per_pixel
+    };
+    let seconds = |name: &str| -> Result<Option<Duration>> {
+        if !matches.is_present(name) {
+            return Ok(None);
+        }
+        let seconds = value_t!(matches, name, f64).with_context(|| format!("invalid {}", name))?;
+        Ok(Some(Duration::from_secs_f64(seconds.max(0.0))))
+    };
+    let snapshot_every = seconds("snapshot-every")?;
+    let time_budget = seconds("time-budget")?;
+    let checkpoint_every = seconds("checkpoint-every")?.unwrap_or(Duration::from_secs(60));
+    // Resuming keeps saving to the checkpoint it resumed from unless told otherwise
+    let checkpoint_path = matches
+        .value_of("checkpoint")
+        .or_else(|| matches.value_of("resume"))
+        .map(Path::new);
+    // Everything the image depends on, a checkpoint only resumes a render started with the same
+    let settings = format!(
+        "scene {} size {}x{} samples {} in passes of {} sampler {} seed {} filter {} radius {} spectral {} adaptive {} from {} aovs {} tiles {} {} crop {}",
+        matches.value_of("scene").unwrap(),
+        width,
+        height,
+        options.samples_per_pixel,
+        pass_samples,
+        matches.value_of("sampler").unwrap(),
+        options.seed,
+        matches.value_of("filter").unwrap(),
+        matches.value_of("filter-radius").unwrap_or("default"),
+        matches.is_present("spectral"),
+        matches.value_of("adaptive").unwrap_or("off"),
+        matches.value_of("min-samples").unwrap(),
+        options.aovs,
+        options.tile_size,
+        matches.value_of("tile-order").unwrap(),
+        crop.map_or("none".to_string(), |c| format!(
+            "{},{},{},{}",
+            c.x0, c.y0, c.x1, c.y1
+        )),
+    );
+    let (mut film, mut done) = match matches.value_of("resume") {
+        Some(path) => checkpoint::load(
+            Path::new(path),
+            &settings,
+            width,
+            height,
+            options.samples_per_pixel,
+        )?,
+        None => (draw::Film::new(width, height), 0),
+    };
+    let start = Instant::now();
+    let mut last_snapshot = start;
+    let mut last_checkpoint = start;
+    let progress = matches.is_present("progress");
+    let preview_path = matches.value_of("preview").map(Path::new);
+    let preview_options = output::Options {
+        heatmap: false,
+        ..output_options
+    };
+    let region = draw::traced_region(width, height, &options);
+    let tile_count = (region.x1 - region.x0).div_ceil(options.tile_size) as usize
+        * (region.y1 - region.y0).div_ceil(options.tile_size) as usize;
+    while done < options.samples_per_pixel {
+        let pass_start = Instant::now();
+        let end = (done + pass_samples).min(options.samples_per_pixel);
+        // Tiles finish on the worker threads, the preview is shared between them
+        let image = preview_path.map(|_| film.preview(&options));
+        let preview = Mutex::new((image, Instant::now(), 0));
+        let on_tile = |tile: &draw::Tile| {
+            let mut preview = preview.lock().unwrap();
+            let (image, last_write, finished) = &mut *preview;
+            *finished += 1;
+            if progress {
+                eprint!(
+                    "\rsamples {}..{} of {}, tile {} of {} done at {}, {}",
+                    done, end, options.samples_per_pixel, finished, tile_count, tile.x, tile.y
+                );
+            }
+            if let (Some(path), Some(image)) = (preview_path, image.as_mut()) {
+                for row in 0..tile.height {
+                    let from = (row * tile.width) as usize;
+                    let to = ((tile.y + row) * image.width + tile.x) as usize;
+                    image.pixels[to..to + tile.width as usize]
+                        .copy_from_slice(&tile.pixels[from..from + tile.width as usize]);
+                }
+                // Writing after every tile would take longer than rendering small ones
+                if last_write.elapsed() >= Duration::from_secs(1) || *finished == tile_count {
+                    let written = match &crop {
+                        Some(crop) if !matches.is_present("composite") => {
+                            output::write(&image.crop(crop), path, &preview_options)
+                        }
+                        _ => output::write(image, path, &preview_options),
+                    };
+                    if let Err(e) = written {
+                        eprintln!("failed to write preview: {:#}", e);
+                    }
+                    *last_write = Instant::now();
+                }
+            }
+        };
+        draw::render_pass(&mut film, &camera, &world, &options, done..end, &on_tile);
+        if progress {
+            eprintln!();
+        }
+        done = end;
+        // Rather than start a pass that would likely run over the budget, stop with what there is
+        let out_of_time =
+            time_budget.is_some_and(|budget| start.elapsed() + pass_start.elapsed() > budget);
+        if let Some(path) = checkpoint_path {
+            if done < options.samples_per_pixel
+                && (out_of_time || last_checkpoint.elapsed() >= checkpoint_every)
+            {
+                checkpoint::save(path, &settings, done, &film)?;
+                last_checkpoint = Instant::now();
+            }
+        }
+        if out_of_time {
+            break;
+        }
+        if done < options.samples_per_pixel
+            && snapshot_every.is_none_or(|every| last_snapshot.elapsed() >= every)
+        {
+            finish(film.framebuffer(&options))?;
+            last_snapshot = Instant::now();
+        }
+    }
+    finish(film.framebuffer(&options))
+}
+
+// A crop window given as X0,Y0,X1,Y1 in pixels or in fractions of the image, clipped to it
+fn parse_crop(value: &str, width: u32, height: u32, normalized: bool) -> Result<draw::Crop> {
+    let values = value
+        .split(',')
+        .map(|v| v.trim().parse::<f64>())
+        .collect::<Result<Vec<f64>, _>>()
+        .with_context(|| format!("invalid crop: {}", value))?;
+    if values.len() != 4 {
+        bail!("a crop needs four values, X0,Y0,X1,Y1, not {}", value);
+    }
+    // Fractions take in every pixel they touch
+    let to_pixels = |v: f64, size: u32, round: fn(f64) -> f64| {
+        let v = if normalized {
+            round(v * f64::from(size))
+        } else {
+            v
+        };
+        v.max(0.0).min(f64::from(size)) as u32
+    };
+    let crop = draw::Crop {
+        x0: to_pixels(values[0], width, f64::floor),
+        y0: to_pixels(values[1], height, f64::floor),
+        x1: to_pixels(values[2], width, f64::ceil),
+        y1: to_pixels(values[3], height, f64::ceil),
+    };
+    if crop.x0 >= crop.x1 || crop.y0 >= crop.y1 {
+        bail!(
+            "crop {} leaves nothing of the {}x{} image",
+            value,
+            width,
+            height
+        );
+    }
+    Ok(crop)
 }
 
-fn write_png(width: u32, height: u32, data: &Vec<u8>, out_path: &Path) -> Result<()> {
-    // Do PNG things
-    let file = File::create(out_path)
-        .with_context(|| format!("failed to open output path: {:?}", out_path))?;
-    let writer = BufWriter::new(file);
-    let mut encoder = png::Encoder::new(writer, width, height);
-    encoder.set_color(png::ColorType::RGBA);
-    encoder.set_depth(png::BitDepth::Eight);
-    let mut writer = encoder.write_header().context("failed to write header")?;
-    writer
-        .write_image_data(&data)
-        .context("failed to write data")
+// Where the camera sits for each scene
+struct View {
+    lookfrom: Vec3,
+    lookat: Vec3,
+    vfov: f64,
+    dist_to_focus: f64,
 }
 
-fn create_large() -> Box<dyn Hittable + Send + Sync> {
-    let mut rng = thread_rng();
+impl View {
+    fn large() -> View {
+        View {
+            lookfrom: Vec3::new(13.0, 2.0, 3.0),
+            lookat: Vec3::new(0.0, 0.0, 0.0),
+            vfov: 20.0,
+            dist_to_focus: 10.0,
+        }
+    }
+
+    // Looking down on the rows of a showcase scene so that they don't hide each other
+    fn rows() -> View {
+        let lookfrom = Vec3::new(15.0, 14.0, 0.0);
+        let lookat = Vec3::new(-1.0, 0.5, 0.0);
+        View {
+            lookfrom,
+            lookat,
+            vfov: 22.0,
+            dist_to_focus: (lookfrom - lookat).length(),
+        }
+    }
+}
+
+fn create_large(scene: &mut SceneBuilder) -> Box<dyn Hittable + Send + Sync> {
+    let mut rng = StdRng::seed_from_u64(SCENE_SEED);
     let random_double = Uniform::new(0.0, 1.0);
     let fuzz_dist = Uniform::new(0.0, 0.5);
 
@@ -103,20 +700,18 @@ fn create_large() -> Box<dyn Hittable + Send + Sync> {
     /*
      * Build the world... this is kind of a bad interface because I tried to be clever with refs
      */
-    let ground: Box<dyn Hittable + Sync + Send> = Box::new(Sphere::new(
-        Vec3::new(0.0, -1000.0, 0.0),
-        1000.0,
-        Arc::new(Lambertian::new(Arc::new(CheckerTexture::new(
-            Arc::new(SolidColor::new(0.2, 0.3, 0.1)),
-            Arc::new(SolidColor::new(0.9, 0.9, 0.9)),
-        )))),
-    ));
+    let checker = scene.material(Arc::new(Lambertian::new(Arc::new(CheckerTexture::new(
+        Arc::new(SolidColor::new(0.2, 0.3, 0.1)),
+        Arc::new(SolidColor::new(0.9, 0.9, 0.9)),
+    )))));
+    let ground: Box<dyn Hittable + Sync + Send> =
+        Box::new(scene.sphere(Vec3::new(0.0, -1000.0, 0.0), 1000.0, &checker));
 
     objects.push(ground);
 
     let spheres = (-11..11)
         .flat_map(|a| (-11..11).map(move |b| (a, b)))
-        .map(|(a, b)| {
+        .filter_map(|(a, b)| {
             let choose_mat = random_double.sample(&mut rng);
             let center = Vec3::new(
                 f64::from(a) + 0.9 * random_double.sample(&mut rng),
@@ -132,53 +727,452 @@ fn create_large() -> Box<dyn Hittable + Send + Sync> {
                     world.add(make_shared<moving_sphere>(
                         center, center2, 0.0, 1.0, 0.2, sphere_material));
                     */
-                    let center2 = center + Vec3::new(0.0, rng.sample(&fuzz_dist), 0.0);
+                    let center2 = center + Vec3::new(0.0, rng.sample(fuzz_dist), 0.0);
                     let albedo = Vec3::random_dist(&mut rng, &random_double)
                         * Vec3::random_dist(&mut rng, &random_double);
-                    let mat = Arc::new(Lambertian::new(Arc::new(SolidColor::new_vec(albedo))));
-                    Box::new(Sphere::new_moving(
+                    let mat = scene.material(Arc::new(Lambertian::new(Arc::new(
+                        SolidColor::new_vec(albedo),
+                    ))));
+                    Box::new(scene.moving_sphere(
                         Timed::new(center, 0.0),
                         Timed::new(center2, 1.0),
                         0.2f64,
-                        mat,
+                        &mat,
                     ))
                 } else if choose_mat < 0.95 {
                     let albedo = Vec3::random_dist(&mut rng, &dist_05_1);
                     let fuzz = fuzz_dist.sample(&mut rng);
-                    let mat = Arc::new(Metal::new(albedo, fuzz));
-                    Box::new(Sphere::new(center, 0.2f64, mat))
+                    let mat = scene.material(Arc::new(Metal::new(albedo, fuzz)));
+                    Box::new(scene.sphere(center, 0.2f64, &mat))
                 } else {
-                    let mat = Arc::new(Dielectric::new(1.5));
-                    Box::new(Sphere::new(center, 0.2f64, mat))
+                    let mat = scene.material(Arc::new(Dielectric::new(1.5)));
+                    Box::new(scene.sphere(center, 0.2f64, &mat))
                 };
                 Some(sphere)
             } else {
                 None
             }
-        })
-        .flatten();
+        });
 
     objects.extend(spheres);
 
-    objects.push(Box::new(Sphere::new(
+    let glass = scene.material(Arc::new(Dielectric::new(1.5)));
+    objects.push(Box::new(scene.sphere(
         Vec3::new(0.0, 1.0, 0.0),
         1.0,
-        Arc::new(Dielectric::new(1.5)),
+        &glass,
     )));
 
-    objects.push(Box::new(Sphere::new(
+    let brown = scene.material(Arc::new(Lambertian::new(Arc::new(SolidColor::new_vec(
+        Vec3::new(0.4, 0.2, 0.1),
+    )))));
+    objects.push(Box::new(scene.sphere(
         Vec3::new(-4.0, 1.0, 0.0),
         1.0,
-        Arc::new(Lambertian::new(Arc::new(SolidColor::new_vec(Vec3::new(
-            0.4, 0.2, 0.1,
-        ))))),
+        &brown,
     )));
 
-    objects.push(Box::new(Sphere::new(
+    let mirror = scene.material(Arc::new(Metal::new(Vec3::new(0.7, 0.6, 0.5), 0.0)));
+    objects.push(Box::new(scene.sphere(
         Vec3::new(4.0, 1.0, 0.0),
         1.0,
-        Arc::new(Metal::new(Vec3::new(0.7, 0.6, 0.5), 0.0)),
+        &mirror,
     )));
 
     bvh_split_hittables(&mut rng, objects, 0.0, 1.0)
 }
+
+// A line of spheres across the view of the default camera to compare materials side by side
+fn create_materials(scene: &mut SceneBuilder) -> Box<dyn Hittable + Send + Sync> {
+    let mut rng = StdRng::seed_from_u64(SCENE_SEED);
+    let mut objects: Vec<Box<dyn Hittable + Sync + Send>> = Vec::new();
+
+    let checker = scene.material(Arc::new(Lambertian::new(Arc::new(CheckerTexture::new(
+        Arc::new(SolidColor::new(0.2, 0.3, 0.1)),
+        Arc::new(SolidColor::new(0.9, 0.9, 0.9)),
+    )))));
+    objects.push(Box::new(scene.sphere(
+        Vec3::new(0.0, -1000.0, 0.0),
+        1000.0,
+        &checker,
+    )));
+
+    let materials: Vec<Arc<dyn Material + Send + Sync>> = vec![
+        Arc::new(Conductor::new(ComplexIor::gold(), 0.0)),
+        Arc::new(Conductor::new(ComplexIor::gold(), 0.3)),
+        Arc::new(Conductor::new_anisotropic(ComplexIor::copper(), 0.6, 0.1)),
+        Arc::new(Conductor::new(ComplexIor::aluminium(), 0.2)),
+        Arc::new(Conductor::new(ComplexIor::silver(), 0.05)),
+    ];
+    add_material_row(scene, &mut objects, materials, 0);
+
+    let green_glass = absorption_from_color(Vec3::new(0.3, 0.8, 0.4), 1.0);
+    let materials: Vec<Arc<dyn Material + Send + Sync>> = vec![
+        Arc::new(Dielectric::new(1.5)),
+        Arc::new(Dielectric::new_absorbing(1.5, green_glass)),
+        Arc::new(RoughDielectric::new(1.5, 0.2)),
+        Arc::new(RoughDielectric::new_absorbing(1.5, 0.4, green_glass)),
+        Arc::new(RoughDielectric::new(1.33, 0.0)),
+    ];
+    add_material_row(scene, &mut objects, materials, 1);
+
+    let materials: Vec<Arc<dyn Material + Send + Sync>> = vec![
+        Arc::new(Dielectric::new_dispersive(Ior::bk7())),
+        Arc::new(Dielectric::new_dispersive(Ior::dense_flint())),
+        Arc::new(Dielectric::new_dispersive(Ior::diamond())),
+        Arc::new(Dielectric::new_dispersive(Ior::Cauchy {
+            a: 1.5046,
+            b: 0.0042,
+        })),
+        Arc::new(RoughDielectric::new_dispersive(Ior::dense_flint(), 0.1)),
+    ];
+    add_material_row(scene, &mut objects, materials, 2);
+
+    let checker = Arc::new(CheckerTexture::new(
+        Arc::new(SolidColor::new(0.1, 0.1, 0.1)),
+        Arc::new(SolidColor::new(0.7, 0.7, 0.7)),
+    ));
+    let materials: Vec<Arc<dyn Material + Send + Sync>> = vec![
+        Arc::new(Principled {
+            roughness: Parameter::Constant(0.3),
+            ..Principled::new(Arc::new(SolidColor::new(0.8, 0.1, 0.1)))
+        }),
+        Arc::new(Principled {
+            metallic: Parameter::Constant(1.0),
+            roughness: Parameter::Texture(checker),
+            ..Principled::new(Arc::new(SolidColor::new(0.9, 0.6, 0.3)))
+        }),
+        Arc::new(Principled {
+            roughness: Parameter::Constant(0.9),
+            sheen: Parameter::Constant(1.0),
+            sheen_tint: Parameter::Constant(1.0),
+            ..Principled::new(Arc::new(SolidColor::new(0.2, 0.1, 0.4)))
+        }),
+        Arc::new(Principled {
+            roughness: Parameter::Constant(0.6),
+            clearcoat: Parameter::Constant(1.0),
+            clearcoat_roughness: Parameter::Constant(0.05),
+            ..Principled::new(Arc::new(SolidColor::new(0.05, 0.2, 0.6)))
+        }),
+        Arc::new(Principled {
+            roughness: Parameter::Constant(0.1),
+            transmission: Parameter::Constant(1.0),
+            specular: Parameter::Constant(1.0),
+            ior: 1.45,
+            ..Principled::new(Arc::new(SolidColor::new(0.8, 0.9, 1.0)))
+        }),
+    ];
+    add_material_row(scene, &mut objects, materials, 3);
+
+    let flakes: Arc<dyn Material + Send + Sync> = Arc::new(MixMaterial::new(
+        Arc::new(Lambertian::new(Arc::new(SolidColor::new(0.5, 0.02, 0.05)))),
+        Arc::new(Conductor::new(ComplexIor::aluminium(), 0.3)),
+        Parameter::Constant(0.3),
+    ));
+    let boards = Arc::new(CheckerTexture::new(
+        Arc::new(SolidColor::new(0.35, 0.18, 0.07)),
+        Arc::new(SolidColor::new(0.55, 0.32, 0.14)),
+    ));
+    let stripes = Arc::new(CheckerTexture::new(
+        Arc::new(SolidColor::new(0.0, 0.0, 0.0)),
+        Arc::new(SolidColor::new(1.0, 1.0, 1.0)),
+    ));
+    let materials: Vec<Arc<dyn Material + Send + Sync>> = vec![
+        flakes.clone(),
+        Arc::new(Coated::new(flakes, 1.5, 0.0)),
+        Arc::new(Coated::new_tinted(
+            Arc::new(Lambertian::new(boards)),
+            1.5,
+            0.05,
+            Vec3::new(0.9, 0.7, 0.4),
+        )),
+        Arc::new(MixMaterial::new(
+            Arc::new(Conductor::new(ComplexIor::gold(), 0.2)),
+            Arc::new(Lambertian::new(Arc::new(SolidColor::new(0.1, 0.3, 0.6)))),
+            Parameter::Texture(stripes),
+        )),
+        Arc::new(Coated::new(
+            Arc::new(Conductor::new(ComplexIor::copper(), 0.5)),
+            1.6,
+            0.1,
+        )),
+    ];
+    add_material_row(scene, &mut objects, materials, 4);
+
+    let materials: Vec<Arc<dyn Material + Send + Sync>> = vec![
+        // Skin, wax, marble, jade and milk
+        Arc::new(Subsurface::new(
+            Medium::from_mean_free_path(Vec3::new(0.95, 0.7, 0.6), Vec3::new(0.25, 0.1, 0.06), 0.0),
+            1.4,
+        )),
+        Arc::new(Subsurface::new(
+            Medium::from_mean_free_path(Vec3::new(0.98, 0.9, 0.6), Vec3::new(0.3, 0.25, 0.15), 0.3),
+            1.45,
+        )),
+        Arc::new(Subsurface::new(
+            Medium::from_mean_free_path(
+                Vec3::new(0.98, 0.98, 0.97),
+                Vec3::new(0.15, 0.15, 0.15),
+                0.0,
+            ),
+            1.5,
+        )),
+        Arc::new(Subsurface::new(
+            Medium::from_mean_free_path(Vec3::new(0.5, 0.95, 0.6), Vec3::new(0.4, 0.6, 0.4), 0.5),
+            1.6,
+        )),
+        Arc::new(Subsurface::new(
+            Medium::new(Vec3::new(0.01, 0.02, 0.04), Vec3::new(8.0, 9.0, 10.0), 0.0),
+            1.33,
+        )),
+    ];
+    add_material_row(scene, &mut objects, materials, 5);
+
+    let clay = Arc::new(SolidColor::new(0.75, 0.45, 0.3));
+    let materials: Vec<Arc<dyn Material + Send + Sync>> = vec![
+        Arc::new(Dielectric::new_iridescent(1.0, ThinFilm::new(400.0, 1.33))),
+        Arc::new(Dielectric::new_iridescent(1.5, ThinFilm::new(250.0, 1.38))),
+        Arc::new(Conductor::new_iridescent(
+            ComplexIor::aluminium(),
+            0.15,
+            ThinFilm::new(500.0, 1.45),
+        )),
+        Arc::new(OrenNayar::new(clay.clone(), 0.0)),
+        Arc::new(OrenNayar::new(clay, 1.0)),
+    ];
+    add_material_row(scene, &mut objects, materials, 6);
+
+    bvh_split_hittables(&mut rng, objects, 0.0, 1.0)
+}
+
+fn asset(name: &str) -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("assets")
+        .join(name)
+}
+
+// Like the material scene but for things layered on top of materials
+fn create_textures(
+    scene: &mut SceneBuilder,
+    cache: &TextureCache,
+) -> Result<Box<dyn Hittable + Send + Sync>> {
+    let mut rng = StdRng::seed_from_u64(SCENE_SEED);
+    let mut objects: Vec<Box<dyn Hittable + Sync + Send>> = Vec::new();
+
+    let grey = scene.material(Arc::new(Lambertian::new(Arc::new(SolidColor::new(
+        0.5, 0.5, 0.5,
+    )))));
+    objects.push(Box::new(scene.sphere(
+        Vec3::new(0.0, -1000.0, 0.0),
+        1000.0,
+        &grey,
+    )));
+
+    let bricks: Arc<dyn Texture + Send + Sync> = cache.get(&asset("bricks_normal.png"))?;
+    let brick_red: Arc<dyn Material + Send + Sync> =
+        Arc::new(Lambertian::new(Arc::new(SolidColor::new(0.6, 0.2, 0.1))));
+    let bricks_height: Arc<dyn Texture + Send + Sync> = cache.get(&asset("bricks_height.png"))?;
+    let materials: Vec<Arc<dyn Material + Send + Sync>> = vec![
+        brick_red.clone(),
+        Arc::new(NormalMap::new(brick_red, bricks.clone(), 1.0)),
+        Arc::new(BumpMap::new(
+            Arc::new(Lambertian::new(Arc::new(SolidColor::new(0.8, 0.8, 0.8)))),
+            bricks_height.clone(),
+            0.01,
+        )),
+        Arc::new(NormalMap::new(
+            Arc::new(Conductor::new(ComplexIor::gold(), 0.2)),
+            bricks,
+            0.5,
+        )),
+        Arc::new(Conductor::new_anisotropic(
+            ComplexIor::aluminium(),
+            0.5,
+            0.05,
+        )),
+    ];
+    add_material_row(scene, &mut objects, materials, 0);
+
+    // Shared by all the cut out spheres, which show as one material in the aovs
+    let leaf = scene.material(Arc::new(Lambertian::new(Arc::new(SolidColor::new(
+        0.2, 0.5, 0.1,
+    )))));
+    let holes = Arc::new(CheckerTexture::new(
+        Arc::new(SolidColor::new(0.0, 0.0, 0.0)),
+        Arc::new(SolidColor::new(1.0, 1.0, 1.0)),
+    ));
+    let cutouts: Vec<(Arc<dyn Texture + Send + Sync>, AlphaMode)> = vec![
+        (holes, AlphaMode::Threshold(0.5)),
+        (bricks_height.clone(), AlphaMode::Threshold(0.9)),
+        (
+            Arc::new(SolidColor::new(0.5, 0.5, 0.5)),
+            AlphaMode::Stochastic,
+        ),
+        (bricks_height, AlphaMode::Stochastic),
+    ];
+    for (i, (opacity, mode)) in cutouts.into_iter().enumerate() {
+        let z = 1.1 * (i as f64 - 1.5);
+        let sphere = Box::new(scene.sphere(Vec3::new(1.5, 0.5, z), 0.5, &leaf));
+        objects.push(Box::new(Cutout::new(sphere, opacity, mode)));
+    }
+
+    let dark = Arc::new(SolidColor::new(0.1, 0.1, 0.1));
+    let light = Arc::new(SolidColor::new(0.9, 0.9, 0.9));
+    let uv_checker: Arc<dyn Texture + Send + Sync> = Arc::new(UvCheckerTexture::new(
+        dark.clone(),
+        light.clone(),
+        16.0,
+        8.0,
+    ));
+    let unit_checker: Arc<dyn Texture + Send + Sync> =
+        Arc::new(UvCheckerTexture::new(dark, light, 1.0, 1.0));
+    let bricks_color: Arc<dyn Texture + Send + Sync> = cache.get(&asset("bricks_height.png"))?;
+    let textures: Vec<Arc<dyn Texture + Send + Sync>> = vec![
+        uv_checker.clone(),
+        Arc::new(UvTransform::new(
+            uv_checker.clone(),
+            [1.0, 2.0],
+            30.0,
+            [0.0, 0.0],
+        )),
+        // The sphere's own parameterization but with the poles turned towards the camera
+        Arc::new(PointTransform::new(
+            Arc::new(Projected::new(uv_checker, Projection::Spherical)),
+            Vec3::new(1.0, 1.0, 1.0),
+            Vec3::new(1.0, 0.0, 0.0),
+            90.0,
+            Vec3::zero(),
+        )),
+        Arc::new(PointTransform::new(
+            Arc::new(Projected::new(
+                unit_checker.clone(),
+                Projection::Cylindrical,
+            )),
+            Vec3::new(1.0, 8.0, 1.0),
+            Vec3::new(0.0, 1.0, 0.0),
+            0.0,
+            Vec3::zero(),
+        )),
+        Arc::new(Projected::new(
+            Arc::new(UvTransform::new(bricks_color, [4.0, 4.0], 0.0, [0.0, 0.0])),
+            Projection::Triplanar,
+        )),
+        Arc::new(PointTransform::new(
+            Arc::new(Projected::new(unit_checker, Projection::Planar)),
+            Vec3::new(6.0, 6.0, 6.0),
+            Vec3::new(0.0, 0.0, 1.0),
+            45.0,
+            Vec3::zero(),
+        )),
+    ];
+    let x = 0.0;
+    let count = textures.len() as f64;
+    for (i, texture) in textures.into_iter().enumerate() {
+        let center = Vec3::new(x, 0.5, 1.1 * (i as f64 - (count - 1.0) / 2.0));
+        // Projections work around the origin so move the lookup there, uv stays attached anyway
+        let local: Arc<dyn Texture + Send + Sync> = Arc::new(PointTransform::new(
+            texture,
+            Vec3::new(1.0, 1.0, 1.0),
+            Vec3::new(0.0, 1.0, 0.0),
+            0.0,
+            center.flip(),
+        ));
+        let material = scene.material(Arc::new(Lambertian::new(local)));
+        objects.push(Box::new(scene.sphere(center, 0.5, &material)));
+    }
+
+    let grey: Arc<dyn Texture + Send + Sync> = Arc::new(SolidColor::new(0.25, 0.25, 0.25));
+    let cells: Arc<dyn Texture + Send + Sync> =
+        Arc::new(WorleyTexture::new(4.0, WorleyOutput::Cells));
+    let distance: Arc<dyn Texture + Send + Sync> =
+        Arc::new(WorleyTexture::new(6.0, WorleyOutput::Distance));
+    let edges: Arc<dyn Texture + Send + Sync> =
+        Arc::new(WorleyTexture::new(4.0, WorleyOutput::Edges));
+    let black = Vec3::zero();
+    let white = Vec3::new(1.0, 1.0, 1.0);
+    let procedurals: Vec<Arc<dyn Texture + Send + Sync>> = vec![
+        cells.clone(),
+        // Cobblestones, the edge distance is ramped into a sharp mask for the gaps
+        Arc::new(MixTexture::new(
+            grey.clone(),
+            cells,
+            Arc::new(ColorRampTexture::new(
+                edges,
+                (0.05, black),
+                vec![(0.1, white)],
+            )),
+        )),
+        Arc::new(WoodTexture::new(
+            Arc::new(SolidColor::new(0.75, 0.5, 0.3)),
+            Arc::new(SolidColor::new(0.4, 0.2, 0.1)),
+            8.0,
+            0.3,
+        )),
+        Arc::new(BrickTexture::new(
+            Arc::new(MultiplyTexture::new(
+                Arc::new(SolidColor::new(0.7, 0.25, 0.15)),
+                Arc::new(ColorRampTexture::new(
+                    distance.clone(),
+                    (0.0, Vec3::new(0.6, 0.6, 0.6)),
+                    vec![(1.0, white)],
+                )),
+            )),
+            grey,
+            0.125,
+            0.0625,
+            0.008,
+        )),
+        Arc::new(AddTexture::new(
+            Arc::new(GradientTexture::new(
+                Arc::new(SolidColor::new(0.1, 0.2, 0.6)),
+                Arc::new(SolidColor::new(0.7, 0.8, 0.9)),
+                Vec3::new(0.0, 1.0, 0.0),
+                -0.5,
+                0.5,
+            )),
+            Arc::new(MultiplyTexture::new(
+                Arc::new(SolidColor::new(0.2, 0.2, 0.2)),
+                Arc::new(InvertTexture::new(distance.clone())),
+            )),
+        )),
+        Arc::new(ColorRampTexture::new(
+            distance,
+            (0.0, Vec3::new(0.9, 0.8, 0.2)),
+            vec![
+                (0.4, Vec3::new(0.8, 0.2, 0.1)),
+                (0.8, Vec3::new(0.1, 0.05, 0.2)),
+            ],
+        )),
+    ];
+    let x = -1.5;
+    let count = procedurals.len() as f64;
+    for (i, texture) in procedurals.into_iter().enumerate() {
+        let center = Vec3::new(x, 0.5, 1.1 * (i as f64 - (count - 1.0) / 2.0));
+        let local: Arc<dyn Texture + Send + Sync> = Arc::new(PointTransform::new(
+            texture,
+            Vec3::new(1.0, 1.0, 1.0),
+            Vec3::new(0.0, 1.0, 0.0),
+            0.0,
+            center.flip(),
+        ));
+        let material = scene.material(Arc::new(Lambertian::new(local)));
+        objects.push(Box::new(scene.sphere(center, 0.5, &material)));
+    }
+
+    Ok(bvh_split_hittables(&mut rng, objects, 0.0, 1.0))
+}
+
+fn add_material_row(
+    scene: &mut SceneBuilder,
+    objects: &mut Vec<Box<dyn Hittable + Sync + Send>>,
+    materials: Vec<Arc<dyn Material + Send + Sync>>,
+    row: u32,
+) {
+    let x = 3.0 - 1.5 * f64::from(row);
+    let count = materials.len() as f64;
+    for (i, material) in materials.into_iter().enumerate() {
+        let z = 1.1 * (i as f64 - (count - 1.0) / 2.0);
+        let material = scene.material(material);
+        objects.push(Box::new(scene.sphere(Vec3::new(x, 0.5, z), 0.5, &material)));
+    }
+}
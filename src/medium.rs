@@ -0,0 +1,232 @@
+use super::geom::*;
+use super::microfacet::fresnel_dielectric;
+use super::sampler::Sampler;
+use rand::distributions::Uniform;
+use rand::Rng;
+use std::f64::consts::PI;
+
+/**
+ * A homogeneous participating medium with per channel coefficients and a Henyey-Greenstein phase function.
+ * Distances are sampled on a randomly chosen channel and the weights average over all three
+ */
+#[derive(Clone, Copy)]
+pub struct Medium {
+    sigma_a: Vec3,
+    sigma_s: Vec3,
+    g: f64,
+}
+
+pub enum Interaction {
+    // Scattered inside the medium at the point with the given throughput weight
+    Scatter(Vec3, Vec3),
+    // Made it the whole distance without scattering
+    Pass(Vec3),
+}
+
+impl Medium {
+    pub fn new(sigma_a: Vec3, sigma_s: Vec3, g: f64) -> Medium {
+        Medium {
+            sigma_a,
+            sigma_s,
+            g: g.clamp(-0.99, 0.99),
+        }
+    }
+
+    // Coefficients from the color after many bounces and the average distance between them
+    pub fn from_mean_free_path(albedo: Vec3, mean_free_path: Vec3, g: f64) -> Medium {
+        let sigma_t = mean_free_path.map(|d| 1.0 / d.max(1e-6));
+        let sigma_s = albedo * sigma_t;
+        Medium::new(sigma_t - sigma_s, sigma_s, g)
+    }
+
+    fn sigma_t(&self) -> Vec3 {
+        self.sigma_a + self.sigma_s
+    }
+
+    // Chance of scattering rather than being absorbed at each interaction
+    pub fn albedo(&self) -> Vec3 {
+        let sigma_t = self.sigma_t();
+        Vec3::new(
+            self.sigma_s.x() / sigma_t.x().max(1e-12),
+            self.sigma_s.y() / sigma_t.y().max(1e-12),
+            self.sigma_s.z() / sigma_t.z().max(1e-12),
+        )
+    }
+
+    pub fn transmittance(&self, distance: f64) -> Vec3 {
+        self.sigma_t().map(|s| (-s * distance).exp())
+    }
+
+    /**
+     * Sample a free flight along ray, which must start inside the medium, up to max_distance.
+     * Uses the single sample spectral MIS of Novak et al. over the three channels
+     */
+    pub fn sample(&self, ray: &Ray, max_distance: f64, rng: &mut Sampler) -> Interaction {
+        let unit = Uniform::new(0.0f64, 1.0f64);
+        let sigma_t = self.sigma_t();
+        let channel = match rng.sample(Uniform::new(0, 3)) {
+            0 => sigma_t.x(),
+            1 => sigma_t.y(),
+            _ => sigma_t.z(),
+        };
+        let distance = if channel > 0.0 {
+            -(1.0 - rng.sample(unit)).ln() / channel
+        } else {
+            f64::INFINITY
+        };
+        let length = ray.direction.length();
+
+        if distance < max_distance {
+            let tr = self.transmittance(distance);
+            let pdf = (sigma_t * tr).dot(&Vec3::new(1.0, 1.0, 1.0)) / 3.0;
+            Interaction::Scatter(ray.at(distance / length), self.sigma_s * tr / pdf)
+        } else {
+            let tr = self.transmittance(max_distance);
+            let pdf = tr.dot(&Vec3::new(1.0, 1.0, 1.0)) / 3.0;
+            Interaction::Pass(tr / pdf)
+        }
+    }
+
+    // New direction for light traveling along direction, importance sampled so the weight is one
+    pub fn sample_phase(&self, direction: &Vec3, rng: &mut Sampler) -> Vec3 {
+        let unit = Uniform::new(0.0, 1.0);
+        let (u1, u2) = (rng.sample(unit), rng.sample(unit));
+        let g = self.g;
+        let cos_theta = if g.abs() < 1e-3 {
+            1.0 - 2.0 * u1
+        } else {
+            let sq = (1.0 - g * g) / (1.0 - g + 2.0 * g * u1);
+            (1.0 + g * g - sq * sq) / (2.0 * g)
+        }
+        .clamp(-1.0, 1.0);
+        let sin_theta = (1.0 - cos_theta * cos_theta).sqrt();
+        let phi = 2.0 * PI * u2;
+        let frame = Onb::from_normal(&direction.unit());
+        frame.to_world(&Vec3::new(
+            sin_theta * phi.cos(),
+            sin_theta * phi.sin(),
+            cos_theta,
+        ))
+    }
+}
+
+/**
+ * Translucent materials like skin, wax and marble through a volumetric random walk.
+ * Rays refract into the object and then every hit against the inside of the boundary decides
+ * whether the medium scattered the ray before it got there. This needs closed geometry
+ */
+pub struct Subsurface {
+    medium: Medium,
+    ref_idx: f64,
+}
+
+impl Subsurface {
+    pub fn new(medium: Medium, ref_idx: f64) -> Subsurface {
+        Subsurface { medium, ref_idx }
+    }
+}
+
+impl Material for Subsurface {
+    fn scatter(&self, ray: &Ray, hit: &Hit, rng: &mut Sampler) -> Option<Scatter> {
+        let mut attenuation = Vec3::new(1.0, 1.0, 1.0);
+        if !hit.front_face {
+            let distance = hit.t * ray.direction.length();
+            match self.medium.sample(ray, distance, rng) {
+                Interaction::Scatter(point, weight) => {
+                    let direction = self.medium.sample_phase(&ray.direction, rng);
+                    return Some(Scatter {
+                        scattered: Ray::new_at(point, direction, ray.time),
+                        attenuation: weight,
+                    });
+                }
+                Interaction::Pass(weight) => attenuation = weight,
+            }
+        }
+
+        // At the boundary, either side, so a smooth dielectric interface
+        let etai_over_etat = if hit.front_face {
+            1.0 / self.ref_idx
+        } else {
+            self.ref_idx
+        };
+        let unit_direction = ray.direction.unit();
+        let cos_theta = unit_direction.flip().dot(&hit.normal);
+        let direction =
+            if rng.sample(Uniform::new(0.0, 1.0)) < fresnel_dielectric(cos_theta, etai_over_etat) {
+                reflect(&unit_direction, &hit.normal)
+            } else {
+                refact(&unit_direction, &hit.normal, etai_over_etat)
+            };
+        Some(Scatter {
+            scattered: Ray::new_at(hit.point, direction, ray.time),
+            attenuation,
+        })
+    }
+
+    fn albedo(&self, _hit: &Hit) -> Vec3 {
+        self.medium.albedo()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::geom::tests::components;
+    use crate::sampler::Method;
+
+    // Mean weights of n scatters and n passes, each counting the others as zero, along a ray of the given length
+    fn mean_weights(medium: &Medium, max_distance: f64, n: u32) -> (Vec3, Vec3) {
+        let ray = Ray::new_at(Vec3::zero(), Vec3::new(0.0, 0.0, 2.0), 0.0);
+        let mut sampler = Sampler::new(Method::Independent, n, 5);
+        let (mut scattered, mut passed) = (Vec3::zero(), Vec3::zero());
+        for index in 0..n {
+            sampler.start_pixel_sample(0, 0, index);
+            match medium.sample(&ray, max_distance, &mut sampler) {
+                Interaction::Scatter(_, weight) => scattered += weight,
+                Interaction::Pass(weight) => passed += weight,
+            }
+        }
+        (scattered / f64::from(n), passed / f64::from(n))
+    }
+
+    fn close(a: &Vec3, b: &Vec3, tolerance: f64) -> bool {
+        (*a - *b).length() < tolerance
+    }
+
+    #[test]
+    fn scattering_weight_is_the_albedo() {
+        // With the same coefficients in every channel each sample's weight is exactly the albedo
+        let grey = Medium::new(Vec3::new(0.5, 0.5, 0.5), Vec3::new(1.5, 1.5, 1.5), 0.0);
+        let ray = Ray::new_at(Vec3::zero(), Vec3::new(1.0, 0.0, 0.0), 0.0);
+        let mut sampler = Sampler::new(Method::Independent, 100, 5);
+        for index in 0..100 {
+            sampler.start_pixel_sample(0, 0, index);
+            match grey.sample(&ray, f64::INFINITY, &mut sampler) {
+                Interaction::Scatter(_, weight) => assert!(close(&weight, &grey.albedo(), 1e-12)),
+                Interaction::Pass(_) => panic!("passed through an infinite medium"),
+            }
+        }
+        // Otherwise it is on average
+        let colored =
+            Medium::from_mean_free_path(Vec3::new(0.9, 0.5, 0.1), Vec3::new(0.5, 1.0, 2.0), 0.3);
+        let (scattered, _) = mean_weights(&colored, f64::INFINITY, 40000);
+        assert!(
+            close(&scattered, &colored.albedo(), 0.01),
+            "{:?}",
+            components(&scattered)
+        );
+    }
+
+    #[test]
+    fn without_scattering_transmittance_is_beer_lambert() {
+        let sigma_a = Vec3::new(0.2, 1.0, 3.0);
+        let absorbing = Medium::new(sigma_a, Vec3::zero(), 0.0);
+        for distance in [0.1, 0.5, 2.0] {
+            let expected = sigma_a.map(|s| (-s * distance).exp());
+            assert!(close(&absorbing.transmittance(distance), &expected, 1e-12));
+            let (scattered, passed) = mean_weights(&absorbing, distance, 40000);
+            assert_eq!(components(&scattered), [0.0; 3]);
+            assert!(close(&passed, &expected, 0.01), "{:?}", components(&passed));
+        }
+    }
+}
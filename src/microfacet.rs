@@ -0,0 +1,453 @@
+use super::geom::*;
+use super::sampler::Sampler;
+use super::thinfilm::ThinFilm;
+use rand::distributions::Uniform;
+use rand::Rng;
+use std::f64::consts::PI;
+
+// Below this alpha the lobe is treated as a perfect mirror, the sampling routines lose precision
+const SMOOTH_ALPHA: f64 = 1e-4;
+
+/**
+ * The GGX/Trowbridge-Reitz microfacet distribution.
+ * Everything works in the local shading frame where the macro surface normal is +z
+ */
+#[derive(Clone, Copy)]
+pub struct TrowbridgeReitz {
+    alpha_x: f64,
+    alpha_y: f64,
+}
+
+impl TrowbridgeReitz {
+    pub fn new(alpha_x: f64, alpha_y: f64) -> TrowbridgeReitz {
+        TrowbridgeReitz {
+            alpha_x: alpha_x.max(0.0),
+            alpha_y: alpha_y.max(0.0),
+        }
+    }
+
+    // Perceptual roughness in [0, 1] maps to alpha = roughness^2
+    pub fn from_roughness(roughness_x: f64, roughness_y: f64) -> TrowbridgeReitz {
+        TrowbridgeReitz::new(roughness_x.powi(2), roughness_y.powi(2))
+    }
+
+    pub fn is_smooth(&self) -> bool {
+        self.alpha_x.max(self.alpha_y) < SMOOTH_ALPHA
+    }
+
+    fn lambda(&self, w: &Vec3) -> f64 {
+        let cos2 = w.z().powi(2);
+        if cos2 == 0.0 {
+            return f64::INFINITY;
+        }
+        let alpha2_tan2 = ((self.alpha_x * w.x()).powi(2) + (self.alpha_y * w.y()).powi(2)) / cos2;
+        ((1.0 + alpha2_tan2).sqrt() - 1.0) / 2.0
+    }
+
+    pub fn g1(&self, w: &Vec3) -> f64 {
+        1.0 / (1.0 + self.lambda(w))
+    }
+
+    // Height correlated masking-shadowing
+    pub fn g(&self, wo: &Vec3, wi: &Vec3) -> f64 {
+        1.0 / (1.0 + self.lambda(wo) + self.lambda(wi))
+    }
+
+    /**
+     * Sample a microfacet normal from the distribution of normals visible from wo.
+     * See Heitz "Sampling the GGX Distribution of Visible Normals" (2018)
+     */
+    pub fn sample_visible(&self, wo: &Vec3, u1: f64, u2: f64) -> Vec3 {
+        // Stretch into the configuration where the distribution is a hemisphere
+        let vh = Vec3::new(self.alpha_x * wo.x(), self.alpha_y * wo.y(), wo.z()).unit();
+        let lensq = vh.x().powi(2) + vh.y().powi(2);
+        let t1 = if lensq > 0.0 {
+            Vec3::new(-vh.y(), vh.x(), 0.0) / lensq.sqrt()
+        } else {
+            Vec3::new(1.0, 0.0, 0.0)
+        };
+        let t2 = vh.cross(&t1);
+
+        let r = u1.sqrt();
+        let phi = 2.0 * PI * u2;
+        let p1 = r * phi.cos();
+        let s = 0.5 * (1.0 + vh.z());
+        let p2 = (1.0 - s) * (1.0 - p1.powi(2)).sqrt() + s * r * phi.sin();
+
+        let nh = p1 * t1 + p2 * t2 + (1.0 - p1.powi(2) - p2.powi(2)).max(0.0).sqrt() * vh;
+        // And unstretch back
+        Vec3::new(
+            self.alpha_x * nh.x(),
+            self.alpha_y * nh.y(),
+            nh.z().max(1e-6),
+        )
+        .unit()
+    }
+}
+
+// Reflect wo about the microfacet normal wm, both pointing away from the surface
+pub fn reflect_about(wo: &Vec3, wm: &Vec3) -> Vec3 {
+    reflect(&wo.flip(), wm)
+}
+
+/**
+ * A complex index of refraction per rgb channel.
+ * Presets are sampled from the measured data at roughly 650, 550, 450nm
+ */
+#[derive(Clone, Copy)]
+pub struct ComplexIor {
+    eta: Vec3,
+    k: Vec3,
+}
+
+impl ComplexIor {
+    pub fn new(eta: Vec3, k: Vec3) -> ComplexIor {
+        ComplexIor { eta, k }
+    }
+
+    pub fn gold() -> ComplexIor {
+        ComplexIor::new(
+            Vec3::new(0.143, 0.374, 1.442),
+            Vec3::new(3.983, 2.385, 1.603),
+        )
+    }
+
+    pub fn copper() -> ComplexIor {
+        ComplexIor::new(
+            Vec3::new(0.200, 0.924, 1.102),
+            Vec3::new(3.912, 2.452, 2.142),
+        )
+    }
+
+    pub fn aluminium() -> ComplexIor {
+        ComplexIor::new(
+            Vec3::new(1.657, 0.880, 0.521),
+            Vec3::new(9.224, 6.270, 4.837),
+        )
+    }
+
+    pub fn silver() -> ComplexIor {
+        ComplexIor::new(
+            Vec3::new(0.155, 0.117, 0.138),
+            Vec3::new(4.828, 3.122, 2.147),
+        )
+    }
+
+    pub fn fresnel(&self, cos_i: f64) -> Vec3 {
+        Vec3::new(
+            fresnel_conductor(cos_i, self.eta.x(), self.k.x()),
+            fresnel_conductor(cos_i, self.eta.y(), self.k.y()),
+            fresnel_conductor(cos_i, self.eta.z(), self.k.z()),
+        )
+    }
+}
+
+// Unpolarized fresnel reflectance for a conductor, exact rather than Schlick's fit
+fn fresnel_conductor(cos_i: f64, eta: f64, k: f64) -> f64 {
+    let cos_i = cos_i.clamp(0.0, 1.0);
+    let cos2 = cos_i * cos_i;
+    let sin2 = 1.0 - cos2;
+    let eta2 = eta * eta;
+    let k2 = k * k;
+
+    let t0 = eta2 - k2 - sin2;
+    let a2b2 = (t0 * t0 + 4.0 * eta2 * k2).sqrt();
+    let t1 = a2b2 + cos2;
+    let a = (0.5 * (a2b2 + t0)).max(0.0).sqrt();
+    let t2 = 2.0 * cos_i * a;
+    let rs = (t1 - t2) / (t1 + t2);
+
+    let t3 = cos2 * a2b2 + sin2 * sin2;
+    let t4 = t2 * sin2;
+    let rp = rs * (t3 - t4) / (t3 + t4);
+
+    0.5 * (rp + rs)
+}
+
+pub struct Conductor {
+    distribution: TrowbridgeReitz,
+    ior: ComplexIor,
+    film: Option<ThinFilm>,
+}
+
+impl Conductor {
+    pub fn new(ior: ComplexIor, roughness: f64) -> Conductor {
+        Conductor::new_anisotropic(ior, roughness, roughness)
+    }
+
+    // Roughness along the tangent and bitangent of the shading frame respectively
+    pub fn new_anisotropic(ior: ComplexIor, roughness_u: f64, roughness_v: f64) -> Conductor {
+        Conductor {
+            distribution: TrowbridgeReitz::from_roughness(roughness_u, roughness_v),
+            ior,
+            film: None,
+        }
+    }
+
+    // An oily or oxidized layer on top of the metal
+    pub fn new_iridescent(ior: ComplexIor, roughness: f64, film: ThinFilm) -> Conductor {
+        Conductor {
+            film: Some(film),
+            ..Conductor::new(ior, roughness)
+        }
+    }
+
+    fn fresnel(&self, cos_i: f64, wavelength: Option<f64>) -> Vec3 {
+        match &self.film {
+            Some(film) => film.reflectance(cos_i, 1.0, self.ior.eta, self.ior.k, wavelength),
+            None => self.ior.fresnel(cos_i),
+        }
+    }
+}
+
+impl Material for Conductor {
+    fn scatter(&self, ray: &Ray, hit: &Hit, rng: &mut Sampler) -> Option<Scatter> {
+        // Anisotropic roughness follows the surface's u direction
+        let frame = Onb::from_normal_tangent(&hit.normal, &hit.dpdu);
+        let wo = frame.to_local(&ray.direction.unit().flip());
+        if wo.z() <= 0.0 {
+            return None;
+        }
+        if self.distribution.is_smooth() {
+            let wi = Vec3::new(-wo.x(), -wo.y(), wo.z());
+            let mut scattered = Ray::new_at(hit.point, frame.to_world(&wi), ray.time);
+            scattered.differentials = reflect_differentials(ray, hit);
+            return Some(Scatter {
+                scattered,
+                attenuation: self.fresnel(wo.z(), ray.wavelength),
+            });
+        }
+
+        let unit = Uniform::new(0.0, 1.0);
+        let wm = self
+            .distribution
+            .sample_visible(&wo, rng.sample(unit), rng.sample(unit));
+        let wi = reflect_about(&wo, &wm);
+        if wi.z() <= 0.0 {
+            return None;
+        }
+        // With visible normal sampling f * cos / pdf reduces to F * G2 / G1
+        let weight = self.distribution.g(&wo, &wi) / self.distribution.g1(&wo);
+        Some(Scatter {
+            scattered: Ray::new_at(hit.point, frame.to_world(&wi), ray.time),
+            attenuation: weight * self.fresnel(wo.dot(&wm), ray.wavelength),
+        })
+    }
+
+    // Reflectance at normal incidence
+    fn albedo(&self, _hit: &Hit) -> Vec3 {
+        self.ior.fresnel(1.0)
+    }
+}
+
+// Unpolarized fresnel reflectance for a dielectric interface where eta is etai_over_etat
+pub fn fresnel_dielectric(cos_i: f64, eta: f64) -> f64 {
+    let cos_i = cos_i.clamp(0.0, 1.0);
+    let sin2_t = eta * eta * (1.0 - cos_i * cos_i);
+    if sin2_t >= 1.0 {
+        // Total internal reflection
+        return 1.0;
+    }
+    let cos_t = (1.0 - sin2_t).sqrt();
+    let rs = (eta * cos_i - cos_t) / (eta * cos_i + cos_t);
+    let rp = (cos_i - eta * cos_t) / (cos_i + eta * cos_t);
+    0.5 * (rs * rs + rp * rp)
+}
+
+pub struct RoughDielectric {
+    distribution: TrowbridgeReitz,
+    ior: Ior,
+    absorption: Vec3,
+}
+
+impl RoughDielectric {
+    pub fn new(ref_idx: f64, roughness: f64) -> RoughDielectric {
+        RoughDielectric::new_absorbing(ref_idx, roughness, Vec3::zero())
+    }
+
+    pub fn new_absorbing(ref_idx: f64, roughness: f64, absorption: Vec3) -> RoughDielectric {
+        RoughDielectric {
+            distribution: TrowbridgeReitz::from_roughness(roughness, roughness),
+            ior: Ior::Constant(ref_idx),
+            absorption,
+        }
+    }
+
+    pub fn new_dispersive(ior: Ior, roughness: f64) -> RoughDielectric {
+        RoughDielectric {
+            distribution: TrowbridgeReitz::from_roughness(roughness, roughness),
+            ior,
+            absorption: Vec3::zero(),
+        }
+    }
+}
+
+impl Material for RoughDielectric {
+    fn scatter(&self, ray: &Ray, hit: &Hit, rng: &mut Sampler) -> Option<Scatter> {
+        let transmittance = interior_transmittance(&self.absorption, ray, hit);
+        let ref_idx = self.ior.at(ray.wavelength);
+        let etai_over_etat = if hit.front_face {
+            1.0 / ref_idx
+        } else {
+            ref_idx
+        };
+        let frame = Onb::from_normal(&hit.normal);
+        let wo = frame.to_local(&ray.direction.unit().flip());
+        if wo.z() <= 0.0 {
+            return None;
+        }
+
+        let unit = Uniform::new(0.0, 1.0);
+        let wm = if self.distribution.is_smooth() {
+            Vec3::new(0.0, 0.0, 1.0)
+        } else {
+            self.distribution
+                .sample_visible(&wo, rng.sample(unit), rng.sample(unit))
+        };
+        let cos_m = wo.dot(&wm);
+        let reflect_prob = fresnel_dielectric(cos_m, etai_over_etat);
+
+        // Choosing between the lobes by fresnel cancels it out of the weight
+        let reflected = rng.sample(unit) < reflect_prob;
+        let wi = if reflected {
+            let wi = reflect_about(&wo, &wm);
+            if wi.z() <= 0.0 {
+                return None;
+            }
+            wi
+        } else {
+            let wi = refact(&wo.flip(), &wm, etai_over_etat);
+            if wi.z() >= 0.0 {
+                return None;
+            }
+            wi
+        };
+        let mut scattered = Ray::new_at(hit.point, frame.to_world(&wi), ray.time);
+        let weight = if self.distribution.is_smooth() {
+            scattered.differentials = if reflected {
+                reflect_differentials(ray, hit)
+            } else {
+                refract_differentials(ray, hit, etai_over_etat)
+            };
+            1.0
+        } else {
+            self.distribution.g(&wo, &wi) / self.distribution.g1(&wo)
+        };
+        Some(Scatter {
+            scattered,
+            attenuation: weight * transmittance,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::geom::tests::*;
+
+    #[test]
+    fn conductor_fresnel_at_normal_incidence() {
+        let gold = ComplexIor::gold();
+        for (eta, k) in [
+            (gold.eta.x(), gold.k.x()),
+            (1.657, 9.224),
+            (1.5, 0.0),
+            (0.2, 3.9),
+        ] {
+            let expected = ((eta - 1.0).powi(2) + k * k) / ((eta + 1.0).powi(2) + k * k);
+            assert!((fresnel_conductor(1.0, eta, k) - expected).abs() < 1e-12);
+        }
+    }
+
+    #[test]
+    fn conductor_fresnel_goes_to_one_at_grazing_angles() {
+        for (eta, k) in [(0.143, 3.983), (1.657, 9.224), (0.155, 4.828)] {
+            let mut last = fresnel_conductor(0.01, eta, k);
+            for cos in [1e-3, 1e-4, 1e-5] {
+                let fresnel = fresnel_conductor(cos, eta, k);
+                assert!(fresnel > last && fresnel < 1.0);
+                last = fresnel;
+            }
+            assert!(last > 0.9999);
+            assert!((fresnel_conductor(0.0, eta, k) - 1.0).abs() < 1e-12);
+        }
+    }
+
+    #[test]
+    fn conductor_loses_energy_but_never_gains_it() {
+        // Close enough to a perfect mirror that only masking and shadowing take anything away
+        let mirror = ComplexIor::new(Vec3::new(1.0, 1.0, 1.0), Vec3::new(1e4, 1e4, 1e4));
+        for roughness in [0.1, 0.3, 0.6, 1.0] {
+            let conductor = Conductor::new(mirror, roughness);
+            for wo in directions() {
+                let n = 2000;
+                let mut total = 0.0;
+                for scatter in scatters(&conductor, wo, n).into_iter().flatten() {
+                    assert!(scatter.attenuation.x() <= 1.0);
+                    assert!(scatter.scattered.direction.z() > 0.0);
+                    total += scatter.attenuation.x();
+                }
+                let mean = total / f64::from(n);
+                // What masking and shadowing let through falls as far as 0.3 straight on at the roughest
+                assert!(mean <= 1.0 && mean > 0.25);
+            }
+        }
+    }
+
+    #[test]
+    fn dielectric_weights_never_add_energy() {
+        for roughness in [0.0, 0.3, 0.7] {
+            let glass = RoughDielectric::new(1.5, roughness);
+            // From outside, and from inside at angles that can still get out
+            let inside = directions()
+                .into_iter()
+                .take(2)
+                .map(|w| Vec3::new(w.x(), w.y(), -w.z()));
+            for wo in directions().into_iter().chain(inside) {
+                let n = 1000;
+                let mut total = 0.0;
+                for scatter in scatters(&glass, wo, n).into_iter().flatten() {
+                    assert!(scatter.attenuation.x() <= 1.0);
+                    total += scatter.attenuation.x();
+                }
+                assert!(total / f64::from(n) <= 1.0);
+            }
+        }
+    }
+
+    #[test]
+    fn dielectric_reflects_everything_past_the_critical_angle() {
+        // Inside glass of index 1.5 light can only get out within about 48 degrees of the normal
+        let wo = Vec3::new(0.9, 0.0, -0.3);
+        for roughness in [0.0, 0.05] {
+            let glass = RoughDielectric::new(1.5, roughness);
+            let reflected: Vec<_> = scatters(&glass, wo, 500).into_iter().flatten().collect();
+            assert_eq!(reflected.len(), 500);
+            for scatter in reflected {
+                assert!(scatter.scattered.direction.z() < 0.0);
+                if roughness == 0.0 {
+                    assert_eq!(scatter.attenuation.x(), 1.0);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn absorption_follows_beer_lambert() {
+        let absorption = Vec3::new(0.5, 1.0, 2.0);
+        let glass = RoughDielectric::new_absorbing(1.5, 0.0, absorption);
+        let mut sampler = Sampler::new(crate::sampler::Method::Independent, 1, 1);
+        for distance in [0.0, 0.3, 2.0] {
+            // Only what travelled through the inside is absorbed
+            let (out_of, inside) = hit(&glass, Vec3::new(0.2, 0.0, -1.0), distance);
+            let (into, outside) = hit(&glass, Vec3::new(0.2, 0.0, 1.0), distance);
+            sampler.start_pixel_sample(0, 0, 0);
+            let through = glass.scatter(&out_of, &inside, &mut sampler).unwrap();
+            let expected = absorption.map(|a| (-a * distance).exp());
+            assert!((through.attenuation - expected).length() < 1e-12);
+            sampler.start_pixel_sample(0, 0, 0);
+            let entered = glass.scatter(&into, &outside, &mut sampler).unwrap();
+            assert_eq!(components(&entered.attenuation), [1.0; 3]);
+        }
+    }
+}
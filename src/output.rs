@@ -0,0 +1,695 @@
+use super::draw::{Aovs, Framebuffer};
+use super::geom::*;
+use super::tonemap::{Operator, ToneMap};
+use anyhow::{Context, Result};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum ExrPixel {
+    Half,
+    Float,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum ExrCompression {
+    None,
+    // Lossless zlib over blocks of 16 scanlines
+    Zip,
+}
+
+pub struct Options {
+    // Only for 8 bit formats, high dynamic range ones get the radiance as is
+    pub tonemap: ToneMap,
+    pub exr_pixel: ExrPixel,
+    pub exr_compression: ExrCompression,
+    // Also write how many samples each pixel got as a false color png named like image.heatmap.png
+    pub heatmap: bool,
+}
+
+/**
+ * Picks the format from the extension, tone mapped png unless it is pfm, hdr or exr.
+ * Any aovs go into the same file as extra layers for exr, other formats get a file per aov named like image.depth.png
+ */
+pub fn write(image: &Framebuffer, path: &Path, options: &Options) -> Result<()> {
+    let extension = path
+        .extension()
+        .and_then(|e| e.to_str())
+        .map(|e| e.to_ascii_lowercase());
+    let file =
+        File::create(path).with_context(|| format!("failed to open output path: {:?}", path))?;
+    let mut writer = BufWriter::new(file);
+    match extension.as_deref() {
+        Some("pfm") => write_pfm(image, &mut writer),
+        Some("hdr") => write_hdr(image, &mut writer),
+        Some("exr") => write_exr(image, &mut writer, options),
+        _ => write_png(image, writer, |p| options.tonemap.encode(p)),
+    }
+    .with_context(|| format!("failed to write image: {:?}", path))?;
+
+    let stem = path.file_stem().and_then(|s| s.to_str()).unwrap_or("");
+    if options.heatmap {
+        let heatmap_path = path.with_file_name(format!("{}.heatmap.png", stem));
+        let file = File::create(&heatmap_path)
+            .with_context(|| format!("failed to open output path: {:?}", heatmap_path))?;
+        write_heatmap(image, BufWriter::new(file))
+            .with_context(|| format!("failed to write image: {:?}", heatmap_path))?;
+    }
+
+    let aovs = match (&image.aovs, extension.as_deref()) {
+        (Some(aovs), Some(e)) if e != "exr" => aovs,
+        (Some(aovs), None) => aovs,
+        _ => return Ok(()),
+    };
+    let png = !matches!(extension.as_deref(), Some("pfm") | Some("hdr"));
+    for (name, aov) in aov_images(image, aovs, png) {
+        let aov_path = path.with_file_name(match extension.as_deref() {
+            Some(e) => format!("{}.{}.{}", stem, name, e),
+            None => format!("{}.{}", stem, name),
+        });
+        let file = File::create(&aov_path)
+            .with_context(|| format!("failed to open output path: {:?}", aov_path))?;
+        let mut writer = BufWriter::new(file);
+        match extension.as_deref() {
+            Some("pfm") => write_pfm(&aov, &mut writer),
+            Some("hdr") => write_hdr(&aov, &mut writer),
+            // Albedo is a color so it gets the usual sRGB encoding, the rest are data already in [0, 1]
+            _ if name == "albedo" => {
+                let tonemap = ToneMap::new(0.0, Operator::Clamp);
+                write_png(&aov, writer, |p| tonemap.encode(p))
+            }
+            _ => write_png(&aov, writer, |p| {
+                let encode = |c: f64| (c.clamp(0.0, 1.0) * 255.0).round() as u8;
+                [encode(p.x()), encode(p.y()), encode(p.z())]
+            }),
+        }
+        .with_context(|| format!("failed to write image: {:?}", aov_path))?;
+    }
+    Ok(())
+}
+
+/**
+ * Each aov as an image of its own, with raw values for high dynamic range formats.
+ * For png everything is squeezed into [0, 1]: normals as 0.5n + 0.5, depth, sample counts and variance over their maximum,
+ * positions over their bounding box and ids as arbitrary distinct colors. Rgbe has no sign so hdr loses negative components
+ */
+fn aov_images(
+    image: &Framebuffer,
+    aovs: &Aovs,
+    visualize: bool,
+) -> Vec<(&'static str, Framebuffer)> {
+    let as_image = |pixels: Vec<Vec3>| Framebuffer {
+        width: image.width,
+        height: image.height,
+        pixels,
+        samples: image.samples.clone(),
+        aovs: None,
+    };
+    let grey = |v: f64| Vec3::new(v, v, v);
+    let hit = |i: usize| aovs.depth[i].is_finite();
+    if !visualize {
+        return vec![
+            (
+                "depth",
+                as_image(aovs.depth.iter().map(|d| grey(*d)).collect()),
+            ),
+            ("normal", as_image(aovs.normal.clone())),
+            ("albedo", as_image(aovs.albedo.clone())),
+            ("position", as_image(aovs.position.clone())),
+            (
+                "material",
+                as_image(
+                    aovs.material_id
+                        .iter()
+                        .map(|id| grey(f64::from(*id)))
+                        .collect(),
+                ),
+            ),
+            (
+                "object",
+                as_image(
+                    aovs.object_id
+                        .iter()
+                        .map(|id| grey(f64::from(*id)))
+                        .collect(),
+                ),
+            ),
+            (
+                "samples",
+                as_image(image.samples.iter().map(|n| grey(f64::from(*n))).collect()),
+            ),
+            (
+                "variance",
+                as_image(aovs.variance.iter().map(|v| grey(*v)).collect()),
+            ),
+        ];
+    }
+
+    let max_depth = aovs
+        .depth
+        .iter()
+        .filter(|d| d.is_finite())
+        .fold(0.0f64, |m, d| m.max(*d));
+    let (low, high) = (0..aovs.position.len()).filter(|i| hit(*i)).fold(
+        (
+            Vec3::new(1.0, 1.0, 1.0) * f64::INFINITY,
+            Vec3::new(1.0, 1.0, 1.0) * -f64::INFINITY,
+        ),
+        |(low, high), i| {
+            let p = aovs.position[i];
+            (
+                Vec3::new(low.x().min(p.x()), low.y().min(p.y()), low.z().min(p.z())),
+                Vec3::new(
+                    high.x().max(p.x()),
+                    high.y().max(p.y()),
+                    high.z().max(p.z()),
+                ),
+            )
+        },
+    );
+    let extent = (high - low).map(|c| c.max(1e-9));
+    let max_samples = f64::from(image.samples.iter().copied().max().unwrap_or(0).max(1));
+    let max_variance = aovs.variance.iter().fold(1e-9f64, |m, v| m.max(*v));
+    let over_hits = |f: &dyn Fn(usize) -> Vec3| {
+        as_image(
+            (0..aovs.depth.len())
+                .map(|i| if hit(i) { f(i) } else { Vec3::zero() })
+                .collect(),
+        )
+    };
+    let ids = |ids: &[u32]| as_image(ids.iter().map(|id| id_color(*id)).collect());
+    vec![
+        (
+            "depth",
+            over_hits(&|i| grey(aovs.depth[i] / max_depth.max(1e-9))),
+        ),
+        (
+            "normal",
+            over_hits(&|i| 0.5 * aovs.normal[i] + Vec3::new(0.5, 0.5, 0.5)),
+        ),
+        ("albedo", as_image(aovs.albedo.clone())),
+        (
+            "position",
+            over_hits(&|i| {
+                let p = aovs.position[i] - low;
+                Vec3::new(p.x() / extent.x(), p.y() / extent.y(), p.z() / extent.z())
+            }),
+        ),
+        ("material", ids(&aovs.material_id)),
+        ("object", ids(&aovs.object_id)),
+        (
+            "samples",
+            as_image(
+                image
+                    .samples
+                    .iter()
+                    .map(|n| grey(f64::from(*n) / max_samples))
+                    .collect(),
+            ),
+        ),
+        (
+            "variance",
+            as_image(
+                aovs.variance
+                    .iter()
+                    .map(|v| grey(v / max_variance))
+                    .collect(),
+            ),
+        ),
+    ]
+}
+
+// Sample counts from the fewest in blue through green and yellow to the most in red
+fn write_heatmap<W: Write>(image: &Framebuffer, writer: W) -> Result<()> {
+    let fewest = image.samples.iter().copied().min().unwrap_or(0);
+    let most = image.samples.iter().copied().max().unwrap_or(0);
+    let stops = [
+        Vec3::new(0.05, 0.05, 0.5),
+        Vec3::new(0.0, 0.5, 1.0),
+        Vec3::new(0.1, 0.8, 0.2),
+        Vec3::new(1.0, 0.9, 0.0),
+        Vec3::new(0.9, 0.1, 0.0),
+    ];
+    let heat = Framebuffer {
+        width: image.width,
+        height: image.height,
+        pixels: image
+            .samples
+            .iter()
+            .map(|n| {
+                let t = f64::from(n - fewest) / f64::from((most - fewest).max(1));
+                let x = t * (stops.len() - 1) as f64;
+                let k = (x.floor() as usize).min(stops.len() - 2);
+                let f = x - k as f64;
+                (1.0 - f) * stops[k] + f * stops[k + 1]
+            })
+            .collect(),
+        samples: image.samples.clone(),
+        aovs: None,
+    };
+    let tonemap = ToneMap::new(0.0, Operator::Clamp);
+    write_png(&heat, writer, |p| tonemap.encode(p))
+}
+
+// Neighboring ids get unrelated colors, zero stays black
+fn id_color(id: u32) -> Vec3 {
+    if id == 0 {
+        return Vec3::zero();
+    }
+    let mut h = id.wrapping_mul(0x9e37_79b9);
+    h ^= h >> 15;
+    h = h.wrapping_mul(0x85eb_ca6b);
+    h ^= h >> 13;
+    let channel = |shift: u32| 0.2 + 0.8 * f64::from((h >> shift) & 0xff) / 255.0;
+    Vec3::new(channel(0), channel(8), channel(16))
+}
+
+fn write_png<W: Write>(
+    image: &Framebuffer,
+    writer: W,
+    encode: impl Fn(&Vec3) -> [u8; 3],
+) -> Result<()> {
+    let data: Vec<u8> = image
+        .pixels
+        .iter()
+        .flat_map(|p| {
+            let [r, g, b] = encode(p);
+            vec![r, g, b, 255]
+        })
+        .collect();
+    let mut encoder = png::Encoder::new(writer, image.width, image.height);
+    encoder.set_color(png::ColorType::RGBA);
+    encoder.set_depth(png::BitDepth::Eight);
+    let mut writer = encoder.write_header().context("failed to write header")?;
+    writer
+        .write_image_data(&data)
+        .context("failed to write data")
+}
+
+// Portable float map, little endian rgb floats with the bottom row first
+fn write_pfm<W: Write>(image: &Framebuffer, writer: &mut W) -> Result<()> {
+    write!(writer, "PF\n{} {}\n-1.0\n", image.width, image.height)?;
+    for row in image.pixels.chunks(image.width as usize).rev() {
+        for p in row {
+            for c in [p.x(), p.y(), p.z()].iter() {
+                writer.write_all(&(*c as f32).to_le_bytes())?;
+            }
+        }
+    }
+    Ok(())
+}
+
+// Radiance rgbe with run length encoded scanlines
+fn write_hdr<W: Write>(image: &Framebuffer, writer: &mut W) -> Result<()> {
+    write!(
+        writer,
+        "#?RADIANCE\nFORMAT=32-bit_rle_rgbe\n\n-Y {} +X {}\n",
+        image.height, image.width
+    )?;
+    let width = image.width as usize;
+    for row in image.pixels.chunks(width) {
+        let rgbe: Vec<[u8; 4]> = row.iter().map(to_rgbe).collect();
+        // The run length encoding only describes widths in this range
+        if !(8..=0x7fff).contains(&width) {
+            for p in &rgbe {
+                writer.write_all(p)?;
+            }
+            continue;
+        }
+        writer.write_all(&[2, 2, (width >> 8) as u8, (width & 0xff) as u8])?;
+        for channel in 0..4 {
+            let values: Vec<u8> = rgbe.iter().map(|p| p[channel]).collect();
+            writer.write_all(&rle_rgbe_channel(&values))?;
+        }
+    }
+    Ok(())
+}
+
+// A shared exponent for the largest channel, each channel keeps 8 bits of mantissa
+fn to_rgbe(color: &Vec3) -> [u8; 4] {
+    let (r, g, b) = (color.x().max(0.0), color.y().max(0.0), color.z().max(0.0));
+    let v = r.max(g).max(b);
+    if v < 1e-32 {
+        return [0, 0, 0, 0];
+    }
+    // v = m * 2^e with m in [0.5, 1)
+    let mut e = v.log2().floor() as i32 + 1;
+    if v / 2f64.powi(e) >= 1.0 {
+        e += 1;
+    }
+    if e > 127 {
+        return [255, 255, 255, 255];
+    }
+    let scale = 256.0 / 2f64.powi(e);
+    [
+        (r * scale) as u8,
+        (g * scale) as u8,
+        (b * scale) as u8,
+        (e + 128) as u8,
+    ]
+}
+
+// Runs of at least four equal bytes become a count above 128, everything else goes out as literals
+fn rle_rgbe_channel(values: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(values.len() + values.len() / 128 + 1);
+    let mut literal_start = 0;
+    let mut i = 0;
+    let flush = |out: &mut Vec<u8>, from: usize, to: usize| {
+        for chunk in values[from..to].chunks(128) {
+            out.push(chunk.len() as u8);
+            out.extend_from_slice(chunk);
+        }
+    };
+    while i < values.len() {
+        let mut run = 1;
+        while i + run < values.len() && values[i + run] == values[i] && run < 127 {
+            run += 1;
+        }
+        if run >= 4 {
+            flush(&mut out, literal_start, i);
+            out.push(128 + run as u8);
+            out.push(values[i]);
+            i += run;
+            literal_start = i;
+        } else {
+            i += 1;
+        }
+    }
+    flush(&mut out, literal_start, values.len());
+    out
+}
+
+enum ExrValues {
+    Float(Vec<f32>),
+    // Ids and counts stay exact as unsigned ints whatever the precision of the other channels
+    Uint(Vec<u32>),
+}
+
+// The image's B, G and R then any aovs, in the alphabetical order exr requires
+fn exr_channels(image: &Framebuffer) -> Vec<(String, ExrValues)> {
+    let mut channels = Vec::new();
+    let mut add_vector = |prefix: &str, names: [&str; 3], values: &[Vec3]| {
+        for (name, channel) in names.iter().zip([Vec3::x, Vec3::y, Vec3::z].iter()) {
+            channels.push((
+                format!("{}{}", prefix, name),
+                ExrValues::Float(values.iter().map(|v| channel(v) as f32).collect()),
+            ));
+        }
+    };
+    add_vector("", ["R", "G", "B"], &image.pixels);
+    if let Some(aovs) = &image.aovs {
+        add_vector("albedo.", ["R", "G", "B"], &aovs.albedo);
+        add_vector("normal.", ["X", "Y", "Z"], &aovs.normal);
+        add_vector("position.", ["X", "Y", "Z"], &aovs.position);
+        channels.push((
+            "Z".to_string(),
+            ExrValues::Float(aovs.depth.iter().map(|d| *d as f32).collect()),
+        ));
+        channels.push((
+            "materialId".to_string(),
+            ExrValues::Uint(aovs.material_id.clone()),
+        ));
+        channels.push((
+            "objectId".to_string(),
+            ExrValues::Uint(aovs.object_id.clone()),
+        ));
+        channels.push((
+            "samples".to_string(),
+            ExrValues::Uint(image.samples.clone()),
+        ));
+        channels.push((
+            "variance".to_string(),
+            ExrValues::Float(aovs.variance.iter().map(|v| *v as f32).collect()),
+        ));
+    }
+    channels.sort_by(|a, b| a.0.cmp(&b.0));
+    channels
+}
+
+/**
+ * Single part scanline OpenEXR with B, G and R channels, plus Z and named layers for aovs.
+ * Zip compression is the same as the file format's own, a byte shuffle and delta predictor then zlib
+ */
+fn write_exr<W: Write>(image: &Framebuffer, writer: &mut W, options: &Options) -> Result<()> {
+    let (width, height) = (image.width as usize, image.height as usize);
+    let float_type = match options.exr_pixel {
+        ExrPixel::Half => 1i32,
+        ExrPixel::Float => 2i32,
+    };
+    let exr_channels = exr_channels(image);
+    let (compression, lines_per_block) = match options.exr_compression {
+        ExrCompression::None => (0u8, 1),
+        ExrCompression::Zip => (3u8, 16),
+    };
+
+    let mut header = Vec::new();
+    header.extend_from_slice(&20_000_630i32.to_le_bytes());
+    header.extend_from_slice(&2i32.to_le_bytes());
+    let mut channels = Vec::new();
+    for (name, values) in &exr_channels {
+        let pixel_type = match values {
+            ExrValues::Uint(_) => 0i32,
+            ExrValues::Float(_) => float_type,
+        };
+        channels.extend_from_slice(name.as_bytes());
+        channels.push(0);
+        channels.extend_from_slice(&pixel_type.to_le_bytes());
+        // pLinear and three reserved bytes, then x and y sampling
+        channels.extend_from_slice(&[0, 0, 0, 0]);
+        channels.extend_from_slice(&1i32.to_le_bytes());
+        channels.extend_from_slice(&1i32.to_le_bytes());
+    }
+    channels.push(0);
+    let window: Vec<u8> = [0i32, 0, width as i32 - 1, height as i32 - 1]
+        .iter()
+        .flat_map(|v| v.to_le_bytes().to_vec())
+        .collect();
+    let attribute = |header: &mut Vec<u8>, name: &str, kind: &str, value: &[u8]| {
+        header.extend_from_slice(name.as_bytes());
+        header.push(0);
+        header.extend_from_slice(kind.as_bytes());
+        header.push(0);
+        header.extend_from_slice(&(value.len() as i32).to_le_bytes());
+        header.extend_from_slice(value);
+    };
+    attribute(&mut header, "channels", "chlist", &channels);
+    attribute(&mut header, "compression", "compression", &[compression]);
+    attribute(&mut header, "dataWindow", "box2i", &window);
+    attribute(&mut header, "displayWindow", "box2i", &window);
+    // Increasing y, the top row first
+    attribute(&mut header, "lineOrder", "lineOrder", &[0]);
+    attribute(
+        &mut header,
+        "pixelAspectRatio",
+        "float",
+        &1f32.to_le_bytes(),
+    );
+    attribute(&mut header, "screenWindowCenter", "v2f", &[0; 8]);
+    attribute(
+        &mut header,
+        "screenWindowWidth",
+        "float",
+        &1f32.to_le_bytes(),
+    );
+    header.push(0);
+
+    let mut blocks = Vec::new();
+    for first in (0..height).step_by(lines_per_block) {
+        // Each scanline holds all of its values for the first channel, then the second and so on
+        let mut raw = Vec::new();
+        for y in first..height.min(first + lines_per_block) {
+            let row = y * width..(y + 1) * width;
+            for (_, values) in &exr_channels {
+                match values {
+                    ExrValues::Uint(values) => {
+                        for value in &values[row.clone()] {
+                            raw.extend_from_slice(&value.to_le_bytes());
+                        }
+                    }
+                    ExrValues::Float(values) => {
+                        for value in &values[row.clone()] {
+                            match options.exr_pixel {
+                                ExrPixel::Half => {
+                                    raw.extend_from_slice(&to_half(*value).to_le_bytes())
+                                }
+                                ExrPixel::Float => raw.extend_from_slice(&value.to_le_bytes()),
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        let data = match options.exr_compression {
+            ExrCompression::None => raw,
+            ExrCompression::Zip => {
+                let compressed = deflate::deflate_bytes_zlib(&exr_predict(&raw));
+                // Readers take a block as stored whenever it is not smaller than the raw data
+                if compressed.len() < raw.len() {
+                    compressed
+                } else {
+                    raw
+                }
+            }
+        };
+        blocks.push((first as i32, data));
+    }
+
+    // The offset table gives the absolute position of each block in the file
+    let mut offset = (header.len() + 8 * blocks.len()) as u64;
+    writer.write_all(&header)?;
+    for (_, data) in &blocks {
+        writer.write_all(&offset.to_le_bytes())?;
+        offset += 8 + data.len() as u64;
+    }
+    for (y, data) in &blocks {
+        writer.write_all(&y.to_le_bytes())?;
+        writer.write_all(&(data.len() as i32).to_le_bytes())?;
+        writer.write_all(data)?;
+    }
+    Ok(())
+}
+
+// Interleaves the even and odd bytes into two halves and stores the differences between neighbors
+fn exr_predict(raw: &[u8]) -> Vec<u8> {
+    let mut shuffled: Vec<u8> = raw.iter().step_by(2).copied().collect();
+    shuffled.extend(raw.iter().skip(1).step_by(2));
+    let mut previous = shuffled[0];
+    for byte in shuffled.iter_mut().skip(1) {
+        let current = *byte;
+        *byte = current.wrapping_sub(previous).wrapping_add(128);
+        previous = current;
+    }
+    shuffled
+}
+
+// IEEE half precision, rounding to nearest even
+fn to_half(value: f32) -> u16 {
+    let bits = value.to_bits();
+    let sign = ((bits >> 16) & 0x8000) as u16;
+    let exponent = ((bits >> 23) & 0xff) as i32;
+    let mantissa = bits & 0x7f_ffff;
+    if exponent == 0xff {
+        // Infinity stays infinity and a NaN keeps a mantissa bit set
+        return sign | 0x7c00 | if mantissa != 0 { 0x200 } else { 0 };
+    }
+    let round = |kept: u32, dropped: u32, shift: u32| {
+        let halfway = 1 << (shift - 1);
+        if dropped > halfway || (dropped == halfway && kept & 1 == 1) {
+            kept + 1
+        } else {
+            kept
+        }
+    };
+    let e = exponent - 127 + 15;
+    if e >= 0x1f {
+        return sign | 0x7c00;
+    }
+    if e <= 0 {
+        // Too small for a normal half, becomes subnormal or zero
+        if e < -10 {
+            return sign;
+        }
+        let m = mantissa | 0x80_0000;
+        let shift = (14 - e) as u32;
+        return sign | round(m >> shift, m & ((1 << shift) - 1), shift) as u16;
+    }
+    // Rounding may carry into the exponent, which is still correct up to infinity
+    sign | round(((e as u32) << 10) | (mantissa >> 13), mantissa & 0x1fff, 13) as u16
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn half_rounds_to_nearest_even() {
+        assert_eq!(to_half(0.0), 0x0000);
+        assert_eq!(to_half(-0.0), 0x8000);
+        assert_eq!(to_half(1.0), 0x3c00);
+        assert_eq!(to_half(-2.0), 0xc000);
+        assert_eq!(to_half(0.1), 0x2e66);
+        assert_eq!(to_half(65504.0), 0x7bff);
+        // Halfway between two halves goes to the even one
+        assert_eq!(to_half(1.0 + 2f32.powi(-11)), 0x3c00);
+        assert_eq!(to_half(1.0 + 3.0 * 2f32.powi(-11)), 0x3c02);
+        // Past the largest half rounds up into infinity
+        assert_eq!(to_half(65520.0), 0x7c00);
+        assert_eq!(to_half(1e6), 0x7c00);
+        assert_eq!(to_half(f32::NEG_INFINITY), 0xfc00);
+        let nan = to_half(f32::NAN);
+        assert_eq!(nan & 0x7c00, 0x7c00);
+        assert_ne!(nan & 0x3ff, 0);
+    }
+
+    #[test]
+    fn half_subnormals() {
+        assert_eq!(to_half(2f32.powi(-24)), 0x0001);
+        assert_eq!(to_half(2f32.powi(-14) - 2f32.powi(-24)), 0x03ff);
+        // Exactly half the smallest subnormal rounds to even, which is zero
+        assert_eq!(to_half(2f32.powi(-25)), 0x0000);
+        assert_eq!(to_half(1.5 * 2f32.powi(-24)), 0x0002);
+        assert_eq!(to_half(1e-10), 0x0000);
+    }
+
+    // The reading side of the run length encoding in the Radiance format
+    fn unrle(encoded: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        let mut i = 0;
+        while i < encoded.len() {
+            let count = encoded[i] as usize;
+            if count > 128 {
+                out.extend(std::iter::repeat_n(encoded[i + 1], count - 128));
+                i += 2;
+            } else {
+                out.extend_from_slice(&encoded[i + 1..i + 1 + count]);
+                i += 1 + count;
+            }
+        }
+        out
+    }
+
+    #[test]
+    fn rgbe_runs_round_trip() {
+        assert_eq!(rle_rgbe_channel(&[7; 200]), vec![128 + 127, 7, 128 + 73, 7]);
+        // Runs shorter than four stay literal
+        assert_eq!(rle_rgbe_channel(&[1, 1, 1, 2]), vec![4, 1, 1, 1, 2]);
+        let values: Vec<u8> = (0..1000u32)
+            .map(|i| {
+                if i % 300 < 150 {
+                    9
+                } else {
+                    (i * 37 % 251) as u8
+                }
+            })
+            .collect();
+        let encoded = rle_rgbe_channel(&values);
+        assert!(encoded.len() < values.len());
+        assert_eq!(unrle(&encoded), values);
+        assert_eq!(unrle(&rle_rgbe_channel(&[])), Vec::<u8>::new());
+    }
+
+    #[test]
+    fn rgbe_shares_the_exponent() {
+        assert_eq!(to_rgbe(&Vec3::new(1.0, 1.0, 1.0)), [128, 128, 128, 129]);
+        assert_eq!(to_rgbe(&Vec3::new(0.5, 0.25, 0.0)), [128, 64, 0, 128]);
+        assert_eq!(to_rgbe(&Vec3::zero()), [0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn exr_predictor_inverts() {
+        assert_eq!(exr_predict(&[1, 2, 3, 4]), vec![1, 130, 127, 130]);
+        let raw: Vec<u8> = (0..513u32).map(|i| (i * i % 256) as u8).collect();
+        let predicted = exr_predict(&raw);
+        // What an OpenEXR reader does, add the differences back up and interleave the halves
+        let mut sums = predicted.clone();
+        for k in 1..sums.len() {
+            sums[k] = sums[k - 1].wrapping_add(sums[k]).wrapping_sub(128);
+        }
+        let (even, odd) = sums.split_at(raw.len().div_ceil(2));
+        let restored: Vec<u8> = (0..raw.len())
+            .map(|k| if k % 2 == 0 { even[k / 2] } else { odd[k / 2] })
+            .collect();
+        assert_eq!(restored, raw);
+    }
+}
@@ -0,0 +1,273 @@
+use super::geom::*;
+use super::microfacet::*;
+use super::sampler::Sampler;
+use rand::distributions::Uniform;
+use rand::Rng;
+use std::sync::Arc;
+
+/**
+ * A Disney style uber material covering diffuse, metals, glass and coatings with one set of inputs.
+ * Construct with new and override fields with struct update syntax
+ */
+pub struct Principled {
+    pub base_color: Arc<dyn Texture + Send + Sync>,
+    pub metallic: Parameter,
+    pub roughness: Parameter,
+    // Scales the reflectance at normal incidence of the dielectric base, 0.5 gives the usual 4%
+    pub specular: Parameter,
+    pub sheen: Parameter,
+    pub sheen_tint: Parameter,
+    pub clearcoat: Parameter,
+    pub clearcoat_roughness: Parameter,
+    pub transmission: Parameter,
+    pub ior: f64,
+}
+
+impl Principled {
+    pub fn new(base_color: Arc<dyn Texture + Send + Sync>) -> Principled {
+        Principled {
+            base_color,
+            metallic: Parameter::Constant(0.0),
+            roughness: Parameter::Constant(0.5),
+            specular: Parameter::Constant(0.5),
+            sheen: Parameter::Constant(0.0),
+            sheen_tint: Parameter::Constant(0.5),
+            clearcoat: Parameter::Constant(0.0),
+            clearcoat_roughness: Parameter::Constant(0.1),
+            transmission: Parameter::Constant(0.0),
+            ior: 1.5,
+        }
+    }
+}
+
+fn schlick_f0(f0: Vec3, cosine: f64) -> Vec3 {
+    let m = (1.0 - cosine.clamp(0.0, 1.0)).powi(5);
+    f0 + m * (Vec3::new(1.0, 1.0, 1.0) - f0)
+}
+
+fn lerp(a: Vec3, b: Vec3, t: f64) -> Vec3 {
+    (1.0 - t) * a + t * b
+}
+
+// Sampled lobe in the local frame along with f * cos / pdf for that lobe alone
+struct LobeSample {
+    wi: Vec3,
+    weight: Vec3,
+}
+
+fn sample_glossy(
+    distribution: &TrowbridgeReitz,
+    f0: Vec3,
+    wo: &Vec3,
+    u1: f64,
+    u2: f64,
+) -> Option<LobeSample> {
+    let wm = distribution.sample_visible(wo, u1, u2);
+    let wi = reflect_about(wo, &wm);
+    if wi.z() <= 0.0 {
+        return None;
+    }
+    Some(LobeSample {
+        wi,
+        weight: distribution.g(wo, &wi) / distribution.g1(wo) * schlick_f0(f0, wo.dot(&wm)),
+    })
+}
+
+// Refraction is tinted by tint, reflection and total internal reflection are not
+fn sample_transmission(
+    distribution: &TrowbridgeReitz,
+    etai_over_etat: f64,
+    tint: Vec3,
+    wo: &Vec3,
+    rng: &mut Sampler,
+) -> Option<LobeSample> {
+    let unit = Uniform::new(0.0, 1.0);
+    let wm = distribution.sample_visible(wo, rng.sample(unit), rng.sample(unit));
+    let reflected = rng.sample(unit) < fresnel_dielectric(wo.dot(&wm), etai_over_etat);
+    let wi = if reflected {
+        reflect_about(wo, &wm)
+    } else {
+        refact(&wo.flip(), &wm, etai_over_etat)
+    };
+    if (wi.z() > 0.0) != reflected {
+        return None;
+    }
+    let tint = if reflected {
+        Vec3::new(1.0, 1.0, 1.0)
+    } else {
+        tint
+    };
+    Some(LobeSample {
+        wi,
+        weight: distribution.g(wo, &wi) / distribution.g1(wo) * tint,
+    })
+}
+
+impl Material for Principled {
+    fn scatter(&self, ray: &Ray, hit: &Hit, rng: &mut Sampler) -> Option<Scatter> {
+        let unit = Uniform::new(0.0, 1.0);
+        let frame = Onb::from_normal(&hit.normal);
+        let wo = frame.to_local(&ray.direction.unit().flip());
+        if wo.z() <= 0.0 {
+            return None;
+        }
+
+        let base =
+            self.base_color
+                .color_filtered(hit.u, hit.v, &hit.point, &hit.normal, hit.footprint());
+        let metallic = self.metallic.eval(hit);
+        let roughness = self.roughness.eval(hit);
+        let transmission = self.transmission.eval(hit);
+        let distribution = TrowbridgeReitz::from_roughness(roughness, roughness);
+
+        // From the inside only the glass part of the material is reachable
+        if !hit.front_face {
+            let lobe =
+                sample_transmission(&distribution, self.ior, Vec3::new(1.0, 1.0, 1.0), &wo, rng)?;
+            return Some(Scatter {
+                scattered: Ray::new_at(hit.point, frame.to_world(&lobe.wi), ray.time),
+                attenuation: lobe.weight,
+            });
+        }
+
+        let dielectric_f0 = 0.08 * self.specular.eval(hit);
+        let sheen = self.sheen.eval(hit);
+        let clearcoat = self.clearcoat.eval(hit);
+
+        let diffuse_weight = (1.0 - metallic) * (1.0 - transmission);
+        let transmission_weight = (1.0 - metallic) * transmission;
+        let glossy_weight = 1.0 - transmission_weight;
+        let clearcoat_weight = 0.25 * clearcoat;
+
+        // Pick a lobe in proportion to a rough estimate of how much light it reflects
+        let dielectric_f0 = Vec3::new(dielectric_f0, dielectric_f0, dielectric_f0);
+        let f0 = lerp(dielectric_f0, base, metallic);
+        let diffuse_albedo = 1.0 - schlick_f0(dielectric_f0, wo.z()).x();
+        let probs = [
+            diffuse_weight * (base.luminance() * diffuse_albedo + sheen).max(1e-3),
+            glossy_weight * schlick_f0(f0, wo.z()).luminance(),
+            transmission_weight,
+            clearcoat_weight * schlick_f0(Vec3::new(0.04, 0.04, 0.04), wo.z()).x(),
+        ];
+        let total: f64 = probs.iter().sum();
+        if total <= 0.0 {
+            return None;
+        }
+        let mut pick = rng.sample(unit) * total;
+        let mut lobe_index = 0;
+        while lobe_index < probs.len() - 1 && pick >= probs[lobe_index] {
+            pick -= probs[lobe_index];
+            lobe_index += 1;
+        }
+        let selection = probs[lobe_index] / total;
+
+        let lobe = match lobe_index {
+            0 => {
+                let wi = cosine_hemisphere(rng.sample(unit), rng.sample(unit));
+                let cos_d = wi.dot(&(wi + wo).unit());
+                let tint = if base.luminance() > 0.0 {
+                    base / base.luminance()
+                } else {
+                    Vec3::new(1.0, 1.0, 1.0)
+                };
+                let sheen_color = sheen
+                    * lerp(Vec3::new(1.0, 1.0, 1.0), tint, self.sheen_tint.eval(hit))
+                    * (1.0 - cos_d).powi(5);
+                LobeSample {
+                    wi,
+                    weight: diffuse_weight * (diffuse_albedo * base + sheen_color),
+                }
+            }
+            1 => {
+                let lobe =
+                    sample_glossy(&distribution, f0, &wo, rng.sample(unit), rng.sample(unit))?;
+                LobeSample {
+                    wi: lobe.wi,
+                    weight: glossy_weight * lobe.weight,
+                }
+            }
+            2 => {
+                let lobe = sample_transmission(&distribution, 1.0 / self.ior, base, &wo, rng)?;
+                LobeSample {
+                    wi: lobe.wi,
+                    weight: transmission_weight * lobe.weight,
+                }
+            }
+            _ => {
+                let roughness = self.clearcoat_roughness.eval(hit);
+                let coat = TrowbridgeReitz::from_roughness(roughness, roughness);
+                let lobe = sample_glossy(
+                    &coat,
+                    Vec3::new(0.04, 0.04, 0.04),
+                    &wo,
+                    rng.sample(unit),
+                    rng.sample(unit),
+                )?;
+                LobeSample {
+                    wi: lobe.wi,
+                    weight: clearcoat_weight * lobe.weight,
+                }
+            }
+        };
+
+        Some(Scatter {
+            scattered: Ray::new_at(hit.point, frame.to_world(&lobe.wi), ray.time),
+            attenuation: lobe.weight / selection,
+        })
+    }
+
+    fn albedo(&self, hit: &Hit) -> Vec3 {
+        self.base_color
+            .color_filtered(hit.u, hit.v, &hit.point, &hit.normal, hit.footprint())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::geom::tests::*;
+
+    fn white(metallic: f64, roughness: f64) -> Principled {
+        Principled {
+            metallic: Parameter::Constant(metallic),
+            roughness: Parameter::Constant(roughness),
+            ..Principled::new(Arc::new(SolidColor::new(1.0, 1.0, 1.0)))
+        }
+    }
+
+    #[test]
+    fn white_furnace_stays_below_one() {
+        for metallic in [0.0, 0.5, 1.0] {
+            for roughness in [0.2, 0.5, 1.0] {
+                let material = white(metallic, roughness);
+                for wo in directions() {
+                    let n = 4000;
+                    let total: f64 = (scatters(&material, wo, n).into_iter().flatten())
+                        .map(|scatter| scatter.attenuation.x())
+                        .sum();
+                    let mean = total / f64::from(n);
+                    // The diffuse lobe leaves out fresnel at the macro normal, and the glossy one takes it at the
+                    // micro normal, so the two can overlap by a fraction of a percent
+                    assert!(mean < 1.01, "{} {} {}", metallic, roughness, mean);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn metals_have_no_diffuse_lobe() {
+        let wo = Vec3::new(0.6, 0.0, 0.8);
+        let mirrored = Vec3::new(-0.6, 0.0, 0.8);
+        let near_mirror = |material: &Principled| {
+            (scatters(material, wo, 1000).into_iter().flatten())
+                .all(|scatter| scatter.scattered.direction.unit().dot(&mirrored) > 0.9)
+        };
+        // Smooth enough that the microfacet lobe's tails don't reach as far
+        assert!(near_mirror(&white(1.0, 0.02)));
+        assert!(!near_mirror(&white(0.9, 0.02)));
+        // A white metal is a perfect reflector, only masking and shadowing take anything away
+        for scatter in scatters(&white(1.0, 0.2), wo, 1000).into_iter().flatten() {
+            assert!(scatter.attenuation.x() <= 1.0);
+        }
+    }
+}
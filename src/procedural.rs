@@ -0,0 +1,414 @@
+use super::geom::*;
+use std::sync::Arc;
+
+// Integer lattice hash to a value in [0, 1), the salt picks independent streams for the same cell
+fn hash_cell(i: i64, j: i64, k: i64, salt: u64) -> f64 {
+    bits_to_unit(hash_bits(&[salt, i as u64, j as u64, k as u64]))
+}
+
+fn smoothstep(t: f64) -> f64 {
+    t * t * (3.0 - 2.0 * t)
+}
+
+fn lerp(a: Vec3, b: Vec3, t: f64) -> Vec3 {
+    (1.0 - t) * a + t * b
+}
+
+// Smoothly interpolated random values on the integer lattice, in [0, 1)
+fn value_noise(p: &Vec3) -> f64 {
+    let (fx, fy, fz) = (p.x().floor(), p.y().floor(), p.z().floor());
+    let (tx, ty, tz) = (
+        smoothstep(p.x() - fx),
+        smoothstep(p.y() - fy),
+        smoothstep(p.z() - fz),
+    );
+    let (i, j, k) = (fx as i64, fy as i64, fz as i64);
+    let corner = |di, dj, dk| hash_cell(i + di, j + dj, k + dk, 0);
+    let x00 = corner(0, 0, 0) * (1.0 - tx) + corner(1, 0, 0) * tx;
+    let x10 = corner(0, 1, 0) * (1.0 - tx) + corner(1, 1, 0) * tx;
+    let x01 = corner(0, 0, 1) * (1.0 - tx) + corner(1, 0, 1) * tx;
+    let x11 = corner(0, 1, 1) * (1.0 - tx) + corner(1, 1, 1) * tx;
+    let y0 = x00 * (1.0 - ty) + x10 * ty;
+    let y1 = x01 * (1.0 - ty) + x11 * ty;
+    y0 * (1.0 - tz) + y1 * tz
+}
+
+#[derive(Clone, Copy)]
+pub enum WorleyOutput {
+    // Distance to the nearest feature point
+    Distance,
+    // Difference between the two nearest, zero along cell borders
+    Edges,
+    // A random color per cell
+    Cells,
+}
+
+// Worley cellular noise with one jittered feature point per unit cell of the scaled point
+pub struct WorleyTexture {
+    scale: f64,
+    output: WorleyOutput,
+}
+
+impl WorleyTexture {
+    pub fn new(scale: f64, output: WorleyOutput) -> WorleyTexture {
+        WorleyTexture { scale, output }
+    }
+}
+
+impl Texture for WorleyTexture {
+    fn color(&self, _u: f64, _v: f64, point: &Vec3, _normal: &Vec3) -> Vec3 {
+        let p = self.scale * *point;
+        let (i, j, k) = (
+            p.x().floor() as i64,
+            p.y().floor() as i64,
+            p.z().floor() as i64,
+        );
+        let mut nearest = (f64::INFINITY, (0, 0, 0));
+        let mut second = f64::INFINITY;
+        for di in -1..=1 {
+            for dj in -1..=1 {
+                for dk in -1..=1 {
+                    let cell = (i + di, j + dj, k + dk);
+                    let feature = Vec3::new(
+                        cell.0 as f64 + hash_cell(cell.0, cell.1, cell.2, 1),
+                        cell.1 as f64 + hash_cell(cell.0, cell.1, cell.2, 2),
+                        cell.2 as f64 + hash_cell(cell.0, cell.1, cell.2, 3),
+                    );
+                    let d = (feature - p).length();
+                    if d < nearest.0 {
+                        second = nearest.0;
+                        nearest = (d, cell);
+                    } else if d < second {
+                        second = d;
+                    }
+                }
+            }
+        }
+        let grey = |g: f64| Vec3::new(g, g, g);
+        match self.output {
+            WorleyOutput::Distance => grey(nearest.0.min(1.0)),
+            WorleyOutput::Edges => grey((second - nearest.0).min(1.0)),
+            WorleyOutput::Cells => {
+                let (a, b, c) = nearest.1;
+                Vec3::new(
+                    hash_cell(a, b, c, 4),
+                    hash_cell(a, b, c, 5),
+                    hash_cell(a, b, c, 6),
+                )
+            }
+        }
+    }
+}
+
+// Concentric growth rings around the y axis, wobbled by noise so they don't look machined
+pub struct WoodTexture {
+    light: Arc<dyn Texture + Send + Sync>,
+    dark: Arc<dyn Texture + Send + Sync>,
+    rings_per_unit: f64,
+    distortion: f64,
+}
+
+impl WoodTexture {
+    pub fn new(
+        light: Arc<dyn Texture + Send + Sync>,
+        dark: Arc<dyn Texture + Send + Sync>,
+        rings_per_unit: f64,
+        distortion: f64,
+    ) -> WoodTexture {
+        WoodTexture {
+            light,
+            dark,
+            rings_per_unit,
+            distortion,
+        }
+    }
+}
+
+impl Texture for WoodTexture {
+    fn color(&self, u: f64, v: f64, point: &Vec3, normal: &Vec3) -> Vec3 {
+        let radius = (point.x().powi(2) + point.z().powi(2)).sqrt();
+        let wobble = self.distortion * value_noise(&(Vec3::new(2.0, 0.5, 2.0) * *point));
+        let ring = (radius + wobble) * self.rings_per_unit;
+        // Sharp edge where a ring starts and a slow fade across it
+        let t = (ring - ring.floor()).powi(3);
+        lerp(
+            self.light.color(u, v, point, normal),
+            self.dark.color(u, v, point, normal),
+            t,
+        )
+    }
+}
+
+// Running bond bricks in texture space with mortar between them
+pub struct BrickTexture {
+    brick: Arc<dyn Texture + Send + Sync>,
+    mortar: Arc<dyn Texture + Send + Sync>,
+    // Size of a brick and the mortar around it in units of u and v
+    width: f64,
+    height: f64,
+    mortar_width: f64,
+}
+
+impl BrickTexture {
+    pub fn new(
+        brick: Arc<dyn Texture + Send + Sync>,
+        mortar: Arc<dyn Texture + Send + Sync>,
+        width: f64,
+        height: f64,
+        mortar_width: f64,
+    ) -> BrickTexture {
+        BrickTexture {
+            brick,
+            mortar,
+            width,
+            height,
+            mortar_width,
+        }
+    }
+}
+
+impl Texture for BrickTexture {
+    fn color(&self, u: f64, v: f64, point: &Vec3, normal: &Vec3) -> Vec3 {
+        let row = (v / self.height).floor();
+        // Every other row is offset by half a brick
+        let shifted = u / self.width + 0.5 * row.rem_euclid(2.0);
+        let along = (shifted - shifted.floor()) * self.width;
+        let across = v - row * self.height;
+        let half = self.mortar_width / 2.0;
+        if along < half || along > self.width - half || across < half || across > self.height - half
+        {
+            self.mortar.color(u, v, point, normal)
+        } else {
+            self.brick.color(u, v, point, normal)
+        }
+    }
+}
+
+// A linear ramp from one texture to another along axis, between start and end along it
+pub struct GradientTexture {
+    from: Arc<dyn Texture + Send + Sync>,
+    to: Arc<dyn Texture + Send + Sync>,
+    axis: Vec3,
+    start: f64,
+    end: f64,
+}
+
+impl GradientTexture {
+    pub fn new(
+        from: Arc<dyn Texture + Send + Sync>,
+        to: Arc<dyn Texture + Send + Sync>,
+        axis: Vec3,
+        start: f64,
+        end: f64,
+    ) -> GradientTexture {
+        GradientTexture {
+            from,
+            to,
+            axis: axis.unit(),
+            start,
+            end,
+        }
+    }
+}
+
+impl Texture for GradientTexture {
+    fn color(&self, u: f64, v: f64, point: &Vec3, normal: &Vec3) -> Vec3 {
+        let t = ((point.dot(&self.axis) - self.start) / (self.end - self.start)).clamp(0.0, 1.0);
+        lerp(
+            self.from.color(u, v, point, normal),
+            self.to.color(u, v, point, normal),
+            t,
+        )
+    }
+}
+
+// b where the first channel of mask is one, a where it is zero
+pub struct MixTexture {
+    a: Arc<dyn Texture + Send + Sync>,
+    b: Arc<dyn Texture + Send + Sync>,
+    mask: Arc<dyn Texture + Send + Sync>,
+}
+
+impl MixTexture {
+    pub fn new(
+        a: Arc<dyn Texture + Send + Sync>,
+        b: Arc<dyn Texture + Send + Sync>,
+        mask: Arc<dyn Texture + Send + Sync>,
+    ) -> MixTexture {
+        MixTexture { a, b, mask }
+    }
+}
+
+impl Texture for MixTexture {
+    fn color(&self, u: f64, v: f64, point: &Vec3, normal: &Vec3) -> Vec3 {
+        let t = self.mask.color(u, v, point, normal).x().clamp(0.0, 1.0);
+        lerp(
+            self.a.color(u, v, point, normal),
+            self.b.color(u, v, point, normal),
+            t,
+        )
+    }
+}
+
+pub struct MultiplyTexture {
+    a: Arc<dyn Texture + Send + Sync>,
+    b: Arc<dyn Texture + Send + Sync>,
+}
+
+impl MultiplyTexture {
+    pub fn new(
+        a: Arc<dyn Texture + Send + Sync>,
+        b: Arc<dyn Texture + Send + Sync>,
+    ) -> MultiplyTexture {
+        MultiplyTexture { a, b }
+    }
+}
+
+impl Texture for MultiplyTexture {
+    fn color(&self, u: f64, v: f64, point: &Vec3, normal: &Vec3) -> Vec3 {
+        self.a.color(u, v, point, normal) * self.b.color(u, v, point, normal)
+    }
+}
+
+pub struct AddTexture {
+    a: Arc<dyn Texture + Send + Sync>,
+    b: Arc<dyn Texture + Send + Sync>,
+}
+
+impl AddTexture {
+    pub fn new(a: Arc<dyn Texture + Send + Sync>, b: Arc<dyn Texture + Send + Sync>) -> AddTexture {
+        AddTexture { a, b }
+    }
+}
+
+impl Texture for AddTexture {
+    fn color(&self, u: f64, v: f64, point: &Vec3, normal: &Vec3) -> Vec3 {
+        self.a.color(u, v, point, normal) + self.b.color(u, v, point, normal)
+    }
+}
+
+// One minus each channel
+pub struct InvertTexture {
+    inner: Arc<dyn Texture + Send + Sync>,
+}
+
+impl InvertTexture {
+    pub fn new(inner: Arc<dyn Texture + Send + Sync>) -> InvertTexture {
+        InvertTexture { inner }
+    }
+}
+
+impl Texture for InvertTexture {
+    fn color(&self, u: f64, v: f64, point: &Vec3, normal: &Vec3) -> Vec3 {
+        Vec3::new(1.0, 1.0, 1.0) - self.inner.color(u, v, point, normal)
+    }
+}
+
+// Maps the first channel of inner through linearly interpolated color stops
+pub struct ColorRampTexture {
+    inner: Arc<dyn Texture + Send + Sync>,
+    stops: Vec<(f64, Vec3)>,
+}
+
+impl ColorRampTexture {
+    // Stops are (position, color) and get sorted by position, taking the first on its own means there is always one
+    pub fn new(
+        inner: Arc<dyn Texture + Send + Sync>,
+        first: (f64, Vec3),
+        rest: Vec<(f64, Vec3)>,
+    ) -> ColorRampTexture {
+        let mut stops = rest;
+        stops.insert(0, first);
+        stops.sort_by(|l, r| l.0.partial_cmp(&r.0).unwrap_or(std::cmp::Ordering::Equal));
+        ColorRampTexture { inner, stops }
+    }
+}
+
+impl Texture for ColorRampTexture {
+    fn color(&self, u: f64, v: f64, point: &Vec3, normal: &Vec3) -> Vec3 {
+        let t = self.inner.color(u, v, point, normal).x();
+        let upper = self.stops.iter().position(|s| s.0 > t);
+        match upper {
+            Some(0) => self.stops[0].1,
+            None => self.stops[self.stops.len() - 1].1,
+            Some(i) => {
+                let (p0, c0) = self.stops[i - 1];
+                let (p1, c1) = self.stops[i];
+                lerp(c0, c1, (t - p0) / (p1 - p0))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::geom::tests::components;
+
+    fn grey(value: f64) -> Arc<dyn Texture + Send + Sync> {
+        Arc::new(SolidColor::new(value, value, value))
+    }
+
+    fn at(texture: &dyn Texture, u: f64, v: f64, point: &Vec3) -> [f64; 3] {
+        components(&texture.color(u, v, point, &Vec3::new(0.0, 0.0, 1.0)))
+    }
+
+    #[test]
+    fn color_ramp_interpolates_between_stops_and_clamps_outside() {
+        let (red, blue) = (Vec3::new(1.0, 0.0, 0.0), Vec3::new(0.0, 0.0, 1.0));
+        let ramp = |t: f64| {
+            // Given out of order
+            let ramp = ColorRampTexture::new(grey(t), (0.75, blue), vec![(0.25, red)]);
+            at(&ramp, 0.0, 0.0, &Vec3::zero())
+        };
+        assert_eq!(ramp(0.5), [0.5, 0.0, 0.5]);
+        assert_eq!(ramp(0.375), [0.75, 0.0, 0.25]);
+        assert_eq!(ramp(0.25), [1.0, 0.0, 0.0]);
+        assert_eq!(ramp(-3.0), [1.0, 0.0, 0.0]);
+        assert_eq!(ramp(0.75), [0.0, 0.0, 1.0]);
+        assert_eq!(ramp(7.0), [0.0, 0.0, 1.0]);
+        for t in [-1.0, 0.5, 2.0] {
+            let single = ColorRampTexture::new(grey(t), (0.5, red), vec![]);
+            assert_eq!(at(&single, 0.0, 0.0, &Vec3::zero()), [1.0, 0.0, 0.0]);
+        }
+    }
+
+    #[test]
+    fn bricks_have_mortar_around_them() {
+        let bricks = BrickTexture::new(grey(1.0), grey(0.0), 1.0, 0.5, 0.1);
+        let brick = |u: f64, v: f64| at(&bricks, u, v, &Vec3::zero())[0] == 1.0;
+        assert!(brick(0.5, 0.25));
+        assert!(brick(0.9, 0.06));
+        // Between bricks in a row, and between rows
+        assert!(!brick(0.02, 0.25) && !brick(0.98, 0.25));
+        assert!(!brick(0.5, 0.01) && !brick(0.5, 0.49));
+        // The next row is offset by half a brick, and it all repeats
+        assert!(!brick(0.5, 0.75) && brick(0.02, 0.75));
+        assert!(brick(3.5, 2.25) && brick(-0.5, -0.75));
+    }
+
+    #[test]
+    fn worley_depends_only_on_the_point() {
+        for output in [
+            WorleyOutput::Distance,
+            WorleyOutput::Edges,
+            WorleyOutput::Cells,
+        ] {
+            let mut state = 11;
+            let mut coordinate = || {
+                state = mix_bits(state);
+                20.0 * bits_to_unit(state) - 10.0
+            };
+            for _ in 0..200 {
+                let point = Vec3::new(coordinate(), coordinate(), coordinate());
+                let first = at(&WorleyTexture::new(1.5, output), 0.3, 0.6, &point);
+                // Whatever the texture coordinates and whichever texture
+                assert_eq!(
+                    at(&WorleyTexture::new(1.5, output), 0.9, 0.1, &point),
+                    first
+                );
+                assert!(first.iter().all(|c| c.is_finite() && *c >= 0.0));
+            }
+        }
+    }
+}
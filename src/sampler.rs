@@ -0,0 +1,364 @@
+use super::geom::*;
+use rand::{Error, RngCore};
+use std::f64::consts::PI;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Method {
+    // Plain random numbers
+    Independent,
+    // One jittered sample in each of the pixel's strata, shuffled separately for every dimension
+    Stratified,
+    // Owen scrambled radical inverses in successive prime bases, random past the bases it has
+    Halton,
+    // The first two Sobol dimensions with shuffled indices and Owen scrambling for each dimension
+    Sobol,
+}
+
+// The largest value below one, sequences can round up to one otherwise
+const ONE_MINUS_EPSILON: f64 = 1.0 - f64::EPSILON / 2.0;
+
+// Bases for the Halton dimensions, higher ones would distribute too poorly to be worth it
+const PRIMES: [u64; 64] = [
+    2, 3, 5, 7, 11, 13, 17, 19, 23, 29, 31, 37, 41, 43, 47, 53, 59, 61, 67, 71, 73, 79, 83, 89, 97,
+    101, 103, 107, 109, 113, 127, 131, 137, 139, 149, 151, 157, 163, 167, 173, 179, 181, 191, 193,
+    197, 199, 211, 223, 227, 229, 233, 239, 241, 251, 257, 263, 269, 271, 277, 281, 283, 293, 307,
+    311,
+];
+
+/**
+ * Hands out the numbers for one sample of a pixel a dimension at a time: pixel jitter, lens, time,
+ * wavelength, then whatever each bounce asks for.
+ * Everything is a pure function of the seed, the pixel, the sample index and the dimension, so a render can be
+ * repeated exactly whichever thread draws which pixel.
+ * As an RngCore every draw is one dimension, which is how materials sample through rand's distributions
+ */
+pub struct Sampler {
+    method: Method,
+    samples_per_pixel: u32,
+    seed: u64,
+    pixel: (u32, u32),
+    index: u32,
+    dimension: u32,
+    // Jitter and any dimensions the sequences don't cover
+    random: u64,
+}
+
+impl Sampler {
+    pub fn new(method: Method, samples_per_pixel: u32, seed: u64) -> Sampler {
+        Sampler {
+            method,
+            samples_per_pixel,
+            seed,
+            pixel: (0, 0),
+            index: 0,
+            dimension: 0,
+            random: 0,
+        }
+    }
+
+    pub fn start_pixel_sample(&mut self, x: u32, y: u32, index: u32) {
+        self.pixel = (x, y);
+        self.index = index;
+        self.dimension = 0;
+        self.random = hash_bits(&[self.seed, u64::from(x), u64::from(y), u64::from(index)]);
+    }
+
+    pub fn get_1d(&mut self) -> f64 {
+        let dimension = self.dimension;
+        self.dimension += 1;
+        let hash = self.dimension_hash(dimension);
+        match self.method {
+            Method::Independent => self.random(),
+            Method::Stratified => {
+                let n = self.samples_per_pixel;
+                let stratum = permutation_element(self.index % n, n, hash as u32);
+                ((f64::from(stratum) + self.random()) / f64::from(n)).min(ONE_MINUS_EPSILON)
+            }
+            Method::Halton => self.halton(dimension, hash),
+            Method::Sobol => {
+                let index = permutation_element(self.index, self.samples_per_pixel, hash as u32);
+                owen_scramble(index.reverse_bits(), mix_bits(hash) as u32)
+            }
+        }
+    }
+
+    pub fn get_2d(&mut self) -> (f64, f64) {
+        let dimension = self.dimension;
+        self.dimension += 2;
+        let hash = self.dimension_hash(dimension);
+        match self.method {
+            Method::Independent => (self.random(), self.random()),
+            Method::Stratified => {
+                // As square as it gets, when the strata outnumber the samples a random subset of them is used
+                let n = self.samples_per_pixel;
+                let nx = f64::from(n).sqrt().ceil() as u32;
+                let ny = n.div_ceil(nx);
+                let stratum = permutation_element(self.index % (nx * ny), nx * ny, hash as u32);
+                let x = (f64::from(stratum % nx) + self.random()) / f64::from(nx);
+                let y = (f64::from(stratum / nx) + self.random()) / f64::from(ny);
+                (x.min(ONE_MINUS_EPSILON), y.min(ONE_MINUS_EPSILON))
+            }
+            Method::Halton => (
+                self.halton(dimension, hash),
+                self.halton(dimension + 1, self.dimension_hash(dimension + 1)),
+            ),
+            Method::Sobol => {
+                let index = permutation_element(self.index, self.samples_per_pixel, hash as u32);
+                (
+                    owen_scramble(index.reverse_bits(), mix_bits(hash) as u32),
+                    owen_scramble(sobol_second(index), mix_bits(hash ^ 0x5be0_cd19) as u32),
+                )
+            }
+        }
+    }
+
+    // A point in the unit disc by Shirley and Chiu's concentric mapping, which keeps strata compact
+    pub fn disc(&mut self) -> (f64, f64) {
+        let (u1, u2) = self.get_2d();
+        let (x, y) = (2.0 * u1 - 1.0, 2.0 * u2 - 1.0);
+        if x == 0.0 && y == 0.0 {
+            return (0.0, 0.0);
+        }
+        let (r, theta) = if x.abs() > y.abs() {
+            (x, PI / 4.0 * (y / x))
+        } else {
+            (y, PI / 2.0 - PI / 4.0 * (x / y))
+        };
+        (r * theta.cos(), r * theta.sin())
+    }
+
+    // A point in the unit ball, a direction from two dimensions and a radius from the third
+    pub fn ball(&mut self) -> Vec3 {
+        let (u1, u2) = self.get_2d();
+        let r = self.get_1d().cbrt();
+        let z = 1.0 - 2.0 * u1;
+        let ring = (1.0 - z * z).max(0.0).sqrt();
+        let phi = 2.0 * PI * u2;
+        r * Vec3::new(ring * phi.cos(), ring * phi.sin(), z)
+    }
+
+    fn dimension_hash(&self, dimension: u32) -> u64 {
+        hash_bits(&[
+            self.seed,
+            u64::from(self.pixel.0),
+            u64::from(self.pixel.1),
+            u64::from(dimension),
+        ])
+    }
+
+    // A SplitMix style generator on top of mix_bits
+    fn random(&mut self) -> f64 {
+        self.random = self.random.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        bits_to_unit(mix_bits(self.random))
+    }
+
+    fn halton(&mut self, dimension: u32, hash: u64) -> f64 {
+        match PRIMES.get(dimension as usize) {
+            Some(base) => owen_scrambled_radical_inverse(*base, u64::from(self.index), hash),
+            None => self.random(),
+        }
+    }
+}
+
+impl RngCore for Sampler {
+    fn next_u32(&mut self) -> u32 {
+        (self.get_1d() * 2f64.powi(32)) as u32
+    }
+
+    // rand builds floats from the high bits of this, so a uniform float takes exactly one dimension
+    fn next_u64(&mut self) -> u64 {
+        (self.get_1d() * 2f64.powi(64)) as u64
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        for chunk in dest.chunks_mut(8) {
+            let bytes = self.next_u64().to_le_bytes();
+            chunk.copy_from_slice(&bytes[..chunk.len()]);
+        }
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), Error> {
+        self.fill_bytes(dest);
+        Ok(())
+    }
+}
+
+// Element i of a pseudo random permutation of 0..l picked by p, from Kensler's Correlated Multi-Jittered Sampling
+fn permutation_element(mut i: u32, l: u32, p: u32) -> u32 {
+    let mut w = l - 1;
+    w |= w >> 1;
+    w |= w >> 2;
+    w |= w >> 4;
+    w |= w >> 8;
+    w |= w >> 16;
+    // Cycle walking, anything landing outside 0..l goes round again
+    loop {
+        i ^= p;
+        i = i.wrapping_mul(0xe170_893d);
+        i ^= p >> 16;
+        i ^= (i & w) >> 4;
+        i ^= p >> 8;
+        i = i.wrapping_mul(0x0929_eb3f);
+        i ^= p >> 23;
+        i ^= (i & w) >> 1;
+        i = i.wrapping_mul(1 | p >> 27);
+        i = i.wrapping_mul(0x6935_fa69);
+        i ^= (i & w) >> 11;
+        i = i.wrapping_mul(0x74dc_b303);
+        i ^= (i & w) >> 2;
+        i = i.wrapping_mul(0x9e50_1cc3);
+        i ^= (i & w) >> 2;
+        i = i.wrapping_mul(0xc860_a3df);
+        i &= w;
+        i ^= i >> 5;
+        if i < l {
+            break;
+        }
+    }
+    (i.wrapping_add(p)) % l
+}
+
+// The second Sobol dimension, its generator matrix comes from the polynomial x + 1
+fn sobol_second(mut index: u32) -> u32 {
+    let mut v = 1u32 << 31;
+    let mut result = 0;
+    while index != 0 {
+        if index & 1 != 0 {
+            result ^= v;
+        }
+        index >>= 1;
+        v ^= v >> 1;
+    }
+    result
+}
+
+// Burley's hash based Owen scrambling, each bit flips depending on the ones above it
+fn owen_scramble(bits: u32, seed: u32) -> f64 {
+    let mut v = bits.reverse_bits();
+    v ^= v.wrapping_mul(0x3d20_adea);
+    v = v.wrapping_add(seed);
+    v = v.wrapping_mul((seed >> 16) | 1);
+    v ^= v.wrapping_mul(0x0552_6c56);
+    v ^= v.wrapping_mul(0x53a2_2864);
+    (f64::from(v.reverse_bits()) / 2f64.powi(32)).min(ONE_MINUS_EPSILON)
+}
+
+// Digits of index in base are mirrored around the point, each permuted depending on the digits before it
+fn owen_scrambled_radical_inverse(base: u64, mut index: u64, hash: u64) -> f64 {
+    let inverse_base = 1.0 / base as f64;
+    let mut inverse_base_m = 1.0;
+    let mut result = 0.0;
+    // Only to tell the digits' positions apart, it wraps for high bases
+    let mut reversed = 0u64;
+    let mut digit = 0u64;
+    // Stop once another digit would be below the precision of the result
+    while 1.0 - (base as f64 - 1.0) * inverse_base_m < 1.0 {
+        let next = index / base;
+        let value = index - next * base;
+        let digit_hash = mix_bits(hash ^ digit.wrapping_mul(0x9e37_79b9) ^ reversed);
+        let value = permutation_element(value as u32, base as u32, digit_hash as u32);
+        reversed = reversed.wrapping_mul(base).wrapping_add(u64::from(value));
+        inverse_base_m *= inverse_base;
+        result += f64::from(value) * inverse_base_m;
+        digit += 1;
+        index = next;
+    }
+    result.min(ONE_MINUS_EPSILON)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const METHODS: [Method; 4] = [
+        Method::Independent,
+        Method::Stratified,
+        Method::Halton,
+        Method::Sobol,
+    ];
+
+    fn draws(sampler: &mut Sampler, x: u32, y: u32, index: u32) -> Vec<f64> {
+        sampler.start_pixel_sample(x, y, index);
+        let mut values = vec![sampler.get_1d()];
+        let (u, v) = sampler.get_2d();
+        values.extend_from_slice(&[u, v]);
+        values.extend((0..80).map(|_| sampler.get_1d()));
+        values
+    }
+
+    #[test]
+    fn samples_only_depend_on_seed_pixel_and_index() {
+        for method in METHODS.iter() {
+            let mut sampler = Sampler::new(*method, 16, 7);
+            let first = draws(&mut sampler, 3, 5, 9);
+            // Whatever was drawn in between, and by which sampler
+            draws(&mut sampler, 4, 5, 2);
+            assert_eq!(draws(&mut sampler, 3, 5, 9), first);
+            assert_eq!(draws(&mut Sampler::new(*method, 16, 7), 3, 5, 9), first);
+            assert_ne!(draws(&mut Sampler::new(*method, 16, 8), 3, 5, 9), first);
+            assert_ne!(draws(&mut sampler, 3, 5, 10), first);
+            assert!(first.iter().all(|v| (0.0..1.0).contains(v)));
+        }
+    }
+
+    fn strata_1d(method: Method, n: u32) -> Vec<u32> {
+        let mut sampler = Sampler::new(method, n, 1);
+        let mut strata = vec![0; n as usize];
+        for index in 0..n {
+            sampler.start_pixel_sample(2, 3, index);
+            strata[(sampler.get_1d() * f64::from(n)) as usize] += 1;
+        }
+        strata
+    }
+
+    fn strata_2d(method: Method, side: u32) -> Vec<u32> {
+        let mut sampler = Sampler::new(method, side * side, 1);
+        let mut strata = vec![0; (side * side) as usize];
+        for index in 0..side * side {
+            sampler.start_pixel_sample(2, 3, index);
+            sampler.get_1d();
+            let (u, v) = sampler.get_2d();
+            strata[(v * f64::from(side)) as usize * side as usize
+                + (u * f64::from(side)) as usize] += 1;
+        }
+        strata
+    }
+
+    #[test]
+    fn one_sample_in_each_stratum() {
+        for method in [Method::Stratified, Method::Halton, Method::Sobol].iter() {
+            let strata = strata_1d(*method, 16);
+            assert!(strata.iter().all(|c| *c == 1), "{:?}", strata);
+        }
+        // Halton's later dimensions are in other bases so only these two make a grid
+        for method in [Method::Stratified, Method::Sobol].iter() {
+            let strata = strata_2d(*method, 4);
+            assert!(strata.iter().all(|c| *c == 1), "{:?}", strata);
+        }
+    }
+
+    #[test]
+    fn stratified_shuffles_each_dimension() {
+        let mut sampler = Sampler::new(Method::Stratified, 16, 1);
+        let (first, second): (Vec<usize>, Vec<usize>) = (0..16)
+            .map(|index| {
+                sampler.start_pixel_sample(0, 0, index);
+                let u = sampler.get_1d();
+                let v = sampler.get_1d();
+                ((u * 16.0) as usize, (v * 16.0) as usize)
+            })
+            .unzip();
+        assert_ne!(first, second);
+    }
+
+    #[test]
+    fn permutations_are_permutations() {
+        for l in [1, 2, 7, 16, 100].iter() {
+            let mut seen: Vec<u32> = (0..*l)
+                .map(|i| permutation_element(i, *l, 0xdead))
+                .collect();
+            seen.sort_unstable();
+            assert_eq!(seen, (0..*l).collect::<Vec<u32>>());
+        }
+    }
+}
@@ -0,0 +1,121 @@
+use super::geom::*;
+
+pub const LAMBDA_MIN: f64 = 380.0;
+pub const LAMBDA_MAX: f64 = 780.0;
+
+// Piecewise gaussian used by the color matching function fit
+fn lobe(lambda: f64, mu: f64, sigma_lower: f64, sigma_upper: f64) -> f64 {
+    let sigma = if lambda < mu {
+        sigma_lower
+    } else {
+        sigma_upper
+    };
+    (-0.5 * ((lambda - mu) / sigma).powi(2)).exp()
+}
+
+/**
+ * The CIE 1931 2 degree observer using the multi lobe fit from
+ * Wyman, Sloan and Shirley "Simple Analytic Approximations to the CIE XYZ Color Matching Functions"
+ */
+pub fn cie_xyz(lambda: f64) -> Vec3 {
+    let x = 1.056 * lobe(lambda, 599.8, 37.9, 31.0) + 0.362 * lobe(lambda, 442.0, 16.0, 26.7)
+        - 0.065 * lobe(lambda, 501.1, 20.4, 26.2);
+    let y = 0.821 * lobe(lambda, 568.8, 46.9, 40.5) + 0.286 * lobe(lambda, 530.9, 16.3, 31.1);
+    let z = 1.217 * lobe(lambda, 437.0, 11.8, 36.0) + 0.681 * lobe(lambda, 459.0, 26.0, 13.8);
+    Vec3::new(x, y, z)
+}
+
+// XYZ to linear sRGB primaries with a D65 white point
+fn xyz_to_linear_srgb(xyz: &Vec3) -> Vec3 {
+    Vec3::new(
+        3.240_454_2 * xyz.x() - 1.537_138_5 * xyz.y() - 0.498_531_4 * xyz.z(),
+        -0.969_266 * xyz.x() + 1.876_010_8 * xyz.y() + 0.041_556 * xyz.z(),
+        0.055_643_4 * xyz.x() - 0.204_025_9 * xyz.y() + 1.057_225_2 * xyz.z(),
+    )
+}
+
+fn smoothstep(edge0: f64, edge1: f64, x: f64) -> f64 {
+    let t = ((x - edge0) / (edge1 - edge0)).clamp(0.0, 1.0);
+    t * t * (3.0 - 2.0 * t)
+}
+
+/**
+ * Upsample an rgb triple to its value at a single wavelength.
+ * The three basis curves sum to one everywhere so white stays flat and albedos stay below one,
+ * at the cost of the round trip back to rgb only being approximate for saturated colors
+ */
+pub fn rgb_at_wavelength(rgb: &Vec3, lambda: f64) -> f64 {
+    let blue = 1.0 - smoothstep(480.0, 510.0, lambda);
+    let red = smoothstep(570.0, 600.0, lambda);
+    let green = 1.0 - blue - red;
+    rgb.x() * red + rgb.y() * green + rgb.z() * blue
+}
+
+/**
+ * Converts radiance carried at uniformly sampled wavelengths back to rgb.
+ * Normalized so that a flat spectrum of one comes out as rgb (1, 1, 1)
+ */
+pub struct Observer {
+    scale: Vec3,
+}
+
+impl Observer {
+    pub fn cie1931() -> Observer {
+        // Integrate the response to a flat spectrum once, 1nm steps are plenty for these curves
+        let mut white = Vec3::zero();
+        let mut lambda = LAMBDA_MIN;
+        while lambda <= LAMBDA_MAX {
+            white += xyz_to_linear_srgb(&cie_xyz(lambda));
+            lambda += 1.0;
+        }
+        let steps = LAMBDA_MAX - LAMBDA_MIN + 1.0;
+        Observer {
+            scale: Vec3::new(steps / white.x(), steps / white.y(), steps / white.z()),
+        }
+    }
+
+    // The rgb estimate for a single radiance sample at lambda drawn uniformly from the visible range
+    pub fn to_rgb(&self, lambda: f64, radiance: f64) -> Vec3 {
+        radiance * xyz_to_linear_srgb(&cie_xyz(lambda)) * self.scale
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::geom::tests::components;
+
+    // Midpoints of n equal steps across the visible range
+    fn wavelengths(n: u32) -> impl Iterator<Item = f64> {
+        let step = (LAMBDA_MAX - LAMBDA_MIN) / f64::from(n);
+        (0..n).map(move |i| LAMBDA_MIN + (f64::from(i) + 0.5) * step)
+    }
+
+    #[test]
+    fn flat_spectrum_is_white() {
+        let n = 4000;
+        // The fit on its own sees an equal energy spectrum at the white point in the middle
+        let xyz = wavelengths(n).fold(Vec3::zero(), |sum, lambda| sum + cie_xyz(lambda));
+        let xyz = (LAMBDA_MAX - LAMBDA_MIN) / f64::from(n) * xyz;
+        assert!((xyz.y() - 106.86).abs() < 1.5);
+        let total = xyz.x() + xyz.y() + xyz.z();
+        assert!((xyz.x() / total - 1.0 / 3.0).abs() < 0.01);
+        assert!((xyz.y() / total - 1.0 / 3.0).abs() < 0.01);
+
+        let observer = Observer::cie1931();
+        let rgb = wavelengths(n).fold(Vec3::zero(), |sum, lambda| {
+            sum + observer.to_rgb(lambda, 1.0)
+        });
+        for c in components(&(rgb / f64::from(n))).iter() {
+            assert!((c - 1.0).abs() < 0.01);
+        }
+    }
+
+    #[test]
+    fn upsampled_grey_is_flat() {
+        let grey = Vec3::new(0.4, 0.4, 0.4);
+        for lambda in wavelengths(100) {
+            assert!((rgb_at_wavelength(&grey, lambda) - 0.4).abs() < 1e-12);
+        }
+    }
+}
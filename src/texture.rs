@@ -0,0 +1,429 @@
+use super::cache::TileCache;
+use super::geom::*;
+use std::f64::consts::PI;
+use std::sync::Arc;
+
+/**
+ * A texture read from a png, handed out by a TextureCache which holds the texels.
+ * Lookups go through a mipmap pyramid so that they can be filtered
+ */
+pub struct ImageTexture {
+    tiles: Arc<TileCache>,
+    id: usize,
+    // Full resolution first, each level half the size of the one before down to a single texel
+    sizes: Vec<(usize, usize)>,
+}
+
+impl ImageTexture {
+    pub fn new(tiles: Arc<TileCache>, id: usize, width: usize, height: usize) -> ImageTexture {
+        let mut sizes = vec![(width, height)];
+        while sizes[sizes.len() - 1] != (1, 1) {
+            let (w, h) = sizes[sizes.len() - 1];
+            sizes.push((w.div_ceil(2), h.div_ceil(2)));
+        }
+        ImageTexture { tiles, id, sizes }
+    }
+
+    // The image repeats outside of [0, 1] so that it can be tiled
+    fn texel(&self, level: usize, i: isize, j: isize) -> Vec3 {
+        let (width, height) = self.sizes[level];
+        let i = i.rem_euclid(width as isize) as usize;
+        let j = j.rem_euclid(height as isize) as usize;
+        self.tiles.texel(self.id, level, i, j)
+    }
+
+    fn bilinear(&self, level: usize, u: f64, v: f64) -> Vec3 {
+        let (width, height) = self.sizes[level];
+        // Images are stored top row first while v runs bottom to top
+        let x = u * width as f64 - 0.5;
+        let y = (1.0 - v) * height as f64 - 0.5;
+        let (i, j) = (x.floor(), y.floor());
+        let (fx, fy) = (x - i, y - j);
+        let (i, j) = (i as isize, j as isize);
+        (1.0 - fy) * ((1.0 - fx) * self.texel(level, i, j) + fx * self.texel(level, i + 1, j))
+            + fy * ((1.0 - fx) * self.texel(level, i, j + 1) + fx * self.texel(level, i + 1, j + 1))
+    }
+}
+
+impl Texture for ImageTexture {
+    // Bilinear so that derivatives of the texture, like bump mapping takes, are smooth
+    fn color(&self, u: f64, v: f64, _point: &Vec3, _normal: &Vec3) -> Vec3 {
+        self.bilinear(0, u, v)
+    }
+
+    // Trilinear, between the two levels whose texels are closest to the footprint in size
+    fn color_filtered(&self, u: f64, v: f64, _point: &Vec3, _normal: &Vec3, width: f64) -> Vec3 {
+        let size = self.sizes[0].0.max(self.sizes[0].1) as f64;
+        let level = (width * size).log2();
+        let last = self.sizes.len() - 1;
+        if level.is_nan() || level <= 0.0 {
+            return self.bilinear(0, u, v);
+        }
+        if level >= last as f64 {
+            return self.bilinear(last, u, v);
+        }
+        let lower = level.floor();
+        let t = level - lower;
+        let lower = lower as usize;
+        (1.0 - t) * self.bilinear(lower, u, v) + t * self.bilinear(lower + 1, u, v)
+    }
+}
+
+// A checkerboard in texture space, so it sticks to the surface however the object moves
+pub struct UvCheckerTexture {
+    odd: Arc<dyn Texture + Send + Sync>,
+    even: Arc<dyn Texture + Send + Sync>,
+    // Number of squares across the full range of u and v
+    u_squares: f64,
+    v_squares: f64,
+}
+
+impl UvCheckerTexture {
+    pub fn new(
+        odd: Arc<dyn Texture + Send + Sync>,
+        even: Arc<dyn Texture + Send + Sync>,
+        u_squares: f64,
+        v_squares: f64,
+    ) -> UvCheckerTexture {
+        UvCheckerTexture {
+            odd,
+            even,
+            u_squares,
+            v_squares,
+        }
+    }
+}
+
+impl Texture for UvCheckerTexture {
+    fn color(&self, u: f64, v: f64, point: &Vec3, normal: &Vec3) -> Vec3 {
+        let parity = (u * self.u_squares).floor() + (v * self.v_squares).floor();
+        if parity.rem_euclid(2.0) == 0.0 {
+            self.even.color(u, v, point, normal)
+        } else {
+            self.odd.color(u, v, point, normal)
+        }
+    }
+
+    fn color_filtered(&self, u: f64, v: f64, point: &Vec3, normal: &Vec3, width: f64) -> Vec3 {
+        let parity = (u * self.u_squares).floor() + (v * self.v_squares).floor();
+        if parity.rem_euclid(2.0) == 0.0 {
+            self.even.color_filtered(u, v, point, normal, width)
+        } else {
+            self.odd.color_filtered(u, v, point, normal, width)
+        }
+    }
+}
+
+// Scale, then rotate (in degrees), then offset the texture coordinates before looking up inner
+pub struct UvTransform {
+    inner: Arc<dyn Texture + Send + Sync>,
+    scale: [f64; 2],
+    rotation: f64,
+    offset: [f64; 2],
+}
+
+impl UvTransform {
+    pub fn new(
+        inner: Arc<dyn Texture + Send + Sync>,
+        scale: [f64; 2],
+        rotation: f64,
+        offset: [f64; 2],
+    ) -> UvTransform {
+        UvTransform {
+            inner,
+            scale,
+            rotation: rotation.to_radians(),
+            offset,
+        }
+    }
+}
+
+impl UvTransform {
+    fn transform(&self, u: f64, v: f64) -> (f64, f64) {
+        let (su, sv) = (u * self.scale[0], v * self.scale[1]);
+        let (sin, cos) = self.rotation.sin_cos();
+        (
+            cos * su - sin * sv + self.offset[0],
+            sin * su + cos * sv + self.offset[1],
+        )
+    }
+}
+
+impl Texture for UvTransform {
+    fn color(&self, u: f64, v: f64, point: &Vec3, normal: &Vec3) -> Vec3 {
+        let (u, v) = self.transform(u, v);
+        self.inner.color(u, v, point, normal)
+    }
+
+    // Scaling up the coordinates tiles the texture so the footprint grows with it
+    fn color_filtered(&self, u: f64, v: f64, point: &Vec3, normal: &Vec3, width: f64) -> Vec3 {
+        let (u, v) = self.transform(u, v);
+        let scale = self.scale[0].abs().max(self.scale[1].abs());
+        self.inner
+            .color_filtered(u, v, point, normal, width * scale)
+    }
+}
+
+/**
+ * Scale, then rotate about axis by angle degrees, then offset the point before looking up inner.
+ * Used to put a 3D or projected texture into the space of the object it is on
+ */
+pub struct PointTransform {
+    inner: Arc<dyn Texture + Send + Sync>,
+    scale: Vec3,
+    axis: Vec3,
+    angle: f64,
+    offset: Vec3,
+}
+
+impl PointTransform {
+    pub fn new(
+        inner: Arc<dyn Texture + Send + Sync>,
+        scale: Vec3,
+        axis: Vec3,
+        angle: f64,
+        offset: Vec3,
+    ) -> PointTransform {
+        PointTransform {
+            inner,
+            scale,
+            axis: axis.unit(),
+            angle: angle.to_radians(),
+            offset,
+        }
+    }
+}
+
+impl PointTransform {
+    fn rotate(&self, p: &Vec3) -> Vec3 {
+        // Rodrigues' rotation formula
+        let (sin, cos) = self.angle.sin_cos();
+        let k = self.axis;
+        cos * *p + sin * k.cross(p) + (1.0 - cos) * k.dot(p) * k
+    }
+
+    fn transform(&self, point: &Vec3) -> Vec3 {
+        self.rotate(&(*point * self.scale)) + self.offset
+    }
+
+    // Normals stay perpendicular to the surface by scaling the other way
+    fn transform_normal(&self, normal: &Vec3) -> Vec3 {
+        self.rotate(&(*normal * self.scale.map(|s| 1.0 / s))).unit()
+    }
+}
+
+impl Texture for PointTransform {
+    fn color(&self, u: f64, v: f64, point: &Vec3, normal: &Vec3) -> Vec3 {
+        let normal = self.transform_normal(normal);
+        self.inner.color(u, v, &self.transform(point), &normal)
+    }
+
+    // Texture coordinates pass through untouched and so does their footprint
+    fn color_filtered(&self, u: f64, v: f64, point: &Vec3, normal: &Vec3, width: f64) -> Vec3 {
+        let normal = self.transform_normal(normal);
+        self.inner
+            .color_filtered(u, v, &self.transform(point), &normal, width)
+    }
+}
+
+#[derive(Clone, Copy)]
+pub enum Projection {
+    // Along z, u and v are x and y
+    Planar,
+    // Around the y axis, v is the height
+    Cylindrical,
+    // Same parameterization as a sphere's own uv
+    Spherical,
+    // Planar along each axis blended by how much the shading normal faces it
+    Triplanar,
+}
+
+// Replaces a surface's own texture coordinates with ones computed from the point
+pub struct Projected {
+    inner: Arc<dyn Texture + Send + Sync>,
+    projection: Projection,
+}
+
+impl Projected {
+    pub fn new(inner: Arc<dyn Texture + Send + Sync>, projection: Projection) -> Projected {
+        Projected { inner, projection }
+    }
+}
+
+impl Texture for Projected {
+    fn color(&self, _u: f64, _v: f64, point: &Vec3, normal: &Vec3) -> Vec3 {
+        match self.projection {
+            Projection::Planar => self.inner.color(point.x(), point.y(), point, normal),
+            Projection::Cylindrical => {
+                let u = (point.z().atan2(point.x()) + PI) / (2.0 * PI);
+                self.inner.color(u, point.y(), point, normal)
+            }
+            Projection::Spherical => {
+                // At the origin there is no direction to go by, so it takes the one along -x
+                let p = if point.length() > 1e-9 {
+                    point.unit()
+                } else {
+                    Vec3::new(-1.0, 0.0, 0.0)
+                };
+                let u = 1.0 - (p.z().atan2(p.x()) + PI) / (2.0 * PI);
+                let v = (p.y().clamp(-1.0, 1.0).asin() + PI / 2.0) / PI;
+                self.inner.color(u, v, point, normal)
+            }
+            Projection::Triplanar => {
+                // Sharpen the blend so that most of the surface sees a single projection
+                let weights = normal.unit().map(|c| c.abs().powi(4));
+                let total = weights.x() + weights.y() + weights.z();
+                (weights.x() * self.inner.color(point.z(), point.y(), point, normal)
+                    + weights.y() * self.inner.color(point.x(), point.z(), point, normal)
+                    + weights.z() * self.inner.color(point.x(), point.y(), point, normal))
+                    / total
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::geom::tests::components;
+
+    // The texture coordinates it was looked up at as a color
+    struct Probe;
+
+    impl Texture for Probe {
+        fn color(&self, u: f64, v: f64, _point: &Vec3, _normal: &Vec3) -> Vec3 {
+            Vec3::new(u, v, 0.0)
+        }
+    }
+
+    fn uv(texture: &dyn Texture, u: f64, v: f64, point: &Vec3, normal: &Vec3) -> (f64, f64) {
+        let color = texture.color(u, v, point, normal);
+        (color.x(), color.y())
+    }
+
+    fn close(a: (f64, f64), b: (f64, f64)) -> bool {
+        (a.0 - b.0).abs() < 1e-12 && (a.1 - b.1).abs() < 1e-12
+    }
+
+    fn transform(
+        inner: Arc<dyn Texture + Send + Sync>,
+        scale: [f64; 2],
+        rotation: f64,
+        offset: [f64; 2],
+    ) -> Arc<dyn Texture + Send + Sync> {
+        Arc::new(UvTransform::new(inner, scale, rotation, offset))
+    }
+
+    #[test]
+    fn uv_transform_scales_rotates_and_offsets() {
+        let (point, normal) = (Vec3::zero(), Vec3::new(0.0, 0.0, 1.0));
+        let scaled = transform(Arc::new(Probe), [2.0, 3.0], 0.0, [0.0, 0.0]);
+        assert!(close(
+            uv(scaled.as_ref(), 0.25, 0.5, &point, &normal),
+            (0.5, 1.5)
+        ));
+        let rotated = transform(Arc::new(Probe), [1.0, 1.0], 90.0, [0.0, 0.0]);
+        assert!(close(
+            uv(rotated.as_ref(), 1.0, 0.0, &point, &normal),
+            (0.0, 1.0)
+        ));
+        let offset = transform(Arc::new(Probe), [1.0, 1.0], 0.0, [0.1, -0.2]);
+        assert!(close(
+            uv(offset.as_ref(), 0.25, 0.5, &point, &normal),
+            (0.35, 0.3)
+        ));
+
+        // Undoing each step in the opposite order gets back to where it started
+        let (scale, rotation, offset) = ([2.0, -0.5], 30.0, [0.7, 1.3]);
+        let undo = transform(
+            Arc::new(Probe),
+            [1.0 / scale[0], 1.0 / scale[1]],
+            0.0,
+            [0.0, 0.0],
+        );
+        let undo = transform(undo, [1.0, 1.0], -rotation, [0.0, 0.0]);
+        let undo = transform(undo, [1.0, 1.0], 0.0, [-offset[0], -offset[1]]);
+        let round_trip = transform(undo, scale, rotation, offset);
+        for (u, v) in [(0.0, 0.0), (0.3, 0.9), (-2.0, 5.5)] {
+            assert!(close(
+                uv(round_trip.as_ref(), u, v, &point, &normal),
+                (u, v)
+            ));
+        }
+    }
+
+    #[test]
+    fn projections_cover_the_unit_square() {
+        let mut state = 3;
+        let mut coordinate = || {
+            state = mix_bits(state);
+            4.0 * bits_to_unit(state) - 2.0
+        };
+        let points: Vec<Vec3> = (0..500)
+            .map(|_| Vec3::new(coordinate(), coordinate(), coordinate()))
+            .chain(std::iter::once(Vec3::zero()))
+            .collect();
+        let normal = Vec3::new(0.0, 1.0, 0.0);
+        for projection in [Projection::Cylindrical, Projection::Spherical] {
+            let projected = Projected::new(Arc::new(Probe), projection);
+            for point in &points {
+                let (u, v) = uv(&projected, 0.0, 0.0, point, &normal);
+                assert!((0.0..=1.0).contains(&u), "{:?}", components(point));
+                if let Projection::Spherical = projection {
+                    assert!((0.0..=1.0).contains(&v), "{:?}", components(point));
+                }
+            }
+        }
+        // Planar and cylindrical height pass coordinates straight through
+        let planar = Projected::new(Arc::new(Probe), Projection::Planar);
+        let cylindrical = Projected::new(Arc::new(Probe), Projection::Cylindrical);
+        for point in &points {
+            assert!(close(
+                uv(&planar, 0.0, 0.0, point, &normal),
+                (point.x(), point.y())
+            ));
+            assert_eq!(uv(&cylindrical, 0.0, 0.0, point, &normal).1, point.y());
+        }
+    }
+
+    #[test]
+    fn triplanar_follows_the_normal() {
+        let triplanar = Projected::new(Arc::new(Probe), Projection::Triplanar);
+        let point = Vec3::new(0.1, 0.2, 0.3);
+        // Wherever the point is, a normal along an axis picks the projection along it
+        for (normal, expected) in [
+            (Vec3::new(-1.0, 0.0, 0.0), (0.3, 0.2)),
+            (Vec3::new(0.0, 1.0, 0.0), (0.1, 0.3)),
+            (Vec3::new(0.0, 0.0, 1.0), (0.1, 0.2)),
+        ] {
+            assert!(close(uv(&triplanar, 0.0, 0.0, &point, &normal), expected));
+        }
+        // And in between they blend
+        let (u, v) = uv(&triplanar, 0.0, 0.0, &point, &Vec3::new(1.0, 0.0, 1.0));
+        assert!((u - 0.2).abs() < 1e-12 && (v - 0.2).abs() < 1e-12);
+    }
+
+    #[test]
+    fn footprint_picks_the_mip_level() {
+        let path = crate::cache::tests::texture_file("texture-footprint", 64, 32);
+        let texture = crate::cache::TextureCache::new(1 << 20).get(&path).unwrap();
+        let (point, normal) = (Vec3::zero(), Vec3::new(0.0, 0.0, 1.0));
+        for (u, v) in [(0.1, 0.2), (0.5, 0.5), (0.93, 0.71)] {
+            let filtered =
+                |width| components(&texture.color_filtered(u, v, &point, &normal, width));
+            // No footprint is the full resolution, as the unfiltered lookup is
+            assert_eq!(
+                filtered(0.0),
+                components(&texture.color(u, v, &point, &normal))
+            );
+            assert_eq!(filtered(0.0), components(&texture.bilinear(0, u, v)));
+            // Each doubling of the footprint goes a level down, a texel of level n being 2^n across
+            for level in 1..7 {
+                let width = f64::from(1 << level) / 64.0;
+                assert_eq!(filtered(width), components(&texture.bilinear(level, u, v)));
+            }
+            assert_eq!(filtered(100.0), components(&texture.bilinear(6, u, v)));
+        }
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,223 @@
+use super::geom::*;
+use super::spectrum;
+use std::f64::consts::PI;
+use std::ops::*;
+
+// Just enough complex arithmetic for the amplitudes in a thin film stack
+#[derive(Clone, Copy)]
+struct Complex {
+    re: f64,
+    im: f64,
+}
+
+impl Complex {
+    fn new(re: f64, im: f64) -> Complex {
+        Complex { re, im }
+    }
+
+    fn real(re: f64) -> Complex {
+        Complex::new(re, 0.0)
+    }
+
+    fn norm_squared(&self) -> f64 {
+        self.re * self.re + self.im * self.im
+    }
+
+    fn sqrt(&self) -> Complex {
+        let r = self.norm_squared().sqrt();
+        let re = ((r + self.re) / 2.0).max(0.0).sqrt();
+        let im = ((r - self.re) / 2.0).max(0.0).sqrt();
+        Complex::new(re, im.copysign(self.im))
+    }
+
+    // e^(i * theta)
+    fn phase(theta: f64) -> Complex {
+        Complex::new(theta.cos(), theta.sin())
+    }
+}
+
+impl Add for Complex {
+    type Output = Complex;
+    fn add(self, rhs: Complex) -> Complex {
+        Complex::new(self.re + rhs.re, self.im + rhs.im)
+    }
+}
+
+impl Sub for Complex {
+    type Output = Complex;
+    fn sub(self, rhs: Complex) -> Complex {
+        Complex::new(self.re - rhs.re, self.im - rhs.im)
+    }
+}
+
+impl Mul for Complex {
+    type Output = Complex;
+    fn mul(self, rhs: Complex) -> Complex {
+        Complex::new(
+            self.re * rhs.re - self.im * rhs.im,
+            self.re * rhs.im + self.im * rhs.re,
+        )
+    }
+}
+
+impl Div for Complex {
+    type Output = Complex;
+    fn div(self, rhs: Complex) -> Complex {
+        let d = rhs.norm_squared();
+        Complex::new(
+            (self.re * rhs.re + self.im * rhs.im) / d,
+            (self.im * rhs.re - self.re * rhs.im) / d,
+        )
+    }
+}
+
+// Wavelengths in nanometers used for the rgb channels when not rendering spectrally
+const RGB_WAVELENGTHS: [f64; 3] = [650.0, 550.0, 450.0];
+
+/**
+ * A thin transparent film, like soap or oil, on top of a surface.
+ * Light bouncing between the two sides of the film interferes with itself which
+ * replaces the plain fresnel reflectance of the surface with an iridescent one
+ */
+#[derive(Clone, Copy)]
+pub struct ThinFilm {
+    thickness: f64,
+    ref_idx: f64,
+}
+
+impl ThinFilm {
+    // thickness in nanometers, interesting colors happen roughly between 100 and 1000
+    pub fn new(thickness: f64, ref_idx: f64) -> ThinFilm {
+        ThinFilm { thickness, ref_idx }
+    }
+
+    /**
+     * Reflectance of the stack outer | film | substrate for light arriving at cos_i in the outer medium.
+     * The substrate index is complex, eta + ik, so this covers dielectrics (k = 0) and conductors
+     */
+    pub fn reflectance(
+        &self,
+        cos_i: f64,
+        outer: f64,
+        substrate_eta: Vec3,
+        substrate_k: Vec3,
+        wavelength: Option<f64>,
+    ) -> Vec3 {
+        match wavelength {
+            Some(lambda) => {
+                let eta = spectrum::rgb_at_wavelength(&substrate_eta, lambda);
+                let k = spectrum::rgb_at_wavelength(&substrate_k, lambda);
+                let r = self.airy(cos_i, outer, Complex::new(eta, k), lambda);
+                Vec3::new(r, r, r)
+            }
+            None => Vec3::new(
+                self.airy(
+                    cos_i,
+                    outer,
+                    Complex::new(substrate_eta.x(), substrate_k.x()),
+                    RGB_WAVELENGTHS[0],
+                ),
+                self.airy(
+                    cos_i,
+                    outer,
+                    Complex::new(substrate_eta.y(), substrate_k.y()),
+                    RGB_WAVELENGTHS[1],
+                ),
+                self.airy(
+                    cos_i,
+                    outer,
+                    Complex::new(substrate_eta.z(), substrate_k.z()),
+                    RGB_WAVELENGTHS[2],
+                ),
+            ),
+        }
+    }
+
+    // Airy summation of the infinite series of reflections inside the film, averaged over polarizations
+    fn airy(&self, cos_i: f64, outer: f64, substrate: Complex, lambda: f64) -> f64 {
+        let cos1 = cos_i.clamp(0.0, 1.0);
+        let sin2_1 = 1.0 - cos1 * cos1;
+        let n1 = Complex::real(outer);
+        let n2 = Complex::real(self.ref_idx);
+        let n3 = substrate;
+
+        let cos2_sq = 1.0 - (outer / self.ref_idx).powi(2) * sin2_1;
+        if cos2_sq <= 0.0 {
+            // Total internal reflection at the top of the film
+            return 1.0;
+        }
+        let cos1 = Complex::real(cos1);
+        let cos2 = Complex::real(cos2_sq.sqrt());
+        let sin3 = Complex::real(outer * sin2_1.sqrt()) / n3;
+        let cos3 = (Complex::real(1.0) - sin3 * sin3).sqrt();
+
+        let r12_s = (n1 * cos1 - n2 * cos2) / (n1 * cos1 + n2 * cos2);
+        let r12_p = (n2 * cos1 - n1 * cos2) / (n2 * cos1 + n1 * cos2);
+        let r23_s = (n2 * cos2 - n3 * cos3) / (n2 * cos2 + n3 * cos3);
+        let r23_p = (n3 * cos2 - n2 * cos3) / (n3 * cos2 + n2 * cos3);
+
+        // Phase picked up by one round trip through the film
+        let delta = 4.0 * PI * self.ref_idx * self.thickness * cos2.re / lambda;
+        let shift = Complex::phase(delta);
+        let one = Complex::real(1.0);
+        let r_s = (r12_s + r23_s * shift) / (one + r12_s * r23_s * shift);
+        let r_p = (r12_p + r23_p * shift) / (one + r12_p * r23_p * shift);
+
+        (0.5 * (r_s.norm_squared() + r_p.norm_squared())).clamp(0.0, 1.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::geom::tests::components;
+    use crate::microfacet::{fresnel_dielectric, ComplexIor};
+
+    const ANGLES: [f64; 5] = [1.0, 0.8, 0.5, 0.2, 0.01];
+
+    #[test]
+    fn no_film_is_plain_fresnel() {
+        let film = ThinFilm::new(0.0, 1.33);
+        for cos in ANGLES {
+            let glass = film.reflectance(cos, 1.0, Vec3::new(1.5, 1.5, 1.5), Vec3::zero(), None);
+            for r in components(&glass) {
+                assert!((r - fresnel_dielectric(cos, 1.0 / 1.5)).abs() < 1e-9);
+            }
+            let eta = Vec3::new(0.143, 0.374, 1.442);
+            let k = Vec3::new(3.983, 2.385, 1.603);
+            let gold = film.reflectance(cos, 1.0, eta, k, None);
+            let expected = ComplexIor::new(eta, k).fresnel(cos);
+            assert!((gold - expected).length() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn reflectance_stays_between_zero_and_one() {
+        let substrates = [
+            (Vec3::new(1.5, 1.5, 1.5), Vec3::zero()),
+            (Vec3::new(1.0, 1.2, 2.4), Vec3::zero()),
+            (
+                Vec3::new(0.155, 0.117, 0.138),
+                Vec3::new(4.828, 3.122, 2.147),
+            ),
+        ];
+        for thickness in [50.0, 250.0, 480.0, 1000.0] {
+            for ref_idx in [1.2, 1.5, 2.2] {
+                let film = ThinFilm::new(thickness, ref_idx);
+                for (eta, k) in substrates {
+                    for cos in ANGLES {
+                        let wavelengths = [None, Some(400.0), Some(530.0), Some(700.0)];
+                        for wavelength in wavelengths {
+                            for r in components(&film.reflectance(cos, 1.0, eta, k, wavelength)) {
+                                assert!((0.0..=1.0).contains(&r));
+                            }
+                        }
+                        // From inside a denser medium too, past the critical angle on the film
+                        let inside = film.reflectance(cos, 1.6, eta, k, None);
+                        assert!(components(&inside).iter().all(|r| (0.0..=1.0).contains(r)));
+                    }
+                }
+            }
+        }
+    }
+}
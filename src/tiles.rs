@@ -0,0 +1,112 @@
+#[derive(Clone, Copy, PartialEq)]
+pub enum Order {
+    // Left to right along rows of tiles, top row first
+    Scanline,
+    // Outwards from the center of the image, where the subject usually is
+    Spiral,
+    // Along a Hilbert curve, consecutive tiles stay close and share what is in the caches
+    Hilbert,
+}
+
+// The columns and rows of tiles covering a width by height image, in the order they should be rendered
+pub fn layout(width: u32, height: u32, size: u32, order: Order) -> Vec<(u32, u32)> {
+    let columns = width.div_ceil(size);
+    let rows = height.div_ceil(size);
+    let mut tiles: Vec<(u32, u32)> = (0..rows)
+        .flat_map(|y| (0..columns).map(move |x| (x, y)))
+        .collect();
+    match order {
+        Order::Scanline => {}
+        Order::Spiral => {
+            let center = (f64::from(columns) / 2.0 - 0.5, f64::from(rows) / 2.0 - 0.5);
+            let key = |(x, y): &(u32, u32)| {
+                let (dx, dy) = (f64::from(*x) - center.0, f64::from(*y) - center.1);
+                // Rings of tiles around the center, clockwise within each ring
+                (dx.abs().max(dy.abs()), dy.atan2(dx))
+            };
+            tiles.sort_by(|a, b| key(a).partial_cmp(&key(b)).unwrap());
+        }
+        Order::Hilbert => {
+            let side = columns.max(rows).next_power_of_two();
+            tiles.sort_by_key(|(x, y)| hilbert_index(side, *x, *y));
+        }
+    }
+    tiles
+}
+
+// Distance along the Hilbert curve filling a side by side grid, side a power of two
+fn hilbert_index(side: u32, mut x: u32, mut y: u32) -> u64 {
+    let mut d = 0u64;
+    let mut s = side / 2;
+    while s > 0 {
+        let rx = u32::from(x & s > 0);
+        let ry = u32::from(y & s > 0);
+        d += u64::from(s) * u64::from(s) * u64::from((3 * rx) ^ ry);
+        // Rotate the quadrant so the curve inside it lines up with the next
+        if ry == 0 {
+            if rx == 1 {
+                x = s - 1 - (x & (s - 1));
+                y = s - 1 - (y & (s - 1));
+            }
+            std::mem::swap(&mut x, &mut y);
+        }
+        s /= 2;
+    }
+    d
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ORDERS: [Order; 3] = [Order::Scanline, Order::Spiral, Order::Hilbert];
+
+    #[test]
+    fn every_tile_once() {
+        for order in ORDERS.iter() {
+            for (width, height) in [(37, 23), (8, 8), (1, 50), (64, 9)].iter() {
+                let mut tiles = layout(*width, *height, 8, *order);
+                tiles.sort_unstable();
+                let mut expected = layout(*width, *height, 8, Order::Scanline);
+                expected.sort_unstable();
+                assert_eq!(tiles, expected);
+                assert_eq!(tiles.len() as u32, width.div_ceil(8) * height.div_ceil(8));
+            }
+        }
+    }
+
+    #[test]
+    fn scanline_goes_along_rows() {
+        assert_eq!(
+            layout(20, 10, 8, Order::Scanline),
+            vec![(0, 0), (1, 0), (2, 0), (0, 1), (1, 1), (2, 1)]
+        );
+    }
+
+    #[test]
+    fn hilbert_steps_to_a_neighbour() {
+        for side in [2, 4, 8, 16].iter() {
+            let tiles = layout(side * 4, side * 4, 4, Order::Hilbert);
+            assert_eq!(tiles[0], (0, 0));
+            for pair in tiles.windows(2) {
+                let ((x0, y0), (x1, y1)) = (pair[0], pair[1]);
+                assert_eq!(x0.abs_diff(x1) + y0.abs_diff(y1), 1, "{:?}", pair);
+            }
+        }
+    }
+
+    #[test]
+    fn spiral_goes_outwards_from_the_center() {
+        let tiles = layout(5 * 8, 5 * 8, 8, Order::Spiral);
+        assert_eq!(tiles[0], (2, 2));
+        let ring = |(x, y): &(u32, u32)| x.abs_diff(2).max(y.abs_diff(2));
+        assert!(tiles
+            .windows(2)
+            .all(|pair| ring(&pair[0]) <= ring(&pair[1])));
+        // An even number of tiles has four in the middle
+        let tiles = layout(4 * 8, 2 * 8, 8, Order::Spiral);
+        let mut middle = tiles[..4].to_vec();
+        middle.sort_unstable();
+        assert_eq!(middle, vec![(1, 0), (1, 1), (2, 0), (2, 1)]);
+    }
+}
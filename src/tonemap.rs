@@ -0,0 +1,204 @@
+use super::geom::*;
+
+#[derive(Clone, Copy)]
+pub enum Operator {
+    // Clip anything over one
+    Clamp,
+    // Extended Reinhard on luminance, white is the luminance that maps to one
+    Reinhard { white: f64 },
+    // Stephen Hill's fit of the ACES reference rendering and sRGB output transforms
+    Aces,
+    // Troy Sobotka's AgX with Benjamin Wrensch's polynomial fit of its curve
+    Agx,
+}
+
+/**
+ * Turns scene radiance into display values for 8 bit output.
+ * Exposure in stops scales radiance first, the operator compresses it into [0, 1] and the result is sRGB encoded
+ */
+#[derive(Clone, Copy)]
+pub struct ToneMap {
+    exposure: f64,
+    operator: Operator,
+}
+
+impl ToneMap {
+    pub fn new(exposure: f64, operator: Operator) -> ToneMap {
+        ToneMap { exposure, operator }
+    }
+
+    pub fn encode(&self, radiance: &Vec3) -> [u8; 3] {
+        let display = self.display(radiance);
+        let encode = |c: f64| (srgb_encode(c) * 255.0).round() as u8;
+        [
+            encode(display.x()),
+            encode(display.y()),
+            encode(display.z()),
+        ]
+    }
+
+    // Linear display values in [0, 1], the fitted curves can go a little outside so they are clipped too
+    fn display(&self, radiance: &Vec3) -> Vec3 {
+        let exposed = 2f64.powf(self.exposure) * radiance.map(|c| c.max(0.0));
+        let display = match self.operator {
+            Operator::Clamp => exposed,
+            Operator::Reinhard { white } => reinhard(&exposed, white),
+            Operator::Aces => aces(&exposed),
+            Operator::Agx => agx(&exposed),
+        };
+        display.map(|c| c.clamp(0.0, 1.0))
+    }
+}
+
+// The piecewise sRGB transfer function from linear to encoded
+fn srgb_encode(c: f64) -> f64 {
+    if c <= 0.003_130_8 {
+        12.92 * c
+    } else {
+        1.055 * c.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+fn multiply(m: &[[f64; 3]; 3], v: &Vec3) -> Vec3 {
+    let row = |r: &[f64; 3]| r[0] * v.x() + r[1] * v.y() + r[2] * v.z();
+    Vec3::new(row(&m[0]), row(&m[1]), row(&m[2]))
+}
+
+// Scaling the color by the change in luminance keeps hues where per channel Reinhard would desaturate
+fn reinhard(color: &Vec3, white: f64) -> Vec3 {
+    let l = color.luminance();
+    if l <= 0.0 {
+        return Vec3::zero();
+    }
+    let mapped = l * (1.0 + l / (white * white)) / (1.0 + l);
+    (mapped / l) * *color
+}
+
+fn aces(color: &Vec3) -> Vec3 {
+    // sRGB to the rendering space, then back out to sRGB after the curve
+    const INPUT: [[f64; 3]; 3] = [
+        [0.59719, 0.35458, 0.04823],
+        [0.07600, 0.90834, 0.01566],
+        [0.02840, 0.13383, 0.83777],
+    ];
+    const OUTPUT: [[f64; 3]; 3] = [
+        [1.60475, -0.53108, -0.07367],
+        [-0.10208, 1.10813, -0.00605],
+        [-0.00327, -0.07276, 1.07602],
+    ];
+    let v = multiply(&INPUT, color);
+    let fitted = v.map(|c| {
+        (c * (c + 0.024_578_6) - 0.000_090_537) / (c * (0.983_729 * c + 0.432_951) + 0.238_081)
+    });
+    multiply(&OUTPUT, &fitted)
+}
+
+fn agx(color: &Vec3) -> Vec3 {
+    // Insetting the primaries first is what keeps bright saturated colors from skewing
+    const INSET: [[f64; 3]; 3] = [
+        [0.842_479_062_253_094, 0.078_433_6, 0.079_223_745_147_764_3],
+        [
+            0.042_328_242_261_012_3,
+            0.878_468_636_469_772,
+            0.079_166_127_460_543_4,
+        ],
+        [0.042_375_654_905_705_1, 0.078_433_6, 0.879_142_973_793_104],
+    ];
+    const OUTSET: [[f64; 3]; 3] = [
+        [
+            1.196_879_005_120_17,
+            -0.098_020_881_140_136_8,
+            -0.099_029_744_079_720_5,
+        ],
+        [
+            -0.052_896_851_757_456_2,
+            1.151_903_129_904_17,
+            -0.098_961_176_844_843_3,
+        ],
+        [
+            -0.052_971_635_514_443_8,
+            -0.098_043_450_117_124_1,
+            1.151_073_672_641_16,
+        ],
+    ];
+    // The curve works on log2 exposure over this range around middle grey
+    const MIN_EV: f64 = -12.473_93;
+    const MAX_EV: f64 = 4.026_069;
+    let v = multiply(&INSET, color);
+    let curve = v.map(|c| {
+        let x = ((c.max(1e-10).log2() - MIN_EV) / (MAX_EV - MIN_EV)).clamp(0.0, 1.0);
+        let x2 = x * x;
+        let x4 = x2 * x2;
+        15.5 * x4 * x2 - 40.14 * x4 * x + 31.96 * x4 - 6.868 * x2 * x + 0.4298 * x2 + 0.1191 * x
+            - 0.00232
+    });
+    // The curve's output is display encoded with a 2.2 gamma, undo it so that sRGB encoding applies after
+    multiply(&OUTSET, &curve).map(|c| c.max(0.0).powf(2.2))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::geom::tests::components;
+
+    // Greys from far below to far above one, each brighter than the last
+    fn ramp() -> impl Iterator<Item = f64> {
+        (0..=400).map(|i| 1e-4 * 1.05f64.powi(i))
+    }
+
+    #[test]
+    fn srgb_endpoints_and_cutoff() {
+        assert_eq!(srgb_encode(0.0), 0.0);
+        assert!((srgb_encode(1.0) - 1.0).abs() < 1e-12);
+        // Linear below the cutoff, and the two pieces meet there
+        assert!((srgb_encode(0.002) - 12.92 * 0.002).abs() < 1e-15);
+        let cutoff = 0.003_130_8;
+        assert!((srgb_encode(cutoff) - srgb_encode(cutoff + 1e-12)).abs() < 1e-7);
+        assert!((srgb_encode(cutoff) - 0.040_45).abs() < 1e-5);
+        let encoded = ToneMap::new(0.0, Operator::Clamp).encode(&Vec3::new(0.0, 0.5, 1.0));
+        assert_eq!(encoded, [0, 188, 255]);
+    }
+
+    #[test]
+    fn curves_rise_and_stay_in_range() {
+        let white = 4.0;
+        let operators = [Operator::Reinhard { white }, Operator::Aces, Operator::Agx];
+        let colors = [Vec3::new(1.0, 0.4, 0.1), Vec3::new(0.05, 0.3, 1.0)];
+        for operator in operators {
+            let tone_map = ToneMap::new(0.0, operator);
+            let mut last = 0.0;
+            for value in ramp() {
+                let grey = tone_map.display(&Vec3::new(value, value, value)).y();
+                assert!((0.0..=1.0).contains(&grey) && grey >= last);
+                last = grey;
+                // Mixing channels in and out of the curves' spaces lets saturated colors dip a little near the top,
+                // so they only have to stay in range
+                for color in &colors {
+                    let display = components(&tone_map.display(&(value * *color)));
+                    assert!(display.iter().all(|c| (0.0..=1.0).contains(c)));
+                }
+            }
+        }
+        // Before the clip the curves themselves rise all the way
+        let mut last = (f64::NEG_INFINITY, f64::NEG_INFINITY);
+        for value in ramp() {
+            let grey = Vec3::new(value, value, value);
+            let now = (reinhard(&grey, white).y(), aces(&grey).y());
+            assert!(now.0 > last.0 && now.1 > last.1);
+            last = now;
+        }
+        assert!((reinhard(&Vec3::new(white, white, white), white).y() - 1.0).abs() < 1e-12);
+    }
+
+    #[test]
+    fn exposure_scales_by_powers_of_two() {
+        let radiance = Vec3::new(0.01, 0.02, 0.03);
+        for ev in [-2.0, -0.5, 0.0, 1.0, 3.0] {
+            let display = ToneMap::new(ev, Operator::Clamp).display(&radiance);
+            assert!((display - 2f64.powf(ev) * radiance).length() < 1e-15);
+        }
+        let brighter = ToneMap::new(1.0, Operator::Aces).display(&radiance);
+        let doubled = ToneMap::new(0.0, Operator::Aces).display(&(2.0 * radiance));
+        assert_eq!(components(&brighter), components(&doubled));
+    }
+}